@@ -0,0 +1,2215 @@
+//! Remediation actions and how their outcome is classified
+
+use anyhow::Result;
+use futures::future::BoxFuture;
+use k8s_openapi::api::{
+    apps::v1::{Deployment, ReplicaSet},
+    autoscaling::v2::HorizontalPodAutoscaler,
+    batch::v1::{CronJob, Job},
+    core::v1::{EnvVar, Node, Pod, Taint},
+};
+use k8s_openapi::chrono::Utc;
+use kube::{
+    api::{
+        ApiResource, DeleteParams, DynamicObject, GroupVersionKind, ListParams, Patch, PatchParams,
+        PostParams, PropagationPolicy,
+    },
+    Api, Client, ResourceExt,
+};
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use std::{
+    collections::BTreeMap,
+    error::Error,
+    process::Stdio,
+    sync::{Arc, OnceLock},
+};
+use tokio::{io::AsyncWriteExt, process::Command, time::Duration};
+
+use crate::{
+    alertmanager::{Alert, Labels},
+    clusters::Clusters,
+    config::Args,
+    metrics::METRICS,
+    notify::{
+        alert_client, pagerduty_routing_key, render_template, send_discord, send_email,
+        send_opsgenie_alert, send_pagerduty_event, send_teams, send_webhook,
+    },
+    rbac::check_action,
+    rules::{target_key, AlertRemediationRule},
+    state::ExecContext,
+};
+
+/// The action deliberately left its target alone for now, e.g. because the live object doesn't
+/// warrant it yet. Unlike a failure it starts no cooldown, so the alert is re-evaluated next cycle.
+#[derive(Debug)]
+pub struct Skipped(pub String);
+
+impl std::fmt::Display for Skipped {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl Error for Skipped {}
+
+/// The action didn't finish within its timeout and was abandoned. Whatever it already changed
+/// stays changed.
+#[derive(Debug)]
+pub struct TimedOut(pub Duration);
+
+impl std::fmt::Display for TimedOut {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "timed out after {}s", self.0.as_secs())
+    }
+}
+
+impl Error for TimedOut {}
+
+/// Whether any guard needs the live pod before it is deleted
+pub fn pod_guards_enabled(args: &Args, labels: &Labels) -> bool {
+    args.require_opt_in || args.min_pod_age_secs > 0 || labels.precondition.is_some()
+}
+
+/// Whether one precondition (crashloop, oomkilled or not_ready) holds on the live pod
+pub fn precondition_holds(
+    precondition: &str,
+    pod: &Pod,
+    not_ready_for: Duration,
+) -> Result<bool, Box<dyn Error>> {
+    let status = pod.status.clone().unwrap_or_default();
+    let containers = status.container_statuses.unwrap_or_default();
+    let holds = match precondition {
+        "crashloop" => containers.iter().any(|container| {
+            container
+                .state
+                .as_ref()
+                .and_then(|state| state.waiting.as_ref())
+                .and_then(|waiting| waiting.reason.as_deref())
+                == Some("CrashLoopBackOff")
+        }),
+        "oomkilled" => containers.iter().any(|container| {
+            [&container.state, &container.last_state]
+                .into_iter()
+                .flatten()
+                .filter_map(|state| state.terminated.as_ref())
+                .any(|terminated| terminated.reason.as_deref() == Some("OOMKilled"))
+        }),
+        "not_ready" => status
+            .conditions
+            .unwrap_or_default()
+            .iter()
+            .any(|condition| {
+                condition.type_ == "Ready"
+                    && condition.status != "True"
+                    && condition
+                        .last_transition_time
+                        .as_ref()
+                        .is_some_and(|since| {
+                            (Utc::now() - since.0).to_std().unwrap_or_default() >= not_ready_for
+                        })
+            }),
+        other => return Err(format!("unknown precondition '{}'", other).into()),
+    };
+    Ok(holds)
+}
+
+/// Verify the live pod against the deletion guards: the opt-in annotation, the minimum age and
+/// the alert's preconditions, since alerts can be stale while the cluster is the source of truth
+pub fn check_pod(args: &Args, labels: &Labels, pod: &Pod) -> Result<(), Box<dyn Error>> {
+    let name = format!("{}/{}", pod.namespace().unwrap_or_default(), pod.name_any());
+    let opted_in = pod
+        .annotations()
+        .get(&args.opt_in_annotation)
+        .is_some_and(|value| value == "true");
+    if args.require_opt_in && !opted_in {
+        return Err(format!(
+            "pod {} has not opted in with annotation {}=\"true\"",
+            name, args.opt_in_annotation
+        )
+        .into());
+    }
+    // A pod that was only just recreated hasn't had a chance to clear the alert yet
+    if let Some(created) = pod.creation_timestamp() {
+        let age = (Utc::now() - created.0).to_std().unwrap_or_default();
+        if age < Duration::from_secs(args.min_pod_age_secs) {
+            return Err(Skipped(format!(
+                "pod {} is only {}s old, younger than --min-pod-age-secs",
+                name,
+                age.as_secs()
+            ))
+            .into());
+        }
+    }
+    if let Some(preconditions) = &labels.precondition {
+        let not_ready_for = match &labels.not_ready_secs {
+            Some(secs) => Duration::from_secs(
+                secs.parse()
+                    .map_err(|_| format!("invalid not_ready_secs '{}'", secs))?,
+            ),
+            None => Duration::ZERO,
+        };
+        let mut any_holds = false;
+        for precondition in preconditions.split(',').map(str::trim) {
+            any_holds |= precondition_holds(precondition, pod, not_ready_for)?;
+        }
+        if !any_holds {
+            return Err(Skipped(format!(
+                "pod {} doesn't meet any precondition of '{}'",
+                name, preconditions
+            ))
+            .into());
+        }
+    }
+    Ok(())
+}
+
+/// Kind and name of the workload controlling a pod, following a ReplicaSet up to its Deployment
+pub async fn owning_workload(
+    client: Client,
+    pod: &str,
+    namespace: &str,
+) -> Result<(String, String), Box<dyn Error>> {
+    let pods: Api<Pod> = Api::namespaced(client.clone(), namespace);
+    let pod = pods.get(pod).await?;
+    let owner = pod
+        .owner_references()
+        .iter()
+        .find(|owner| owner.controller == Some(true))
+        .ok_or_else(|| {
+            format!(
+                "pod {}/{} has no owning workload",
+                namespace,
+                pod.name_any()
+            )
+        })?;
+    if owner.kind == "ReplicaSet" {
+        let replica_sets: Api<ReplicaSet> = Api::namespaced(client, namespace);
+        let replica_set = replica_sets.get(&owner.name).await?;
+        if let Some(deployment) = replica_set
+            .owner_references()
+            .iter()
+            .find(|owner| owner.controller == Some(true) && owner.kind == "Deployment")
+        {
+            return Ok((deployment.kind.clone(), deployment.name.clone()));
+        }
+    }
+    Ok((owner.kind.clone(), owner.name.clone()))
+}
+
+/// Restart an apps/v1 workload the way `kubectl rollout restart` does, or scale it
+pub async fn act_on_owner(
+    client: Client,
+    namespace: &str,
+    kind: &str,
+    name: &str,
+    owner_action: &str,
+    replicas: Option<&str>,
+    dry_run: bool,
+) -> Result<(), Box<dyn Error>> {
+    if !matches!(
+        kind,
+        "Deployment" | "StatefulSet" | "DaemonSet" | "ReplicaSet"
+    ) {
+        return Err(format!("cannot {} owner {} {}", owner_action, kind, name).into());
+    }
+    let resource = ApiResource::from_gvk(&GroupVersionKind::gvk("apps", "v1", kind));
+    let workloads: Api<DynamicObject> = Api::namespaced_with(client, namespace, &resource);
+    let patch = match owner_action {
+        "restart" => json!({
+            "spec": { "template": { "metadata": { "annotations": {
+                "kubectl.kubernetes.io/restartedAt": Utc::now().to_rfc3339(),
+            }}}}
+        }),
+        "scale" if kind != "DaemonSet" => {
+            let replicas: u32 = replicas
+                .ok_or("owner_action scale needs an owner_replicas label")?
+                .parse()
+                .map_err(|_| "invalid owner_replicas")?;
+            json!({ "spec": { "replicas": replicas } })
+        }
+        other => return Err(format!("cannot {} owner {} {}", other, kind, name).into()),
+    };
+    let pp = PatchParams {
+        dry_run,
+        ..Default::default()
+    };
+    workloads.patch(name, &pp, &Patch::Merge(&patch)).await?;
+    info!(
+        "Ran {} on {} {} in namespace {}",
+        owner_action, kind, name, namespace
+    );
+    Ok(())
+}
+
+/// Annotation on a Deployment recording the pod-template-hash of the revision rolled back from
+pub const ROLLED_BACK_FROM_ANNOTATION: &str = "alert-actor/rolled-back-from";
+
+fn revision(replica_set: &ReplicaSet) -> Option<u64> {
+    replica_set
+        .annotations()
+        .get("deployment.kubernetes.io/revision")?
+        .parse()
+        .ok()
+}
+
+/// Roll a Deployment back to its previous revision like `kubectl rollout undo`, by copying the pod
+/// template of the ReplicaSet with the next lower revision. Only rollouts younger than
+/// --rollback-max-age-secs are undone, and never back onto the revision a rollback left, so two
+/// bad revisions can't be flipped between forever.
+pub async fn rollback_deployment(
+    args: &Args,
+    client: Client,
+    namespace: &str,
+    name: &str,
+    dry_run: bool,
+) -> Result<(), Box<dyn Error>> {
+    let deployments: Api<Deployment> = Api::namespaced(client.clone(), namespace);
+    let mut deployment = deployments.get(name).await?;
+    let uid = deployment.uid().ok_or("deployment has no uid")?;
+    let replica_sets: Api<ReplicaSet> = Api::namespaced(client, namespace);
+    let mut owned: Vec<(u64, ReplicaSet)> = replica_sets
+        .list(&ListParams::default())
+        .await?
+        .items
+        .into_iter()
+        .filter(|rs| rs.owner_references().iter().any(|owner| owner.uid == uid))
+        .filter_map(|rs| Some((revision(&rs)?, rs)))
+        .collect();
+    owned.sort_by_key(|(revision, _)| std::cmp::Reverse(*revision));
+    let [(current_revision, current), (previous_revision, previous), ..] = owned.as_slice() else {
+        return Err(format!("deployment {}/{} has no previous revision", namespace, name).into());
+    };
+    let created = current
+        .creation_timestamp()
+        .ok_or("ReplicaSet has no creation timestamp")?;
+    let age = (Utc::now() - created.0).num_seconds().max(0) as u64;
+    if age > args.rollback_max_age_secs {
+        return Err(Skipped(format!(
+            "revision {} of deployment {}/{} was rolled out {}s ago, longer than {}s",
+            current_revision, namespace, name, age, args.rollback_max_age_secs
+        ))
+        .into());
+    }
+    let hash = |rs: &ReplicaSet| rs.labels().get("pod-template-hash").cloned();
+    if hash(previous).is_some()
+        && deployment.annotations().get(ROLLED_BACK_FROM_ANNOTATION) == hash(previous).as_ref()
+    {
+        return Err(Skipped(format!(
+            "deployment {}/{} was already rolled back from revision {}",
+            namespace, name, previous_revision
+        ))
+        .into());
+    }
+    let mut template = previous
+        .spec
+        .as_ref()
+        .and_then(|spec| spec.template.clone())
+        .ok_or("ReplicaSet has no pod template")?;
+    if let Some(labels) = template
+        .metadata
+        .as_mut()
+        .and_then(|meta| meta.labels.as_mut())
+    {
+        labels.remove("pod-template-hash");
+    }
+    deployment
+        .spec
+        .as_mut()
+        .ok_or("deployment has no spec")?
+        .template = template;
+    if let Some(hash) = hash(current) {
+        deployment
+            .annotations_mut()
+            .insert(ROLLED_BACK_FROM_ANNOTATION.to_string(), hash);
+    }
+    // Replacing with the resourceVersion we read fails instead of clobbering a concurrent rollout
+    let pp = PostParams {
+        dry_run,
+        ..Default::default()
+    };
+    deployments.replace(name, &pp, &deployment).await?;
+    info!(
+        "Rolled back deployment {}/{} from revision {} to {}",
+        namespace, name, current_revision, previous_revision
+    );
+    Ok(())
+}
+
+/// Delete every pod matching a label selector, refusing when more than `max_pods` match so a
+/// too broad selector can't take down a whole namespace
+pub async fn delete_pods_by_selector(
+    args: &Args,
+    client: Client,
+    labels: &Labels,
+    selector: &str,
+    namespace: &str,
+    max_pods: usize,
+    dp: &DeleteParams,
+) -> Result<(), Box<dyn Error>> {
+    let pods: Api<Pod> = Api::namespaced(client.clone(), namespace);
+    let mut matched = pods
+        .list(&ListParams::default().labels(selector))
+        .await?
+        .items;
+    if matched.is_empty() {
+        return Err(format!("no pods match selector '{}' in {}", selector, namespace).into());
+    }
+    matched.retain(|pod| match check_pod(args, labels, pod) {
+        Ok(()) => true,
+        Err(err) => {
+            info!("Leaving pod alone: {}", err);
+            false
+        }
+    });
+    if matched.is_empty() {
+        return Err(Skipped(format!(
+            "no pod matching selector '{}' in {} passes the deletion guards",
+            selector, namespace
+        ))
+        .into());
+    }
+    if matched.len() > max_pods {
+        return Err(format!(
+            "selector '{}' matches {} pods in {}, more than the limit of {}",
+            selector,
+            matched.len(),
+            namespace,
+            max_pods
+        )
+        .into());
+    }
+    for pod in matched {
+        delete_pod(
+            client.clone(),
+            &pod.name_any(),
+            namespace,
+            dp,
+            Duration::from_secs(args.foreground_wait_secs),
+        )
+        .await?;
+    }
+    Ok(())
+}
+
+/// Release a pod stuck in Terminating for longer than `stuck_after` by clearing its finalizers, or,
+/// when it has none (e.g. its node is gone), by deleting it with a grace period of 0. Pods that
+/// aren't terminating, or only recently started to, are skipped. The pod's resourceVersion
+/// guards the patch so a finalizer added meanwhile isn't dropped unseen.
+pub async fn force_finalize(
+    client: Client,
+    pod: &str,
+    namespace: &str,
+    stuck_after: Duration,
+    dry_run: bool,
+) -> Result<(), Box<dyn Error>> {
+    let pods: Api<Pod> = Api::namespaced(client, namespace);
+    let current = pods.get(pod).await?;
+    let Some(deleted_at) = &current.metadata.deletion_timestamp else {
+        return Err(Skipped(format!("pod {}/{} is not terminating", namespace, pod)).into());
+    };
+    let terminating = (Utc::now() - deleted_at.0).to_std().unwrap_or_default();
+    if terminating < stuck_after {
+        return Err(Skipped(format!(
+            "pod {}/{} has been terminating for {}s, less than {}s",
+            namespace,
+            pod,
+            terminating.as_secs(),
+            stuck_after.as_secs()
+        ))
+        .into());
+    }
+    let finalizers = current.finalizers();
+    if finalizers.is_empty() {
+        let dp = DeleteParams {
+            dry_run,
+            ..DeleteParams::default().grace_period(0)
+        };
+        pods.delete(pod, &dp).await?;
+        warn!(
+            "Force deleted pod {}/{}, terminating for {}s",
+            namespace,
+            pod,
+            terminating.as_secs()
+        );
+        return Ok(());
+    }
+    let patch = json!({
+        "metadata": {
+            "resourceVersion": current.metadata.resource_version,
+            "finalizers": null,
+        }
+    });
+    let pp = PatchParams {
+        dry_run,
+        ..Default::default()
+    };
+    pods.patch(pod, &pp, &Patch::Merge(&patch)).await?;
+    warn!(
+        "Removed finalizers {} from pod {}/{}, terminating for {}s",
+        finalizers.join(", "),
+        namespace,
+        pod,
+        terminating.as_secs()
+    );
+    Ok(())
+}
+
+/// Apply an hpa_min_replicas / hpa_max_replicas label value to the current bound: "5" sets it,
+/// "+2" or "-1" adjust it
+fn adjust_bound(name: &str, value: Option<&str>, current: i32) -> Result<i32, Box<dyn Error>> {
+    let Some(value) = value else {
+        return Ok(current);
+    };
+    let parsed = || {
+        value
+            .trim_start_matches(['+', '-'])
+            .parse::<i32>()
+            .map_err(|_| format!("invalid {} '{}'", name, value))
+    };
+    Ok(match value.chars().next() {
+        Some('+') => current.saturating_add(parsed()?),
+        Some('-') => current.saturating_sub(parsed()?),
+        _ => parsed()?,
+    })
+}
+
+/// Change the replica bounds of a HorizontalPodAutoscaler, e.g. raising minReplicas during a
+/// traffic surge. The result must keep 1 <= minReplicas <= maxReplicas <= --hpa-max-replicas.
+pub async fn hpa_adjust(
+    args: &Args,
+    client: Client,
+    namespace: &str,
+    name: &str,
+    labels: &Labels,
+    dry_run: bool,
+) -> Result<(), Box<dyn Error>> {
+    if labels.hpa_min_replicas.is_none() && labels.hpa_max_replicas.is_none() {
+        return Err("hpa_adjust needs an hpa_min_replicas or hpa_max_replicas label".into());
+    }
+    let hpas: Api<HorizontalPodAutoscaler> = Api::namespaced(client, namespace);
+    let current = hpas.get(name).await?;
+    let spec = current.spec.as_ref().ok_or("HPA has no spec")?;
+    let (min, max) = (spec.min_replicas.unwrap_or(1), spec.max_replicas);
+    let new_min = adjust_bound("hpa_min_replicas", labels.hpa_min_replicas.as_deref(), min)?;
+    let new_max = adjust_bound("hpa_max_replicas", labels.hpa_max_replicas.as_deref(), max)?;
+    if new_min < 1 || new_min > new_max || new_max as u32 > args.hpa_max_replicas {
+        return Err(format!(
+            "refusing to set HPA {}/{} to {}-{} replicas, bounds must keep 1 <= min <= max <= {}",
+            namespace, name, new_min, new_max, args.hpa_max_replicas
+        )
+        .into());
+    }
+    if (new_min, new_max) == (min, max) {
+        info!(
+            "HPA {}/{} already has {}-{} replicas",
+            namespace, name, min, max
+        );
+        return Ok(());
+    }
+    let patch = json!({
+        "metadata": { "resourceVersion": current.metadata.resource_version },
+        "spec": { "minReplicas": new_min, "maxReplicas": new_max },
+    });
+    let pp = PatchParams {
+        dry_run,
+        ..Default::default()
+    };
+    hpas.patch(name, &pp, &Patch::Merge(&patch)).await?;
+    info!(
+        "Adjusted HPA {}/{} from {}-{} to {}-{} replicas",
+        namespace, name, min, max, new_min, new_max
+    );
+    Ok(())
+}
+
+/// Set `.spec.suspend` of a CronJob, halting (or resuming) its schedule. Jobs it already started
+/// keep running.
+pub async fn suspend_cronjob(
+    client: Client,
+    namespace: &str,
+    name: &str,
+    suspend: bool,
+    dry_run: bool,
+) -> Result<(), Box<dyn Error>> {
+    let cronjobs: Api<CronJob> = Api::namespaced(client, namespace);
+    let pp = PatchParams {
+        dry_run,
+        ..Default::default()
+    };
+    let patch = json!({ "spec": { "suspend": suspend } });
+    cronjobs.patch(name, &pp, &Patch::Merge(&patch)).await?;
+    info!(
+        "{} cronjob {}/{}",
+        if suspend { "Suspended" } else { "Resumed" },
+        namespace,
+        name
+    );
+    Ok(())
+}
+
+/// Delete pods in the given terminal phases (Failed, which includes Evicted, and Succeeded), oldest
+/// first and at most `max_pods` per run, optionally narrowed by a label selector
+pub async fn cleanup_pods(
+    client: Client,
+    namespace: &str,
+    phases: &[&str],
+    selector: Option<&str>,
+    max_pods: usize,
+    dp: &DeleteParams,
+    foreground_wait: Duration,
+) -> Result<(), Box<dyn Error>> {
+    let pods: Api<Pod> = Api::namespaced(client.clone(), namespace);
+    let mut finished = Vec::new();
+    for phase in phases {
+        if !matches!(*phase, "Failed" | "Succeeded") {
+            return Err(format!("cannot clean up pods in phase '{}'", phase).into());
+        }
+        let mut lp = ListParams::default().fields(&format!("status.phase={}", phase));
+        if let Some(selector) = selector {
+            lp = lp.labels(selector);
+        }
+        finished.extend(pods.list(&lp).await?.items);
+    }
+    if finished.is_empty() {
+        info!("No {} pods to clean up in {}", phases.join("/"), namespace);
+        return Ok(());
+    }
+    finished.sort_by_key(|pod| pod.creation_timestamp());
+    let total = finished.len();
+    finished.truncate(max_pods);
+    for pod in &finished {
+        delete_pod(
+            client.clone(),
+            &pod.name_any(),
+            namespace,
+            dp,
+            foreground_wait,
+        )
+        .await?;
+    }
+    info!(
+        "Cleaned up {} of {} {} pods in {}",
+        finished.len(),
+        total,
+        phases.join("/"),
+        namespace
+    );
+    Ok(())
+}
+
+/// Delete the pods of a DaemonSet running on one node, so the DaemonSet recreates them there
+pub async fn restart_daemonset_pod(
+    client: Client,
+    daemonset: &str,
+    node: &str,
+    namespace: &str,
+    dp: &DeleteParams,
+    foreground_wait: Duration,
+) -> Result<(), Box<dyn Error>> {
+    let pods: Api<Pod> = Api::namespaced(client.clone(), namespace);
+    let on_node = pods
+        .list(&ListParams::default().fields(&format!("spec.nodeName={}", node)))
+        .await?
+        .items;
+    // Ownership, not labels, tells which DaemonSet a pod belongs to
+    let owned: Vec<Pod> = on_node
+        .into_iter()
+        .filter(|pod| {
+            pod.owner_references()
+                .iter()
+                .any(|owner| owner.kind == "DaemonSet" && owner.name == daemonset)
+        })
+        .collect();
+    if owned.is_empty() {
+        return Err(format!(
+            "no pod of DaemonSet {}/{} runs on node {}",
+            namespace, daemonset, node
+        )
+        .into());
+    }
+    for pod in owned {
+        delete_pod(
+            client.clone(),
+            &pod.name_any(),
+            namespace,
+            dp,
+            foreground_wait,
+        )
+        .await?;
+    }
+    Ok(())
+}
+
+/// Taint taint_node applies for the alert, from its taint_key, taint_value and taint_effect
+/// labels or the flags
+pub fn node_taint(args: &Args, labels: &Labels) -> Taint {
+    let value = labels.taint_value.as_deref().unwrap_or(&args.taint_value);
+    Taint {
+        key: labels
+            .taint_key
+            .as_deref()
+            .unwrap_or(&args.taint_key)
+            .to_string(),
+        value: Some(value.to_string()).filter(|value| !value.is_empty()),
+        effect: labels
+            .taint_effect
+            .as_deref()
+            .unwrap_or(&args.taint_effect)
+            .to_string(),
+        time_added: None,
+    }
+}
+
+/// Seconds until a taint put on by taint_node is taken off again, from the alert's
+/// taint_ttl_secs label or --taint-ttl-secs. Zero keeps it until a human removes it.
+pub fn taint_ttl(args: &Args, labels: &Labels) -> Result<u64, String> {
+    match &labels.taint_ttl_secs {
+        Some(value) => value
+            .parse()
+            .map_err(|_| format!("invalid taint_ttl_secs '{}'", value)),
+        None => Ok(args.taint_ttl_secs),
+    }
+}
+
+/// A taint put on by taint_node that is taken off again once it expires
+#[derive(Debug, Clone, Deserialize, Serialize, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct TrackedTaint {
+    /// Remote cluster of the node, None for the local one
+    pub cluster: Option<String>,
+    pub node: String,
+    pub key: String,
+    pub effect: String,
+    /// Unix timestamp at which the taint is removed
+    pub expires_at: i64,
+}
+
+/// Remove a taint by key and effect, if the node still has it
+pub async fn untaint_node(
+    client: Client,
+    node: &str,
+    key: &str,
+    effect: &str,
+) -> Result<(), Box<dyn Error>> {
+    let nodes: Api<Node> = Api::all(client);
+    let Some(current) = nodes.get_opt(node).await? else {
+        return Ok(());
+    };
+    let taints = current
+        .spec
+        .and_then(|spec| spec.taints)
+        .unwrap_or_default();
+    let kept: Vec<&Taint> = taints
+        .iter()
+        .filter(|taint| taint.key != key || taint.effect != effect)
+        .collect();
+    if kept.len() == taints.len() {
+        return Ok(());
+    }
+    let patch = json!({
+        "metadata": { "resourceVersion": current.metadata.resource_version },
+        "spec": { "taints": kept },
+    });
+    nodes
+        .patch(node, &PatchParams::default(), &Patch::Merge(&patch))
+        .await?;
+    info!("Removed taint {}:{} from node {}", key, effect, node);
+    Ok(())
+}
+
+/// Take off the tracked taints that have expired. Failures are retried next time. Returns
+/// whether the tracked set changed.
+pub async fn revert_taints(
+    args: &Args,
+    clusters: &Clusters,
+    tracked: &mut Vec<TrackedTaint>,
+) -> bool {
+    let now = Utc::now().timestamp();
+    let before = tracked.len();
+    let mut kept = Vec::new();
+    for taint in tracked.drain(..) {
+        if now < taint.expires_at {
+            kept.push(taint);
+            continue;
+        }
+        let remote = Clusters::remote_cluster(args, taint.cluster.as_deref());
+        let untainted = match clusters.client_in(remote, None) {
+            Ok(client) => untaint_node(client, &taint.node, &taint.key, &taint.effect).await,
+            Err(err) => Err(err),
+        };
+        match untainted {
+            Ok(()) => METRICS.inc("alert_actor_taints_reverted_total", &[]),
+            Err(err) => {
+                warn!(
+                    "Failed to remove expired taint {} from node {}: {}",
+                    taint.key, taint.node, err
+                );
+                kept.push(taint);
+            }
+        }
+    }
+    *tracked = kept;
+    tracked.len() != before
+}
+
+/// Add a taint to a node, or update the value of a taint with the same key and effect. The
+/// node's resourceVersion guards the read-modify-write of its taints against concurrent changes.
+pub async fn taint_node(
+    client: Client,
+    node: &str,
+    taint: Taint,
+    dry_run: bool,
+) -> Result<(), Box<dyn Error>> {
+    if !matches!(
+        taint.effect.as_str(),
+        "NoSchedule" | "PreferNoSchedule" | "NoExecute"
+    ) {
+        return Err(format!("invalid taint effect '{}'", taint.effect).into());
+    }
+    let nodes: Api<Node> = Api::all(client);
+    let current = nodes.get(node).await?;
+    let mut taints = current
+        .spec
+        .and_then(|spec| spec.taints)
+        .unwrap_or_default();
+    match taints
+        .iter_mut()
+        .find(|existing| existing.key == taint.key && existing.effect == taint.effect)
+    {
+        Some(existing) if existing.value == taint.value => {
+            info!("Node {} already has taint {}", node, taint.key);
+            return Ok(());
+        }
+        Some(existing) => existing.value = taint.value.clone(),
+        None => taints.push(taint.clone()),
+    }
+    let patch = json!({
+        "metadata": { "resourceVersion": current.metadata.resource_version },
+        "spec": { "taints": taints },
+    });
+    let pp = PatchParams {
+        dry_run,
+        ..Default::default()
+    };
+    nodes.patch(node, &pp, &Patch::Merge(&patch)).await?;
+    info!(
+        "Tainted node {} with {}={}:{}",
+        node,
+        taint.key,
+        taint.value.unwrap_or_default(),
+        taint.effect
+    );
+    Ok(())
+}
+
+/// How long the alert's action may run, from its action_timeout_secs label or
+/// --action-timeout-secs. Zero means no limit.
+pub fn action_timeout(args: &Args, labels: &Labels) -> Result<Duration, String> {
+    match &labels.action_timeout_secs {
+        Some(value) => value
+            .parse()
+            .map(Duration::from_secs)
+            .map_err(|_| format!("invalid action_timeout_secs '{}'", value)),
+        None => Ok(Duration::from_secs(args.action_timeout_secs)),
+    }
+}
+
+/// Whether Kubernetes changes for the alert are only validated by the API server (dryRun=All),
+/// from its server_dry_run label or --server-dry-run
+pub fn server_dry_run(args: &Args, labels: &Labels) -> bool {
+    match labels.server_dry_run.as_deref() {
+        Some(value) => value == "true",
+        None => args.server_dry_run,
+    }
+}
+
+/// Delete options from the alert's grace_period_seconds, force, propagation_policy and
+/// server_dry_run labels
+pub fn delete_params(args: &Args, labels: &Labels) -> Result<DeleteParams, Box<dyn Error>> {
+    let mut dp = DeleteParams {
+        dry_run: server_dry_run(args, labels),
+        ..Default::default()
+    };
+    if let Some(seconds) = &labels.grace_period_seconds {
+        dp = dp.grace_period(
+            seconds
+                .parse()
+                .map_err(|_| format!("invalid grace_period_seconds '{}'", seconds))?,
+        );
+    }
+    if labels.force.as_deref() == Some("true") {
+        dp = dp.grace_period(0);
+    }
+    dp.propagation_policy = match labels.propagation_policy.as_deref() {
+        None => None,
+        Some("Foreground") => Some(PropagationPolicy::Foreground),
+        Some("Background") => Some(PropagationPolicy::Background),
+        Some("Orphan") => Some(PropagationPolicy::Orphan),
+        Some(other) => return Err(format!("invalid propagation_policy '{}'", other).into()),
+    };
+    Ok(dp)
+}
+
+pub async fn delete_pod(
+    client: Client,
+    pod: &str,
+    namespace: &str,
+    dp: &DeleteParams,
+    foreground_wait: Duration,
+) -> Result<(), Box<dyn Error>> {
+    let pods: Api<Pod> = Api::namespaced(client, namespace);
+    let deleting = pods.delete(pod, dp).await?;
+    if dp.dry_run {
+        info!(
+            "Deletion of pod {} in namespace {} passed server-side dry-run",
+            pod, namespace
+        );
+        return Ok(());
+    }
+    // Foreground deletion only returns once the pod is marked for deletion, wait until its
+    // dependents and then the pod itself are gone so the action only succeeds once they are
+    if let (Some(PropagationPolicy::Foreground), Some(deleting)) =
+        (&dp.propagation_policy, deleting.left())
+    {
+        let uid = deleting.uid();
+        let deadline = tokio::time::Instant::now() + foreground_wait;
+        while let Some(current) = pods.get_opt(pod).await? {
+            // A pod recreated under the same name is a different one
+            if current.uid() != uid {
+                break;
+            }
+            if tokio::time::Instant::now() >= deadline {
+                return Err(format!(
+                    "pod {} in namespace {} is still being deleted after {}s",
+                    pod,
+                    namespace,
+                    foreground_wait.as_secs()
+                )
+                .into());
+            }
+            tokio::time::sleep(Duration::from_secs(1)).await;
+        }
+    }
+    info!("Deleted pod {} in namespace {}", pod, namespace);
+    Ok(())
+}
+
+/// Parse a Kubernetes resource quantity ("250m", "1.5", "128Mi", "2G") into base units
+pub fn parse_quantity(quantity: &str) -> Option<f64> {
+    const SUFFIXES: [(&str, f64); 15] = [
+        ("Ki", 1024.0),
+        ("Mi", 1048576.0),
+        ("Gi", 1073741824.0),
+        ("Ti", 1099511627776.0),
+        ("Pi", 1125899906842624.0),
+        ("Ei", 1152921504606846976.0),
+        ("n", 1e-9),
+        ("u", 1e-6),
+        ("m", 1e-3),
+        ("k", 1e3),
+        ("M", 1e6),
+        ("G", 1e9),
+        ("T", 1e12),
+        ("P", 1e15),
+        ("E", 1e18),
+    ];
+    for (suffix, multiplier) in SUFFIXES {
+        if let Some(number) = quantity.strip_suffix(suffix) {
+            return number.parse::<f64>().ok().map(|n| n * multiplier);
+        }
+    }
+    quantity.parse().ok()
+}
+
+/// Environment handed to commands and Jobs: `ALERT_FINGERPRINT`, `ALERT_STATE` and an
+/// `ALERT_LABEL_<NAME>` variable per label
+fn alert_env(alert: &Alert) -> Result<Vec<(String, String)>, Box<dyn Error>> {
+    let mut env = vec![
+        ("ALERT_FINGERPRINT".to_string(), alert.fingerprint.clone()),
+        ("ALERT_STATE".to_string(), alert.status.state.clone()),
+    ];
+    if let Value::Object(labels) = serde_json::to_value(&alert.labels)? {
+        for (label, value) in labels {
+            if let Value::String(value) = value {
+                let variable: String = label
+                    .chars()
+                    .map(|c| match c {
+                        'a'..='z' | 'A'..='Z' | '0'..='9' => c.to_ascii_uppercase(),
+                        _ => '_',
+                    })
+                    .collect();
+                env.push((format!("ALERT_LABEL_{}", variable), value));
+            }
+        }
+    }
+    Ok(env)
+}
+
+/// Create a Job from a --job-templates entry rendered against the alert, with the alert's labels
+/// added to the environment of every container. The Job runs in the template's namespace, or
+/// the alert's if the template has none. Returns the namespaced name of the created Job.
+pub async fn run_job(
+    args: &Args,
+    client: Client,
+    name: &str,
+    alert: &Alert,
+) -> Result<String, Box<dyn Error>> {
+    let path = args
+        .job_templates
+        .iter()
+        .find_map(|entry| entry.strip_prefix(name)?.strip_prefix('='))
+        .ok_or_else(|| format!("unknown job template '{}'", name))?;
+    let template = tokio::fs::read_to_string(path).await?;
+    let mut job: Job = serde_yaml::from_str(&render_template(&template, alert))
+        .map_err(|err| format!("invalid job template '{}': {}", name, err))?;
+
+    let namespace = job
+        .metadata
+        .namespace
+        .clone()
+        .or_else(|| alert.labels.namespace.clone())
+        .ok_or("job template and alert both lack a namespace")?;
+    if job.metadata.name.is_none() && job.metadata.generate_name.is_none() {
+        job.metadata.generate_name = Some(format!("{}-", name));
+    }
+    let labels = job.metadata.labels.get_or_insert_with(BTreeMap::new);
+    labels.insert(
+        "app.kubernetes.io/managed-by".to_string(),
+        "alert-actor".to_string(),
+    );
+    labels.insert("alert-actor/job-template".to_string(), name.to_string());
+
+    let spec = job.spec.as_mut().ok_or("job template has no spec")?;
+    spec.ttl_seconds_after_finished
+        .get_or_insert(args.job_ttl_secs);
+    let env: Vec<EnvVar> = alert_env(alert)?
+        .into_iter()
+        .map(|(name, value)| EnvVar {
+            name,
+            value: Some(value),
+            ..Default::default()
+        })
+        .collect();
+    if let Some(pod_spec) = spec.template.spec.as_mut() {
+        let init_containers = pod_spec.init_containers.iter_mut().flatten();
+        for container in pod_spec.containers.iter_mut().chain(init_containers) {
+            container
+                .env
+                .get_or_insert_with(Vec::new)
+                .extend(env.iter().cloned());
+        }
+    }
+
+    let jobs: Api<Job> = Api::namespaced(client, &namespace);
+    let pp = PostParams {
+        dry_run: server_dry_run(args, &alert.labels),
+        ..Default::default()
+    };
+    let job = jobs.create(&pp, &job).await?;
+    Ok(format!("{}/{}", namespace, job.name_any()))
+}
+
+/// Output lines of a command that are logged per stream; the rest is dropped
+const COMMAND_OUTPUT_MAX_LINES: usize = 50;
+
+/// Run a --commands executable with the alert as JSON on stdin and its labels in `ALERT_LABEL_*`
+/// environment variables. The command fails the action if it exits non-zero or times out.
+pub async fn run_command(args: &Args, name: &str, alert: &Alert) -> Result<(), Box<dyn Error>> {
+    let command_line = args
+        .commands
+        .iter()
+        .find_map(|entry| entry.strip_prefix(name)?.strip_prefix('='))
+        .ok_or_else(|| format!("unknown command '{}'", name))?;
+    let mut parts = command_line.split_whitespace();
+    let program = parts
+        .next()
+        .ok_or_else(|| format!("command '{}' has no executable", name))?;
+
+    let mut command = Command::new(program);
+    command
+        .args(parts)
+        .envs(alert_env(alert)?)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .kill_on_drop(true);
+
+    let mut child = command.spawn()?;
+    let mut stdin = child.stdin.take().ok_or("command stdin unavailable")?;
+    let input = serde_json::to_vec(alert)?;
+    // Write stdin while reading the output, a command may not read its input before writing
+    let run = async {
+        let (written, output) = tokio::join!(
+            async move {
+                let written = stdin.write_all(&input).await;
+                drop(stdin);
+                written
+            },
+            child.wait_with_output()
+        );
+        match written {
+            // A command is free to ignore its input
+            Err(err) if err.kind() != std::io::ErrorKind::BrokenPipe => Err(err),
+            _ => output,
+        }
+    };
+    // The child is killed when the timeout drops it
+    let output = tokio::time::timeout(Duration::from_secs(args.command_timeout_secs), run)
+        .await
+        .map_err(|_| {
+            format!(
+                "command '{}' timed out after {}s",
+                name, args.command_timeout_secs
+            )
+        })??;
+
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    for line in stdout.lines().take(COMMAND_OUTPUT_MAX_LINES) {
+        info!("[command {}] {}", name, line);
+    }
+    for line in stderr.lines().take(COMMAND_OUTPUT_MAX_LINES) {
+        warn!("[command {}] {}", name, line);
+    }
+    if !output.status.success() {
+        let last_line = stderr.lines().last().unwrap_or_default();
+        return Err(format!("command '{}' {}: {}", name, output.status, last_line).into());
+    }
+    Ok(())
+}
+
+/// Compare a pod's live usage from the metrics API with its requests and recommend new requests.
+/// The recommendation is posted to the alert's webhook_url, or recorded as a pod annotation.
+pub async fn suggest_rightsizing(
+    args: &Args,
+    client: &Client,
+    alert: &Alert,
+    pod: &str,
+    namespace: &str,
+) -> Result<(), Box<dyn Error>> {
+    let gvk = GroupVersionKind::gvk("metrics.k8s.io", "v1beta1", "PodMetrics");
+    let resource = ApiResource::from_gvk_with_plural(&gvk, "pods");
+    let metrics: Api<DynamicObject> = Api::namespaced_with(client.clone(), namespace, &resource);
+    let usage = metrics.get(pod).await?;
+    let pods: Api<Pod> = Api::namespaced(client.clone(), namespace);
+    let spec = pods.get(pod).await?.spec.unwrap_or_default();
+
+    let headroom = 1.0 + args.rightsizing_headroom_percent as f64 / 100.0;
+    let mut containers = Vec::new();
+    for container in usage.data["containers"].as_array().into_iter().flatten() {
+        let name = container["name"].as_str().unwrap_or_default();
+        let usage_of = |resource: &str| {
+            container["usage"][resource]
+                .as_str()
+                .and_then(parse_quantity)
+        };
+        let requests = spec
+            .containers
+            .iter()
+            .find(|c| c.name == name)
+            .and_then(|c| c.resources.as_ref()?.requests.clone())
+            .unwrap_or_default();
+        let request_of = |resource: &str| requests.get(resource).and_then(|q| parse_quantity(&q.0));
+        let (cpu, memory) = (usage_of("cpu"), usage_of("memory"));
+        containers.push(json!({
+            "container": name,
+            "cpu_usage_cores": cpu,
+            "cpu_request_cores": request_of("cpu"),
+            "suggested_cpu_request": cpu.map(|c| format!("{}m", (c * headroom * 1000.0).ceil())),
+            "memory_usage_bytes": memory,
+            "memory_request_bytes": request_of("memory"),
+            "suggested_memory_request": memory.map(|m| format!("{}Mi", (m * headroom / 1048576.0).ceil())),
+        }));
+    }
+    let recommendation = json!({
+        "pod": pod,
+        "namespace": namespace,
+        "alertname": alert.labels.alertname,
+        "headroom_percent": args.rightsizing_headroom_percent,
+        "containers": containers,
+    });
+
+    if let Some(url) = &alert.labels.webhook_url {
+        let http = alert_client(args, alert)?;
+        http.post(url)
+            .json(&recommendation)
+            .send()
+            .await?
+            .error_for_status()?;
+        info!(
+            "Sent rightsizing suggestion for pod {} in namespace {}",
+            pod, namespace
+        );
+    } else {
+        let patch = json!({
+            "metadata": {"annotations": {"alert-actor/rightsizing": recommendation.to_string()}}
+        });
+        let pp = PatchParams {
+            dry_run: server_dry_run(args, &alert.labels),
+            ..Default::default()
+        };
+        pods.patch(pod, &pp, &Patch::Merge(&patch)).await?;
+        info!(
+            "Annotated pod {} in namespace {} with rightsizing suggestion",
+            pod, namespace
+        );
+    }
+    Ok(())
+}
+
+/// An action that passed every check of a cycle and waits to be executed
+pub struct PlannedAction {
+    pub alert: Alert,
+    pub action: String,
+    pub target: String,
+    pub rule: Option<Arc<AlertRemediationRule>>,
+    pub require_approval: bool,
+    /// Shard of the alert, whose leader keeps track of the action's mitigations
+    pub shard: usize,
+}
+
+/// Run the named action for an alert within the cycle's context
+pub async fn execute_action(
+    ctx: &ExecContext,
+    action: &str,
+    alert: &Alert,
+    args: &Args,
+    client: &Client,
+    webhook_template: Option<&str>,
+) -> Result<(), Box<dyn Error>> {
+    if ctx.dry_run {
+        info!(
+            "[dry-run] would run {} for alert {} (cycle {})",
+            action, alert.fingerprint, ctx.correlation_id
+        );
+        return Ok(());
+    }
+    let timeout = action_timeout(args, &alert.labels)?;
+    let run = async {
+        if args.rbac_check_actions && is_kube_action(action) {
+            check_action(args, client, action, alert).await?;
+        }
+        let target = target_key(alert);
+        let attributes = [
+            ("alert_actor.action", action),
+            ("alert_actor.fingerprint", alert.fingerprint.as_str()),
+            ("alert_actor.target", target.as_str()),
+        ];
+        ctx.traced(
+            "action",
+            &attributes,
+            run_action(action, alert, args, client, webhook_template),
+        )
+        .await
+    };
+    if timeout.is_zero() {
+        return run.await;
+    }
+    // A hung API call or webhook would otherwise hold its slot for the rest of the cycle
+    match tokio::time::timeout(timeout, run).await {
+        Ok(result) => result,
+        Err(_) => Err(TimedOut(timeout).into()),
+    }
+}
+
+/// Run the named action from the registry
+pub async fn run_action(
+    action: &str,
+    alert: &Alert,
+    args: &Args,
+    client: &Client,
+    webhook_template: Option<&str>,
+) -> ActionResult {
+    let Some(handler) = registry().get(action) else {
+        return Err(format!("unknown action '{}'", action).into());
+    };
+    let ctx = ActionContext {
+        args,
+        client,
+        webhook_template,
+    };
+    handler.execute(&ctx, alert).await
+}
+
+/// Result of running an action
+pub type ActionResult = Result<(), Box<dyn Error>>;
+
+/// What actions are run with besides the alert
+pub struct ActionContext<'a> {
+    pub args: &'a Args,
+    pub client: &'a Client,
+    pub webhook_template: Option<&'a str>,
+}
+
+/// A remediation or notification that alerts (and rules) can name in their action label
+pub trait Action: Send + Sync {
+    /// Whether the action needs the Kubernetes API, and so has to wait while it is unreachable
+    fn needs_kube(&self) -> bool {
+        false
+    }
+
+    /// Whether the action deletes, evicts, scales or rolls back workloads, and so must never
+    /// touch protected namespaces
+    fn destructive(&self) -> bool {
+        false
+    }
+
+    /// Whether the action stays within the alert's namespace (or touches nothing in the
+    /// cluster), so team rules may use it
+    fn namespaced(&self) -> bool {
+        false
+    }
+
+    /// Run the action for the alert
+    fn execute<'a>(
+        &'a self,
+        ctx: &'a ActionContext<'a>,
+        alert: &'a Alert,
+    ) -> BoxFuture<'a, ActionResult>;
+}
+
+/// Actions by name
+#[derive(Default)]
+pub struct ActionRegistry {
+    actions: BTreeMap<String, Box<dyn Action>>,
+}
+
+impl ActionRegistry {
+    /// Registry of every action this crate implements
+    pub fn builtin() -> ActionRegistry {
+        let mut registry = ActionRegistry::default();
+        registry.register("delete_pod", DeletePod);
+        registry.register("delete_pods_by_selector", DeletePodsBySelector);
+        registry.register("cleanup_pods", CleanupPods);
+        registry.register("force_finalize", ForceFinalize);
+        registry.register("hpa_adjust", HpaAdjust);
+        registry.register("suspend_cronjob", SuspendCronJob { suspend: true });
+        registry.register("resume_cronjob", SuspendCronJob { suspend: false });
+        registry.register("rollback", Rollback);
+        registry.register("restart_daemonset_pod", RestartDaemonSetPod);
+        registry.register("taint_node", TaintNode);
+        registry.register("run_job", RunJob);
+        registry.register("suggest_rightsizing", SuggestRightsizing);
+        registry.register("webhook", Webhook);
+        registry.register("pagerduty", PagerDuty);
+        registry.register("opsgenie", OpsGenie);
+        registry.register("teams", Teams);
+        registry.register("discord", Discord);
+        registry.register("email", Email);
+        registry.register("command", RunCommand);
+        registry
+    }
+
+    /// Add an action, replacing any registered under the same name
+    pub fn register(&mut self, name: &str, action: impl Action + 'static) {
+        self.actions.insert(name.to_string(), Box::new(action));
+    }
+
+    pub fn get(&self, name: &str) -> Option<&dyn Action> {
+        self.actions.get(name).map(Box::as_ref)
+    }
+
+    /// Names of the registered actions, sorted
+    pub fn names(&self) -> impl Iterator<Item = &str> {
+        self.actions.keys().map(String::as_str)
+    }
+}
+
+/// Actions the controller runs. Embedders may set their own registry (e.g. the builtin one with
+/// extra actions) before starting it; otherwise the builtin registry is used.
+pub static ACTIONS: OnceLock<ActionRegistry> = OnceLock::new();
+
+/// The registry in use
+pub fn registry() -> &'static ActionRegistry {
+    ACTIONS.get_or_init(ActionRegistry::builtin)
+}
+
+/// Deletes the alert's pod, or acts on its owning workload with owner_action
+pub struct DeletePod;
+
+impl Action for DeletePod {
+    fn namespaced(&self) -> bool {
+        true
+    }
+
+    fn needs_kube(&self) -> bool {
+        true
+    }
+
+    fn destructive(&self) -> bool {
+        true
+    }
+
+    fn execute<'a>(
+        &'a self,
+        ctx: &'a ActionContext<'a>,
+        alert: &'a Alert,
+    ) -> BoxFuture<'a, ActionResult> {
+        let (args, client) = (ctx.args, ctx.client);
+        Box::pin(async move {
+            let (Some(pod), Some(namespace)) = (&alert.labels.pod, &alert.labels.namespace) else {
+                return Err("alert is missing pod or namespace".into());
+            };
+            let dp = delete_params(args, &alert.labels)?;
+            if pod_guards_enabled(args, &alert.labels) {
+                let pods: Api<Pod> = Api::namespaced(client.clone(), namespace);
+                check_pod(args, &alert.labels, &pods.get(pod).await?)?;
+            }
+            // Deleting a managed pod is often whack-a-mole, so rules can act on its owner instead
+            if let Some(owner_action) = &alert.labels.owner_action {
+                let (kind, name) = owning_workload(client.clone(), pod, namespace).await?;
+                return act_on_owner(
+                    client.clone(),
+                    namespace,
+                    &kind,
+                    &name,
+                    owner_action,
+                    alert.labels.owner_replicas.as_deref(),
+                    dp.dry_run,
+                )
+                .await;
+            }
+            delete_pod(
+                client.clone(),
+                pod,
+                namespace,
+                &dp,
+                Duration::from_secs(args.foreground_wait_secs),
+            )
+            .await
+        })
+    }
+}
+
+/// Deletes the pods matching the alert's selector
+pub struct DeletePodsBySelector;
+
+impl Action for DeletePodsBySelector {
+    fn namespaced(&self) -> bool {
+        true
+    }
+
+    fn needs_kube(&self) -> bool {
+        true
+    }
+
+    fn destructive(&self) -> bool {
+        true
+    }
+
+    fn execute<'a>(
+        &'a self,
+        ctx: &'a ActionContext<'a>,
+        alert: &'a Alert,
+    ) -> BoxFuture<'a, ActionResult> {
+        let (args, client) = (ctx.args, ctx.client);
+        Box::pin(async move {
+            let (Some(selector), Some(namespace)) =
+                (&alert.labels.selector, &alert.labels.namespace)
+            else {
+                return Err("alert is missing selector or namespace".into());
+            };
+            let max_pods = match &alert.labels.max_pods {
+                Some(max) => max
+                    .parse()
+                    .map_err(|_| format!("invalid max_pods '{}'", max))?,
+                None => args.selector_max_pods,
+            };
+            let dp = delete_params(args, &alert.labels)?;
+            delete_pods_by_selector(
+                args,
+                client.clone(),
+                &alert.labels,
+                selector,
+                namespace,
+                max_pods,
+                &dp,
+            )
+            .await
+        })
+    }
+}
+
+/// Deletes finished pods of the alert's namespace
+pub struct CleanupPods;
+
+impl Action for CleanupPods {
+    fn namespaced(&self) -> bool {
+        true
+    }
+
+    fn needs_kube(&self) -> bool {
+        true
+    }
+
+    fn destructive(&self) -> bool {
+        true
+    }
+
+    fn execute<'a>(
+        &'a self,
+        ctx: &'a ActionContext<'a>,
+        alert: &'a Alert,
+    ) -> BoxFuture<'a, ActionResult> {
+        let (args, client) = (ctx.args, ctx.client);
+        Box::pin(async move {
+            let Some(namespace) = &alert.labels.namespace else {
+                return Err("alert is missing namespace".into());
+            };
+            let phases: Vec<&str> = match &alert.labels.cleanup_phases {
+                Some(phases) => phases.split(',').map(str::trim).collect(),
+                None => vec!["Failed"],
+            };
+            let max_pods = match &alert.labels.max_pods {
+                Some(max) => max
+                    .parse()
+                    .map_err(|_| format!("invalid max_pods '{}'", max))?,
+                None => args.cleanup_max_pods,
+            };
+            let dp = delete_params(args, &alert.labels)?;
+            cleanup_pods(
+                client.clone(),
+                namespace,
+                &phases,
+                alert.labels.selector.as_deref(),
+                max_pods,
+                &dp,
+                Duration::from_secs(args.foreground_wait_secs),
+            )
+            .await
+        })
+    }
+}
+
+/// Releases a pod stuck terminating, if --allow-force-finalize is set
+pub struct ForceFinalize;
+
+impl Action for ForceFinalize {
+    fn namespaced(&self) -> bool {
+        true
+    }
+
+    fn needs_kube(&self) -> bool {
+        true
+    }
+
+    fn destructive(&self) -> bool {
+        true
+    }
+
+    fn execute<'a>(
+        &'a self,
+        ctx: &'a ActionContext<'a>,
+        alert: &'a Alert,
+    ) -> BoxFuture<'a, ActionResult> {
+        let (args, client) = (ctx.args, ctx.client);
+        Box::pin(async move {
+            if !args.allow_force_finalize {
+                return Err(
+                    "force_finalize is disabled, enable it with --allow-force-finalize".into(),
+                );
+            }
+            let (Some(pod), Some(namespace)) = (&alert.labels.pod, &alert.labels.namespace) else {
+                return Err("alert is missing pod or namespace".into());
+            };
+            force_finalize(
+                client.clone(),
+                pod,
+                namespace,
+                Duration::from_secs(args.force_finalize_after_secs),
+                server_dry_run(args, &alert.labels),
+            )
+            .await
+        })
+    }
+}
+
+/// Changes the replica bounds of the alert's HorizontalPodAutoscaler
+pub struct HpaAdjust;
+
+impl Action for HpaAdjust {
+    fn namespaced(&self) -> bool {
+        true
+    }
+
+    fn needs_kube(&self) -> bool {
+        true
+    }
+
+    fn destructive(&self) -> bool {
+        true
+    }
+
+    fn execute<'a>(
+        &'a self,
+        ctx: &'a ActionContext<'a>,
+        alert: &'a Alert,
+    ) -> BoxFuture<'a, ActionResult> {
+        let (args, client) = (ctx.args, ctx.client);
+        Box::pin(async move {
+            let (Some(hpa), Some(namespace)) = (&alert.labels.hpa, &alert.labels.namespace) else {
+                return Err("alert is missing hpa or namespace".into());
+            };
+            hpa_adjust(
+                args,
+                client.clone(),
+                namespace,
+                hpa,
+                &alert.labels,
+                server_dry_run(args, &alert.labels),
+            )
+            .await
+        })
+    }
+}
+
+/// Suspends, or resumes, the alert's CronJob
+pub struct SuspendCronJob {
+    pub suspend: bool,
+}
+
+impl Action for SuspendCronJob {
+    fn namespaced(&self) -> bool {
+        true
+    }
+
+    fn needs_kube(&self) -> bool {
+        true
+    }
+
+    fn execute<'a>(
+        &'a self,
+        ctx: &'a ActionContext<'a>,
+        alert: &'a Alert,
+    ) -> BoxFuture<'a, ActionResult> {
+        let (args, client) = (ctx.args, ctx.client);
+        Box::pin(async move {
+            let (Some(cronjob), Some(namespace)) = (&alert.labels.cronjob, &alert.labels.namespace)
+            else {
+                return Err("alert is missing cronjob or namespace".into());
+            };
+            suspend_cronjob(
+                client.clone(),
+                namespace,
+                cronjob,
+                self.suspend,
+                server_dry_run(args, &alert.labels),
+            )
+            .await
+        })
+    }
+}
+
+/// Rolls the alert's Deployment back to its previous revision
+pub struct Rollback;
+
+impl Action for Rollback {
+    fn namespaced(&self) -> bool {
+        true
+    }
+
+    fn needs_kube(&self) -> bool {
+        true
+    }
+
+    fn destructive(&self) -> bool {
+        true
+    }
+
+    fn execute<'a>(
+        &'a self,
+        ctx: &'a ActionContext<'a>,
+        alert: &'a Alert,
+    ) -> BoxFuture<'a, ActionResult> {
+        let (args, client) = (ctx.args, ctx.client);
+        Box::pin(async move {
+            let Some(namespace) = &alert.labels.namespace else {
+                return Err("alert is missing namespace".into());
+            };
+            let name = match (&alert.labels.deployment, &alert.labels.pod) {
+                (Some(deployment), _) => deployment.clone(),
+                (None, Some(pod)) => match owning_workload(client.clone(), pod, namespace).await? {
+                    (kind, name) if kind == "Deployment" => name,
+                    (kind, name) => {
+                        return Err(format!("pod {} is owned by {} {}", pod, kind, name).into())
+                    }
+                },
+                (None, None) => return Err("alert is missing deployment or pod".into()),
+            };
+            rollback_deployment(
+                args,
+                client.clone(),
+                namespace,
+                &name,
+                server_dry_run(args, &alert.labels),
+            )
+            .await
+        })
+    }
+}
+
+/// Restarts a DaemonSet's pod on the alert's node. Destructive like any pod deletion; node agents
+/// in protected namespaces need --allow-protected-daemonset-restart.
+pub struct RestartDaemonSetPod;
+
+impl Action for RestartDaemonSetPod {
+    fn needs_kube(&self) -> bool {
+        true
+    }
+
+    fn destructive(&self) -> bool {
+        true
+    }
+
+    fn execute<'a>(
+        &'a self,
+        ctx: &'a ActionContext<'a>,
+        alert: &'a Alert,
+    ) -> BoxFuture<'a, ActionResult> {
+        let (args, client) = (ctx.args, ctx.client);
+        Box::pin(async move {
+            let (Some(daemonset), Some(node), Some(namespace)) = (
+                &alert.labels.daemonset,
+                &alert.labels.node,
+                &alert.labels.namespace,
+            ) else {
+                return Err("alert is missing daemonset, node or namespace".into());
+            };
+            let dp = delete_params(args, &alert.labels)?;
+            restart_daemonset_pod(
+                client.clone(),
+                daemonset,
+                node,
+                namespace,
+                &dp,
+                Duration::from_secs(args.foreground_wait_secs),
+            )
+            .await
+        })
+    }
+}
+
+/// Taints the alert's node
+pub struct TaintNode;
+
+impl Action for TaintNode {
+    fn needs_kube(&self) -> bool {
+        true
+    }
+
+    fn execute<'a>(
+        &'a self,
+        ctx: &'a ActionContext<'a>,
+        alert: &'a Alert,
+    ) -> BoxFuture<'a, ActionResult> {
+        let (args, client) = (ctx.args, ctx.client);
+        Box::pin(async move {
+            let node = alert
+                .labels
+                .node
+                .as_deref()
+                .ok_or("alert is missing node")?;
+            let taint = node_taint(args, &alert.labels);
+            // NoExecute evicts every pod on the node, those of protected namespaces included
+            if taint.effect == "NoExecute" && !args.allow_noexecute_taints {
+                return Err(
+                    "NoExecute taints are disabled, enable them with --allow-noexecute-taints"
+                        .into(),
+                );
+            }
+            taint_node(
+                client.clone(),
+                node,
+                taint,
+                server_dry_run(args, &alert.labels),
+            )
+            .await
+        })
+    }
+}
+
+/// Creates a Job from a registered template
+pub struct RunJob;
+
+impl Action for RunJob {
+    fn needs_kube(&self) -> bool {
+        true
+    }
+
+    fn execute<'a>(
+        &'a self,
+        ctx: &'a ActionContext<'a>,
+        alert: &'a Alert,
+    ) -> BoxFuture<'a, ActionResult> {
+        let (args, client) = (ctx.args, ctx.client);
+        Box::pin(async move {
+            let name = alert
+                .labels
+                .job_template
+                .as_deref()
+                .ok_or("no job template specified in alert")?;
+            let job = run_job(args, client.clone(), name, alert).await?;
+            info!("Created Job {} for alert {}", job, alert.fingerprint);
+            Ok(())
+        })
+    }
+}
+
+/// Suggests new resource requests for the alert's pod
+pub struct SuggestRightsizing;
+
+impl Action for SuggestRightsizing {
+    fn namespaced(&self) -> bool {
+        true
+    }
+
+    fn needs_kube(&self) -> bool {
+        true
+    }
+
+    fn execute<'a>(
+        &'a self,
+        ctx: &'a ActionContext<'a>,
+        alert: &'a Alert,
+    ) -> BoxFuture<'a, ActionResult> {
+        let (args, client) = (ctx.args, ctx.client);
+        Box::pin(async move {
+            let (Some(pod), Some(namespace)) = (&alert.labels.pod, &alert.labels.namespace) else {
+                return Err("alert is missing pod or namespace".into());
+            };
+            suggest_rightsizing(args, client, alert, pod, namespace).await
+        })
+    }
+}
+
+/// Posts the alert to its webhook_url
+pub struct Webhook;
+
+impl Action for Webhook {
+    fn namespaced(&self) -> bool {
+        true
+    }
+
+    fn execute<'a>(
+        &'a self,
+        ctx: &'a ActionContext<'a>,
+        alert: &'a Alert,
+    ) -> BoxFuture<'a, ActionResult> {
+        Box::pin(async move {
+            // Get webhook URL from alert label
+            let url = alert
+                .labels
+                .webhook_url
+                .as_ref()
+                .ok_or("no webhook URL specified in alert")?;
+            send_webhook(ctx.args, url, alert, ctx.webhook_template).await?;
+            info!("Sent webhook for alert {}", alert.fingerprint);
+            Ok(())
+        })
+    }
+}
+
+/// Triggers a PagerDuty event
+pub struct PagerDuty;
+
+impl Action for PagerDuty {
+    fn namespaced(&self) -> bool {
+        true
+    }
+
+    fn execute<'a>(
+        &'a self,
+        ctx: &'a ActionContext<'a>,
+        alert: &'a Alert,
+    ) -> BoxFuture<'a, ActionResult> {
+        let args = ctx.args;
+        Box::pin(async move {
+            let routing_key =
+                pagerduty_routing_key(alert, args).ok_or("no PagerDuty routing key for alert")?;
+            send_pagerduty_event(
+                &args.pagerduty_url,
+                routing_key,
+                &alert.fingerprint,
+                Some(alert),
+            )
+            .await?;
+            info!("Triggered PagerDuty event for alert {}", alert.fingerprint);
+            Ok(())
+        })
+    }
+}
+
+/// Creates an OpsGenie alert
+pub struct OpsGenie;
+
+impl Action for OpsGenie {
+    fn namespaced(&self) -> bool {
+        true
+    }
+
+    fn execute<'a>(
+        &'a self,
+        ctx: &'a ActionContext<'a>,
+        alert: &'a Alert,
+    ) -> BoxFuture<'a, ActionResult> {
+        Box::pin(async move {
+            send_opsgenie_alert(ctx.args, &alert.fingerprint, Some(alert)).await?;
+            info!("Created OpsGenie alert for alert {}", alert.fingerprint);
+            Ok(())
+        })
+    }
+}
+
+/// Posts the alert to Microsoft Teams
+pub struct Teams;
+
+impl Action for Teams {
+    fn namespaced(&self) -> bool {
+        true
+    }
+
+    fn execute<'a>(
+        &'a self,
+        ctx: &'a ActionContext<'a>,
+        alert: &'a Alert,
+    ) -> BoxFuture<'a, ActionResult> {
+        Box::pin(async move {
+            send_teams(ctx.args, alert, None).await?;
+            info!("Sent Teams message for alert {}", alert.fingerprint);
+            Ok(())
+        })
+    }
+}
+
+/// Posts the alert to Discord
+pub struct Discord;
+
+impl Action for Discord {
+    fn namespaced(&self) -> bool {
+        true
+    }
+
+    fn execute<'a>(
+        &'a self,
+        ctx: &'a ActionContext<'a>,
+        alert: &'a Alert,
+    ) -> BoxFuture<'a, ActionResult> {
+        Box::pin(async move {
+            send_discord(ctx.args, alert, None).await?;
+            info!("Sent Discord message for alert {}", alert.fingerprint);
+            Ok(())
+        })
+    }
+}
+
+/// Emails the alert
+pub struct Email;
+
+impl Action for Email {
+    fn namespaced(&self) -> bool {
+        true
+    }
+
+    fn execute<'a>(
+        &'a self,
+        ctx: &'a ActionContext<'a>,
+        alert: &'a Alert,
+    ) -> BoxFuture<'a, ActionResult> {
+        let args = ctx.args;
+        Box::pin(async move {
+            let recipients: Vec<String> = match &alert.labels.email_to {
+                Some(to) => to.split(',').map(|r| r.trim().to_string()).collect(),
+                None => args.email_to.clone(),
+            };
+            if recipients.is_empty() {
+                return Err("no email recipients for alert".into());
+            }
+            send_email(args, &recipients, alert).await?;
+            info!("Sent email for alert {}", alert.fingerprint);
+            Ok(())
+        })
+    }
+}
+
+/// Runs a registered command
+pub struct RunCommand;
+
+impl Action for RunCommand {
+    fn execute<'a>(
+        &'a self,
+        ctx: &'a ActionContext<'a>,
+        alert: &'a Alert,
+    ) -> BoxFuture<'a, ActionResult> {
+        Box::pin(async move {
+            let name = alert
+                .labels
+                .command
+                .as_deref()
+                .ok_or("no command specified in alert")?;
+            run_command(ctx.args, name, alert).await?;
+            info!("Ran command {} for alert {}", name, alert.fingerprint);
+            Ok(())
+        })
+    }
+}
+
+/// Namespaces that destructive actions are always refused in, on top of --protected-namespaces
+pub const BUILTIN_PROTECTED_NAMESPACES: &[&str] = &["kube-system"];
+
+/// Whether an action deletes, evicts, scales or rolls back workloads, and so must never touch
+/// protected namespaces
+pub fn is_destructive_action(action: &str) -> bool {
+    registry()
+        .get(action)
+        .is_some_and(|action| action.destructive())
+}
+
+/// The protected namespace a destructive action would touch, if any
+pub fn protected_namespace<'a>(args: &Args, action: &str, labels: &'a Labels) -> Option<&'a str> {
+    if !is_destructive_action(action) {
+        return None;
+    }
+    if action == "restart_daemonset_pod" && args.allow_protected_daemonset_restart {
+        return None;
+    }
+    labels.namespace.as_deref().filter(|namespace| {
+        BUILTIN_PROTECTED_NAMESPACES.contains(namespace)
+            || args.protected_namespaces.iter().any(|p| p == namespace)
+    })
+}
+
+/// Whether the registry knows the action
+pub fn is_known_action(action: &str) -> bool {
+    registry().get(action).is_some()
+}
+
+/// Whether team rules may use an action
+pub fn is_namespaced_action(action: &str) -> bool {
+    registry()
+        .get(action)
+        .is_some_and(|action| action.namespaced())
+}
+
+/// Whether an action needs the Kubernetes API, and so has to wait while it is unreachable
+pub fn is_kube_action(action: &str) -> bool {
+    registry()
+        .get(action)
+        .is_some_and(|action| action.needs_kube())
+}
+
+/// Classification of a failed Kubernetes API call, deciding how the failure is handled
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum KubeErrorKind {
+    /// The target is already gone; nothing left to do
+    NotFound,
+    /// Missing RBAC; retrying won't help, so escalate
+    Forbidden,
+    /// Concurrent modification; safe to retry
+    Conflict,
+    /// The API server timed out or asked us to back off; safe to retry
+    Timeout,
+    /// The API server could not be reached at all; safe to retry
+    Unavailable,
+    Other,
+}
+
+impl KubeErrorKind {
+    /// Classify the Kubernetes error behind a failed action, if there is one
+    pub fn of(result: &Result<(), Box<dyn Error>>) -> Option<Self> {
+        let err = result.as_ref().err()?.downcast_ref::<kube::Error>()?;
+        let kind = match err {
+            kube::Error::Api(resp) => match resp.code {
+                404 => Self::NotFound,
+                401 | 403 => Self::Forbidden,
+                409 => Self::Conflict,
+                408 | 429 | 500 | 503 | 504 => Self::Timeout,
+                _ => Self::Other,
+            },
+            kube::Error::HyperError(_) | kube::Error::Service(_) => {
+                if err.to_string().contains("timed out") {
+                    Self::Timeout
+                } else {
+                    Self::Unavailable
+                }
+            }
+            _ => Self::Other,
+        };
+        Some(kind)
+    }
+
+    pub fn is_retryable(self) -> bool {
+        matches!(self, Self::Conflict | Self::Timeout | Self::Unavailable)
+    }
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Self::NotFound => "not_found",
+            Self::Forbidden => "forbidden",
+            Self::Conflict => "conflict",
+            Self::Timeout => "timeout",
+            Self::Unavailable => "unavailable",
+            Self::Other => "other",
+        }
+    }
+}
+
+/// Result label for metrics and audit records: success, skipped, timed_out, failure, or the
+/// Kubernetes error kind
+pub fn action_outcome(result: &Result<(), Box<dyn Error>>) -> &'static str {
+    match (result, KubeErrorKind::of(result)) {
+        (Ok(()), _) => "success",
+        (Err(err), _) if err.is::<Skipped>() => "skipped",
+        (Err(err), _) if err.is::<TimedOut>() => "timed_out",
+        (Err(_), Some(kind)) => kind.as_str(),
+        (Err(_), None) => "failure",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use clap::Parser;
+
+    fn args(flags: &[&str]) -> Args {
+        Args::try_parse_from(
+            [
+                "alert-actor",
+                "--alertmanager-url",
+                "http://alertmanager:9093",
+                "--no-leader-election",
+            ]
+            .iter()
+            .chain(flags),
+        )
+        .unwrap()
+    }
+
+    fn labels(namespace: Option<&str>) -> Labels {
+        Labels {
+            alertname: "A".to_string(),
+            namespace: namespace.map(str::to_string),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn registry_looks_up_actions_by_name() {
+        let registry = ActionRegistry::builtin();
+        assert!(registry.get("delete_pod").is_some());
+        assert!(registry.get("delete-pod").is_none());
+        assert!(registry.get("").is_none());
+        assert!(is_known_action("taint_node"));
+        assert!(!is_known_action("drain_node"));
+        let names: Vec<&str> = registry.names().collect();
+        assert_eq!(names.len(), 19);
+        assert!(names.windows(2).all(|pair| pair[0] < pair[1]));
+    }
+
+    #[test]
+    fn register_replaces_an_action_of_the_same_name() {
+        let mut registry = ActionRegistry::builtin();
+        registry.register("delete_pod", Webhook);
+        let replaced = registry.get("delete_pod").unwrap();
+        assert!(!replaced.destructive());
+        assert!(!replaced.needs_kube());
+        assert_eq!(registry.names().count(), 19);
+    }
+
+    #[test]
+    fn action_flags() {
+        let registry = ActionRegistry::builtin();
+        let with = |flag: fn(&dyn Action) -> bool| -> Vec<&str> {
+            registry
+                .names()
+                .filter(|name| flag(registry.get(name).unwrap()))
+                .collect()
+        };
+        assert_eq!(
+            with(|action| action.destructive()),
+            [
+                "cleanup_pods",
+                "delete_pod",
+                "delete_pods_by_selector",
+                "force_finalize",
+                "hpa_adjust",
+                "restart_daemonset_pod",
+                "rollback",
+            ]
+        );
+        // Taints, Jobs and CronJob suspension don't delete anything, but still need the API
+        for action in ["taint_node", "run_job", "suspend_cronjob", "resume_cronjob"] {
+            assert!(!is_destructive_action(action), "{}", action);
+            assert!(is_kube_action(action), "{}", action);
+        }
+        for action in ["webhook", "pagerduty", "email", "command"] {
+            assert!(!is_kube_action(action), "{}", action);
+        }
+        assert_eq!(
+            registry
+                .names()
+                .filter(|name| !is_namespaced_action(name))
+                .collect::<Vec<_>>(),
+            ["command", "restart_daemonset_pod", "run_job", "taint_node"]
+        );
+        assert!(!is_destructive_action("unknown"));
+        assert!(!is_kube_action("unknown"));
+        assert!(!is_namespaced_action("unknown"));
+    }
+
+    #[test]
+    fn protected_namespaces_only_stop_destructive_actions() {
+        let args = args(&["--protected-namespaces", "payments"]);
+        let system = labels(Some("kube-system"));
+        assert_eq!(
+            protected_namespace(&args, "delete_pod", &system),
+            Some("kube-system")
+        );
+        assert_eq!(
+            protected_namespace(&args, "delete_pod", &labels(Some("payments"))),
+            Some("payments")
+        );
+        assert_eq!(
+            protected_namespace(&args, "delete_pod", &labels(Some("billing"))),
+            None
+        );
+        // Only the namespace label counts, an alert without one is not protected
+        assert_eq!(
+            protected_namespace(&args, "delete_pod", &labels(None)),
+            None
+        );
+        for action in ["taint_node", "run_job", "suspend_cronjob", "webhook"] {
+            assert_eq!(
+                protected_namespace(&args, action, &system),
+                None,
+                "{}",
+                action
+            );
+        }
+        assert_eq!(
+            protected_namespace(&args, "restart_daemonset_pod", &system),
+            Some("kube-system")
+        );
+        let allowed = self::args(&["--allow-protected-daemonset-restart"]);
+        assert_eq!(
+            protected_namespace(&allowed, "restart_daemonset_pod", &system),
+            None
+        );
+        assert_eq!(
+            protected_namespace(&allowed, "delete_pod", &system),
+            Some("kube-system")
+        );
+    }
+
+    #[test]
+    fn parses_quantities() {
+        assert_eq!(parse_quantity("250m"), Some(0.25));
+        assert_eq!(parse_quantity("1.5"), Some(1.5));
+        assert_eq!(parse_quantity("128Mi"), Some(134217728.0));
+        assert_eq!(parse_quantity("2G"), Some(2e9));
+        assert_eq!(parse_quantity("1Ki"), Some(1024.0));
+        assert_eq!(parse_quantity("lots"), None);
+        assert_eq!(parse_quantity("Mi"), None);
+    }
+}
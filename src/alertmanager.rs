@@ -0,0 +1,752 @@
+//! Alerts and where they come from: Alertmanager and the cluster itself
+
+use anyhow::Result;
+use k8s_openapi::api::core::v1::Pod;
+use k8s_openapi::{apimachinery::pkg::apis::meta::v1::Time, chrono::Utc};
+use kube::{api::ListParams, Api, Client};
+use log::{info, warn};
+use reqwest::{Client as HttpClient, Identity, RequestBuilder};
+use serde::{
+    de::{DeserializeSeed, IgnoredAny, SeqAccess, Visitor},
+    Deserialize, Deserializer, Serialize,
+};
+use serde_json::{json, Value};
+use std::{
+    collections::{hash_map::RandomState, BTreeMap, HashMap},
+    error::Error,
+    hash::BuildHasher,
+};
+use tokio::time::Duration;
+
+use crate::{
+    config::Args,
+    expr::json_path,
+    metrics::METRICS,
+    notify::{http_client_builder, render_value, resolve_secret},
+};
+
+#[derive(Debug, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Alert {
+    pub fingerprint: String,
+    pub status: AlertStatus,
+    pub labels: Labels,
+    #[serde(default)]
+    pub annotations: HashMap<String, String>,
+    pub starts_at: Option<String>,
+    pub ends_at: Option<String>,
+    pub updated_at: Option<String>,
+    #[serde(rename = "generatorURL")]
+    pub generator_url: Option<String>,
+    #[serde(default)]
+    pub receivers: Vec<Receiver>,
+    /// Labels as Alertmanager sent them, before annotations and rules filled in parameters
+    #[serde(skip)]
+    pub original_labels: BTreeMap<String, String>,
+}
+
+impl Alert {
+    /// The alert as JSON, with only the labels that are set, for expressions and JSONPath
+    pub fn to_value(&self) -> Value {
+        let mut value = json!(self);
+        value["labels"] = json!(self.labels.to_map());
+        value
+    }
+
+    /// Value at a JSONPath-style reference into the alert, e.g. `$.labels.kubernetes_pod_name`
+    pub fn resolve_path(&self, path: &str) -> Result<Option<String>, Box<dyn Error>> {
+        match json_path(&self.to_value(), path)? {
+            None | Some(Value::Null) => Ok(None),
+            Some(Value::String(value)) => Ok(Some(value.clone())),
+            Some(value @ (Value::Number(_) | Value::Bool(_))) => Ok(Some(value.to_string())),
+            Some(_) => Err(format!("'{}' does not point at a single value", path).into()),
+        }
+    }
+
+    /// Set parameters such as `pod`, `namespace` or `node` from JSONPath references, for alerting
+    /// rules that name them differently. Paths that resolve to nothing leave the parameter as is.
+    pub fn apply_target_paths<'a>(
+        &mut self,
+        paths: impl IntoIterator<Item = (&'a str, &'a str)>,
+    ) -> Result<(), Box<dyn Error>> {
+        let mut labels = self.labels.to_map();
+        let mut changed = false;
+        for (name, path) in paths {
+            if let Some(value) = self.resolve_path(path)? {
+                changed |= labels.insert(name.to_string(), value.clone()) != Some(value);
+            }
+        }
+        if changed {
+            self.labels = serde_json::from_value(json!(labels))?;
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct AlertStatus {
+    pub state: String,
+    #[serde(default)]
+    pub silenced_by: Vec<String>,
+    #[serde(default)]
+    pub inhibited_by: Vec<String>,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct Receiver {
+    pub name: String,
+}
+
+/// Parameters annotations may fill in: none of them picks the action, where it acts or whether
+/// it needs approval
+pub const ANNOTATION_PARAMETERS: [&str; 8] = [
+    "selector",
+    "max_pods",
+    "cleanup_phases",
+    "grace_period_seconds",
+    "precondition",
+    "not_ready_secs",
+    "severity",
+    "webhook_headers",
+];
+
+#[derive(Debug, Default, Deserialize, Serialize)]
+pub struct Labels {
+    pub alertname: String,
+    pub pod: Option<String>,         // Pod might be missing in some alerts
+    pub namespace: Option<String>,   // Namespace might be missing in some alerts
+    pub action: Option<String>,      // Action to take, e.g. delete_pod or webhook
+    pub webhook_url: Option<String>, // Webhook URL for this specific alert
+    pub webhook_headers: Option<String>, // Extra 'Name: value' headers for webhook_url, one per line
+    pub severity: Option<String>,        // Severity forwarded to PagerDuty
+    pub pagerduty_routing_key: Option<String>, // PagerDuty routing key for this specific alert
+    pub email_to: Option<String>,        // Comma separated recipients for the email action
+    pub webhook_format: Option<String>,  // Overrides --webhook-format for this alert
+    pub webhook_encoding: Option<String>, // Overrides --webhook-encoding for this alert
+    pub require_approval: Option<String>, // "true" or "false", overrides --approval-actions
+    pub grace_period_seconds: Option<String>, // Overrides the pod's termination grace period on delete
+    pub force: Option<String>,                // "true" deletes immediately (grace period 0)
+    pub propagation_policy: Option<String>,   // Foreground, Background or Orphan
+    pub selector: Option<String>,             // Label selector for delete_pods_by_selector
+    pub max_pods: Option<String>,             // Overrides --selector-max-pods / --cleanup-max-pods
+    pub cleanup_phases: Option<String>,       // Comma separated pod phases cleanup_pods deletes
+    pub owner_action: Option<String>,         // "restart" or "scale" the owner instead of deleting
+    pub owner_replicas: Option<String>,       // Replica count for owner_action "scale"
+    pub precondition: Option<String>, // Any of crashloop, oomkilled, not_ready on the live pod
+    pub not_ready_secs: Option<String>, // Minimum NotReady duration for not_ready
+    pub quiet_window: Option<String>, // Cron window during which this alert is left alone
+    pub active_window: Option<String>, // Cron window outside of which it is left alone
+    pub command: Option<String>,      // Name of a --commands entry for the command action
+    pub job_template: Option<String>, // Name of a --job-templates entry for run_job
+    pub node: Option<String>,         // Node a node-scoped alert is about
+    pub daemonset: Option<String>,    // DaemonSet restarted by restart_daemonset_pod
+    pub deployment: Option<String>,   // Deployment rolled back by rollback
+    pub cronjob: Option<String>,      // CronJob of suspend_cronjob and resume_cronjob
+    pub hpa: Option<String>,          // HorizontalPodAutoscaler changed by hpa_adjust
+    pub hpa_min_replicas: Option<String>, // "5" sets minReplicas, "+2" / "-1" adjust it
+    pub hpa_max_replicas: Option<String>, // Same for maxReplicas
+    pub taint_key: Option<String>,    // Overrides --taint-key for taint_node
+    pub taint_value: Option<String>,  // Overrides --taint-value for taint_node
+    pub taint_effect: Option<String>, // Overrides --taint-effect for taint_node
+    pub taint_ttl_secs: Option<String>, // Overrides --taint-ttl-secs for taint_node
+    pub teams_webhook_url: Option<String>, // Overrides --teams-webhook-url for this alert
+    pub discord_webhook_url: Option<String>, // Overrides --discord-webhook-url for this alert
+    pub notify: Option<String>,       // Comma separated channels told about the action's outcome
+    pub cluster: Option<String>,      // Name of a --clusters entry to act in
+    pub server_dry_run: Option<String>, // "true" only validates Kubernetes changes (dryRun=All)
+    pub confirmations: Option<String>, // Overrides --confirmations for this alert
+    pub ca_cert: Option<String>,      // CA bundle trusted for this alert's webhook_url
+    pub action_timeout_secs: Option<String>, // Overrides --action-timeout-secs for this alert
+    #[serde(flatten)]
+    pub other: BTreeMap<String, String>, // Every other label on the alert
+}
+
+impl Labels {
+    /// All labels present on the alert as name/value pairs
+    pub fn to_map(&self) -> BTreeMap<String, String> {
+        match serde_json::to_value(self) {
+            Ok(Value::Object(map)) => map
+                .into_iter()
+                .filter_map(|(k, v)| Some((k, v.as_str()?.to_string())))
+                .collect(),
+            _ => BTreeMap::new(),
+        }
+    }
+
+    /// Fill parameters the alert has no label for from annotations of the same name, e.g. a
+    /// `selector` or `webhook_headers` annotation. Long values fit annotations better than labels,
+    /// which make up the alert's identity. Only ANNOTATION_PARAMETERS are filled: the action,
+    /// its scope and policy labels like require_approval always come from labels or rules.
+    pub fn fill_from_annotations(
+        &mut self,
+        annotations: &HashMap<String, String>,
+    ) -> Result<(), Box<dyn Error>> {
+        let Value::Object(mut labels) = serde_json::to_value(&*self)? else {
+            return Ok(());
+        };
+        let mut filled = false;
+        for (name, value) in annotations {
+            if !ANNOTATION_PARAMETERS.contains(&name.as_str()) {
+                continue;
+            }
+            // Unset parameters serialize as null, other labels and set parameters as strings
+            if let Some(slot @ Value::Null) = labels.get_mut(name) {
+                *slot = Value::String(value.clone());
+                filled = true;
+            }
+        }
+        if !filled {
+            return Ok(());
+        }
+        let scope = (
+            self.namespace.clone(),
+            self.cluster.clone(),
+            self.node.clone(),
+        );
+        let filled: Labels = serde_json::from_value(Value::Object(labels))?;
+        if (
+            filled.namespace.clone(),
+            filled.cluster.clone(),
+            filled.node.clone(),
+        ) != scope
+        {
+            return Err("annotations may not change the namespace, cluster or node".into());
+        }
+        *self = filled;
+        Ok(())
+    }
+}
+
+/// Webhook notification sent by Alertmanager
+#[derive(Debug, Deserialize)]
+pub struct Notification {
+    #[serde(default)]
+    pub receiver: String,
+    pub alerts: Vec<NotificationAlert>,
+}
+
+/// Alert as it appears in an Alertmanager webhook notification
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct NotificationAlert {
+    pub status: String,
+    pub labels: Labels,
+    #[serde(default)]
+    pub annotations: HashMap<String, String>,
+    pub starts_at: Option<String>,
+    pub ends_at: Option<String>,
+    #[serde(rename = "generatorURL")]
+    pub generator_url: Option<String>,
+    pub fingerprint: String,
+    // Only sent by Grafana
+    #[serde(rename = "dashboardURL")]
+    pub dashboard_url: Option<String>,
+    #[serde(rename = "panelURL")]
+    pub panel_url: Option<String>,
+    pub value_string: Option<String>,
+}
+
+/// Alertmanager API v2 endpoint (e.g. "alerts", "silences") for a configured base or full alerts URL
+pub fn alertmanager_endpoint(alertmanager_url: &str, resource: &str) -> String {
+    let url = alertmanager_url.trim_end_matches('/');
+    let base = url.strip_suffix("/api/v2/alerts").unwrap_or(url);
+    format!("{}/api/v2/{}", base, resource)
+}
+
+/// Attach the configured Alertmanager credentials to a request
+pub fn alertmanager_auth(
+    args: &Args,
+    request: RequestBuilder,
+) -> Result<RequestBuilder, Box<dyn Error>> {
+    if let Some(token) = &args.alertmanager_bearer_token {
+        Ok(request.bearer_auth(resolve_secret(token)?))
+    } else if let Some(username) = &args.alertmanager_username {
+        let password = args
+            .alertmanager_password
+            .as_deref()
+            .map(resolve_secret)
+            .transpose()?;
+        Ok(request.basic_auth(username, password))
+    } else {
+        Ok(request)
+    }
+}
+
+/// Build the HTTP client used to poll Alertmanager, presenting a client certificate if configured
+pub fn build_alertmanager_client(args: &Args) -> Result<HttpClient, Box<dyn Error>> {
+    let mut builder = http_client_builder(args, args.alertmanager_proxy.as_deref())?;
+    if let (Some(cert), Some(key)) = (
+        &args.alertmanager_client_cert,
+        &args.alertmanager_client_key,
+    ) {
+        let identity = Identity::from_pkcs8_pem(&std::fs::read(cert)?, &std::fs::read(key)?)?;
+        builder = builder.identity(identity);
+    }
+    Ok(builder.build()?)
+}
+
+/// Fetch alerts like `get_alerts`, retrying failed attempts with jittered exponential backoff
+pub async fn get_alerts_with_retry(
+    args: &Args,
+    http_client: &HttpClient,
+    alertmanager_url: &str,
+) -> Result<Vec<Alert>, Box<dyn Error>> {
+    let mut backoff = Duration::from_millis(args.poll_backoff_ms);
+    let mut attempt = 1;
+    loop {
+        let err = match get_alerts(args, http_client, alertmanager_url).await {
+            Ok(alerts) => return Ok(alerts),
+            Err(err) => err,
+        };
+        if attempt >= args.poll_max_attempts {
+            return Err(format!("giving up after {} attempts: {}", attempt, err).into());
+        }
+        // Somewhere between half and one and a half times the backoff
+        let random = RandomState::new().hash_one(attempt) as f64 / u64::MAX as f64;
+        let delay = backoff.mul_f64(0.5 + random);
+        warn!(
+            "Poll attempt {} of {} failed, retrying in {:?}: {}",
+            attempt, alertmanager_url, delay, err
+        );
+        METRICS.inc("alert_actor_poll_retries_total", &[]);
+        tokio::time::sleep(delay).await;
+        backoff *= 2;
+        attempt += 1;
+    }
+}
+
+/// Fetch active, unsilenced, uninhibited alerts, letting Alertmanager filter by alert name
+pub async fn get_alerts(
+    args: &Args,
+    http_client: &HttpClient,
+    alertmanager_url: &str,
+) -> Result<Vec<Alert>, Box<dyn Error>> {
+    let mut query = vec![
+        ("active", "true".to_string()),
+        ("silenced", "false".to_string()),
+        ("inhibited", "false".to_string()),
+    ];
+    // Rules can match any alert, so filtering by name is only possible without them
+    if !args.alert_names.is_empty() && !args.remediation_rules {
+        query.push((
+            "filter",
+            format!("alertname=~\"{}\"", args.alert_names.join("|")),
+        ));
+    }
+    for filter in &args.alertmanager_filters {
+        query.push(("filter", filter.clone()));
+    }
+    if let Some(receiver) = &args.alertmanager_receiver {
+        query.push(("receiver", receiver.clone()));
+    }
+    if args.alertmanager_skip_unprocessed {
+        query.push(("unprocessed", "false".to_string()));
+    }
+
+    let request = http_client
+        .get(alertmanager_endpoint(alertmanager_url, "alerts"))
+        .query(&query);
+    let mut resp = alertmanager_auth(args, request)?
+        .send()
+        .await?
+        .error_for_status()?;
+    // Read chunk by chunk, so an oversized response is abandoned before it is all in memory
+    let mut body = Vec::new();
+    while let Some(chunk) = resp.chunk().await? {
+        if body.len() + chunk.len() > args.alertmanager_max_response_bytes {
+            return Err(format!(
+                "response exceeds {} bytes",
+                args.alertmanager_max_response_bytes
+            )
+            .into());
+        }
+        body.extend_from_slice(&chunk);
+    }
+
+    // Parsing thousands of alerts takes a while, keep it off the tasks renewing leases
+    let max = args.max_alerts;
+    let (alerts, total) = tokio::task::spawn_blocking(move || {
+        let mut deserializer = serde_json::Deserializer::from_slice(&body);
+        let parsed = CappedAlerts { max }.deserialize(&mut deserializer)?;
+        deserializer.end()?;
+        Ok::<_, serde_json::Error>(parsed)
+    })
+    .await?
+    .map_err(|err| format!("invalid alert list: {}", err))?;
+    if total > alerts.len() {
+        warn!(
+            "Alertmanager {} returned {} alerts, only handling the first {}",
+            alertmanager_url,
+            total,
+            alerts.len()
+        );
+        METRICS.inc("alert_actor_alert_lists_truncated_total", &[]);
+    }
+
+    Ok(alerts)
+}
+
+/// Deserializes an alert list one element at a time, keeping the first `max` alerts and only
+/// counting the rest, so a huge response never turns into a huge `Vec`
+struct CappedAlerts {
+    max: usize,
+}
+
+impl<'de> DeserializeSeed<'de> for CappedAlerts {
+    type Value = (Vec<Alert>, usize);
+
+    fn deserialize<D: Deserializer<'de>>(self, deserializer: D) -> Result<Self::Value, D::Error> {
+        deserializer.deserialize_seq(self)
+    }
+}
+
+impl<'de> Visitor<'de> for CappedAlerts {
+    type Value = (Vec<Alert>, usize);
+
+    fn expecting(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.write_str("a list of alerts")
+    }
+
+    fn visit_seq<A: SeqAccess<'de>>(self, mut seq: A) -> Result<Self::Value, A::Error> {
+        let mut alerts = Vec::new();
+        let mut total = 0;
+        loop {
+            let more = if alerts.len() < self.max {
+                seq.next_element::<Alert>()?.map(|alert| alerts.push(alert))
+            } else {
+                seq.next_element::<IgnoredAny>()?.map(|_| ())
+            };
+            if more.is_none() {
+                return Ok((alerts, total));
+            }
+            total += 1;
+        }
+    }
+}
+
+/// What a silence is created for, available to --silence-comment and --silence-created-by as
+/// `{{ silence.* }}`
+pub struct SilenceContext<'a> {
+    pub action: &'a str,
+    pub target: &'a str,
+    /// Matching rule as namespace/name
+    pub rule: Option<&'a str>,
+    pub correlation_id: &'a str,
+}
+
+/// Silence an alert's exact label set so it stops paging (and re-triggering) once handled.
+/// Replicas gossip silences, so the first Alertmanager that accepts it is enough.
+pub async fn create_silence(
+    args: &Args,
+    http_client: &HttpClient,
+    alert: &Alert,
+    context: &SilenceContext<'_>,
+) -> Result<String, Box<dyn Error>> {
+    let now = Utc::now();
+    let ends_at = now + k8s_openapi::chrono::Duration::seconds(args.silence_duration_secs as i64);
+    let mut value = serde_json::to_value(alert)?;
+    value["silence"] = json!({
+        "action": context.action,
+        "target": context.target,
+        "rule": context.rule.unwrap_or("none"),
+        "correlationId": context.correlation_id,
+        "startsAt": now.to_rfc3339(),
+        "endsAt": ends_at.to_rfc3339(),
+        "durationSecs": args.silence_duration_secs,
+    });
+    // Parameters added by annotations and rules aren't labels Alertmanager knows the alert by
+    let matchers: Vec<Value> = alert
+        .original_labels
+        .iter()
+        .map(|(name, value)| json!({"name": name, "value": value, "isRegex": false, "isEqual": true}))
+        .collect();
+    let silence = json!({
+        "matchers": matchers,
+        "startsAt": now.to_rfc3339(),
+        "endsAt": ends_at.to_rfc3339(),
+        "createdBy": render_value(&args.silence_created_by, &value),
+        "comment": render_value(&args.silence_comment, &value),
+    });
+
+    let mut last_err: Box<dyn Error> = "no Alertmanager URL configured".into();
+    for url in &args.alertmanager_url {
+        let request = http_client
+            .post(alertmanager_endpoint(url, "silences"))
+            .json(&silence);
+        let resp = async {
+            let resp = alertmanager_auth(args, request)?
+                .send()
+                .await?
+                .error_for_status()?
+                .json::<Value>()
+                .await?;
+            Ok::<Value, Box<dyn Error>>(resp)
+        };
+        match resp.await {
+            Ok(resp) => return Ok(resp["silenceID"].as_str().unwrap_or_default().to_string()),
+            Err(err) => last_err = err,
+        }
+    }
+    Err(last_err)
+}
+
+/// A silence created after an action, watched so it can be lifted once the alert resolves
+#[derive(Debug, Clone, Deserialize, Serialize, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct TrackedSilence {
+    pub id: String,
+    pub fingerprint: String,
+    /// Labels Alertmanager knows the alert by, which the silence matches
+    pub labels: BTreeMap<String, String>,
+    /// Unix timestamps of its creation and of when it ends by itself
+    pub created_at: i64,
+    pub ends_at: i64,
+}
+
+/// Whether an alert is still firing, silenced or not. Alertmanager only lists alerts that haven't
+/// resolved, so one that is missing has cleared.
+pub async fn alert_firing(
+    args: &Args,
+    http_client: &HttpClient,
+    fingerprint: &str,
+    labels: &BTreeMap<String, String>,
+) -> Result<bool, Box<dyn Error>> {
+    let mut query = vec![
+        ("active", "true".to_string()),
+        ("silenced", "true".to_string()),
+        ("inhibited", "true".to_string()),
+    ];
+    for (name, value) in labels {
+        query.push(("filter", format!("{}={:?}", name, value)));
+    }
+    let mut last_err: Box<dyn Error> = "no Alertmanager URL configured".into();
+    for url in &args.alertmanager_url {
+        let request = http_client
+            .get(alertmanager_endpoint(url, "alerts"))
+            .query(&query);
+        let resp = async {
+            let alerts = alertmanager_auth(args, request)?
+                .send()
+                .await?
+                .error_for_status()?
+                .json::<Vec<Value>>()
+                .await?;
+            Ok::<_, Box<dyn Error>>(alerts)
+        };
+        match resp.await {
+            Ok(alerts) => {
+                return Ok(alerts
+                    .iter()
+                    .any(|alert| alert["fingerprint"].as_str() == Some(fingerprint)))
+            }
+            Err(err) => last_err = err,
+        }
+    }
+    Err(last_err)
+}
+
+/// Expire a silence before its end. Replicas gossip the change like the silence itself.
+pub async fn expire_silence(
+    args: &Args,
+    http_client: &HttpClient,
+    id: &str,
+) -> Result<(), Box<dyn Error>> {
+    let mut last_err: Box<dyn Error> = "no Alertmanager URL configured".into();
+    for url in &args.alertmanager_url {
+        let request = http_client.delete(alertmanager_endpoint(url, &format!("silence/{}", id)));
+        let resp = async {
+            alertmanager_auth(args, request)?
+                .send()
+                .await?
+                .error_for_status()?;
+            Ok::<_, Box<dyn Error>>(())
+        };
+        match resp.await {
+            Ok(()) => return Ok(()),
+            Err(err) => last_err = err,
+        }
+    }
+    Err(last_err)
+}
+
+/// Lift the tracked silences of alerts that have resolved, restoring alert coverage before the
+/// silences would end. Silences that have ended by themselves are forgotten, those that can't be
+/// checked are tried again next time. Returns whether the tracked set changed.
+pub async fn verify_silences(
+    args: &Args,
+    http_client: &HttpClient,
+    tracked: &mut Vec<TrackedSilence>,
+) -> bool {
+    let now = Utc::now().timestamp();
+    let before = tracked.len();
+    let mut kept = Vec::new();
+    for silence in tracked.drain(..) {
+        if now >= silence.ends_at {
+            continue;
+        }
+        // Give the replacement time to come up before the alert's absence means anything
+        if now < silence.created_at + args.silence_verify_secs as i64 {
+            kept.push(silence);
+            continue;
+        }
+        match alert_firing(args, http_client, &silence.fingerprint, &silence.labels).await {
+            Ok(true) => kept.push(silence),
+            Ok(false) => match expire_silence(args, http_client, &silence.id).await {
+                Ok(()) => {
+                    info!(
+                        "Alert {} resolved, expired silence {} early",
+                        silence.fingerprint, silence.id
+                    );
+                    METRICS.inc("alert_actor_silences_expired_early_total", &[]);
+                }
+                Err(err) => {
+                    warn!("Failed to expire silence {}: {}", silence.id, err);
+                    kept.push(silence);
+                }
+            },
+            Err(err) => {
+                warn!(
+                    "Failed to check whether alert {} resolved: {}",
+                    silence.fingerprint, err
+                );
+                kept.push(silence);
+            }
+        }
+    }
+    *tracked = kept;
+    tracked.len() != before
+}
+
+/// Build synthetic alerts from pod state so remediation keeps working without Prometheus
+pub async fn get_kube_state_alerts(
+    client: Client,
+    crashloop_threshold: Duration,
+    pending_threshold: Duration,
+) -> Result<Vec<Alert>, Box<dyn Error>> {
+    let pods: Api<Pod> = Api::all(client);
+    let now = Utc::now();
+    let mut alerts = Vec::new();
+
+    for pod in pods.list(&ListParams::default()).await? {
+        let (Some(name), Some(namespace)) = (pod.metadata.name, pod.metadata.namespace) else {
+            continue;
+        };
+        let Some(status) = pod.status else {
+            continue;
+        };
+
+        let crashlooping = status
+            .container_statuses
+            .iter()
+            .flatten()
+            .filter_map(|cs| cs.state.as_ref()?.waiting.as_ref()?.reason.as_deref())
+            .any(|reason| reason == "CrashLoopBackOff");
+        // A crashlooping pod has been unready since its first failed restart
+        let not_ready_since = status
+            .conditions
+            .iter()
+            .flatten()
+            .find(|c| c.type_ == "Ready" && c.status == "False")
+            .and_then(|c| c.last_transition_time.as_ref())
+            .or(status.start_time.as_ref());
+
+        let exceeded = |since: Option<&Time>, threshold: Duration| {
+            since
+                .filter(|t| (now - t.0).to_std().unwrap_or_default() > threshold)
+                .cloned()
+        };
+        let (alertname, since) = if let Some(since) =
+            exceeded(not_ready_since, crashloop_threshold).filter(|_| crashlooping)
+        {
+            ("KubePodCrashLooping", since)
+        } else if let Some(since) =
+            exceeded(pod.metadata.creation_timestamp.as_ref(), pending_threshold)
+                .filter(|_| status.phase.as_deref() == Some("Pending"))
+        {
+            ("KubePodStuckPending", since)
+        } else {
+            continue;
+        };
+
+        alerts.push(Alert {
+            fingerprint: format!("kube-state/{}/{}/{}", namespace, name, alertname),
+            status: AlertStatus {
+                state: "active".to_string(),
+                silenced_by: Vec::new(),
+                inhibited_by: Vec::new(),
+            },
+            labels: Labels {
+                alertname: alertname.to_string(),
+                pod: Some(name),
+                namespace: Some(namespace),
+                ..Default::default()
+            },
+            annotations: HashMap::new(),
+            starts_at: Some(since.0.to_rfc3339()),
+            ends_at: None,
+            updated_at: Some(now.to_rfc3339()),
+            generator_url: None,
+            receivers: Vec::new(),
+            original_labels: BTreeMap::new(),
+        });
+    }
+
+    Ok(alerts)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn labels(labels: &[(&str, &str)]) -> Labels {
+        let labels: BTreeMap<&str, &str> = labels.iter().copied().collect();
+        serde_json::from_value(json!(labels)).unwrap()
+    }
+
+    fn annotations(annotations: &[(&str, &str)]) -> HashMap<String, String> {
+        annotations
+            .iter()
+            .map(|(name, value)| (name.to_string(), value.to_string()))
+            .collect()
+    }
+
+    #[test]
+    fn annotations_fill_unset_parameters() {
+        let mut filled = labels(&[("alertname", "A"), ("max_pods", "3")]);
+        filled
+            .fill_from_annotations(&annotations(&[
+                ("selector", "app=worker"),
+                ("max_pods", "50"),
+                ("summary", "stuck"),
+            ]))
+            .unwrap();
+        assert_eq!(filled.selector.as_deref(), Some("app=worker"));
+        // Labels win over annotations
+        assert_eq!(filled.max_pods.as_deref(), Some("3"));
+        assert!(!filled.to_map().contains_key("summary"));
+    }
+
+    #[test]
+    fn annotations_cannot_set_action_scope_or_policy() {
+        let mut filled = labels(&[("alertname", "A"), ("namespace", "payments")]);
+        filled
+            .fill_from_annotations(&annotations(&[
+                ("action", "taint_node"),
+                ("cluster", "prod-eu"),
+                ("require_approval", "false"),
+                ("namespace", "kube-system"),
+                ("node", "node-1"),
+                ("command", "drain"),
+                ("webhook_url", "https://elsewhere.example"),
+            ]))
+            .unwrap();
+        assert_eq!(filled.action, None);
+        assert_eq!(filled.cluster, None);
+        assert_eq!(filled.require_approval, None);
+        assert_eq!(filled.namespace.as_deref(), Some("payments"));
+        assert_eq!(filled.node, None);
+        assert_eq!(filled.command, None);
+        assert_eq!(filled.webhook_url, None);
+    }
+}
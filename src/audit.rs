@@ -0,0 +1,256 @@
+//! Audit records and their sinks
+
+use anyhow::Result;
+use k8s_openapi::api::core::v1::ConfigMap;
+use k8s_openapi::{apimachinery::pkg::apis::meta::v1::ObjectMeta, chrono::Utc};
+use kube::{api::PostParams, Api, Client};
+use log::error;
+use serde::Serialize;
+use serde_json::json;
+use std::{collections::BTreeMap, error::Error};
+use tokio::{io::AsyncWriteExt, net::UdpSocket};
+
+use crate::{
+    actions::action_outcome,
+    alertmanager::Alert,
+    config::Args,
+    notify::{notification_client, resolve_secret},
+    state::ExecContext,
+};
+
+/// One audited decision or action, exported to the configured SIEM sinks
+#[derive(Debug, Serialize)]
+pub struct AuditRecord {
+    pub timestamp: String,
+    pub correlation_id: String,
+    pub fingerprint: String,
+    pub alertname: String,
+    pub labels: BTreeMap<String, String>,
+    pub action: String,
+    pub target: String,
+    pub result: String,
+    pub message: String,
+    pub leader: String,
+}
+
+impl AuditRecord {
+    pub fn new(
+        ctx: &ExecContext,
+        alert: &Alert,
+        action: &str,
+        target: &str,
+        result: &Result<(), Box<dyn Error>>,
+    ) -> Self {
+        let message = match result {
+            Ok(()) => String::new(),
+            Err(err) => err.to_string(),
+        };
+        Self {
+            timestamp: Utc::now().to_rfc3339(),
+            correlation_id: ctx.correlation_id.clone(),
+            fingerprint: alert.fingerprint.clone(),
+            alertname: alert.labels.alertname.clone(),
+            labels: alert.labels.to_map(),
+            action: action.to_string(),
+            target: target.to_string(),
+            result: action_outcome(result).to_string(),
+            message,
+            leader: ctx.identity.clone(),
+        }
+    }
+
+    pub fn is_failure(&self) -> bool {
+        !matches!(
+            self.result.as_str(),
+            "success" | "not_found" | "suppressed" | "skipped"
+        )
+    }
+
+    /// Format as an ArcSight CEF event
+    pub fn to_cef(&self) -> String {
+        let header = |v: &str| v.replace('\\', "\\\\").replace('|', "\\|");
+        let ext = |v: &str| {
+            v.replace('\\', "\\\\")
+                .replace('=', "\\=")
+                .replace(['\r', '\n'], " ")
+        };
+        let severity = if self.is_failure() { 7 } else { 3 };
+        format!(
+            "CEF:0|alert-actor|alert-actor|{}|{}|{} {}|{}|rt={} cs4Label=correlationId cs4={} cs1Label=fingerprint cs1={} cs2Label=alertname cs2={} cs3Label=target cs3={} outcome={} msg={}",
+            env!("CARGO_PKG_VERSION"),
+            header(&self.action),
+            header(&self.action),
+            header(&self.result),
+            severity,
+            ext(&self.timestamp),
+            ext(&self.correlation_id),
+            ext(&self.fingerprint),
+            ext(&self.alertname),
+            ext(&self.target),
+            ext(&self.result),
+            ext(&self.message),
+        )
+    }
+}
+
+/// Append an audit record to a JSON lines file, rotating it to `<path>.1` .. `<path>.<max_files>`
+/// before it grows past `max_bytes`
+pub async fn append_audit_file(
+    path: &str,
+    max_bytes: u64,
+    max_files: u32,
+    record: &AuditRecord,
+) -> Result<(), Box<dyn Error>> {
+    let mut line = serde_json::to_vec(record)?;
+    line.push(b'\n');
+    let size = match tokio::fs::metadata(path).await {
+        Ok(metadata) => metadata.len(),
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => 0,
+        Err(err) => return Err(err.into()),
+    };
+    if size > 0 && size + line.len() as u64 > max_bytes {
+        for n in (1..max_files).rev() {
+            let from = format!("{}.{}", path, n);
+            if tokio::fs::try_exists(&from).await? {
+                tokio::fs::rename(&from, format!("{}.{}", path, n + 1)).await?;
+            }
+        }
+        if max_files > 0 {
+            tokio::fs::rename(path, format!("{}.1", path)).await?;
+        } else {
+            tokio::fs::remove_file(path).await?;
+        }
+    }
+    let mut file = tokio::fs::OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)
+        .await?;
+    file.write_all(&line).await?;
+    file.flush().await?;
+    Ok(())
+}
+
+/// Append an audit record to the `audit.log` key of a ConfigMap, keeping the newest `max_records`
+pub async fn append_audit_configmap(
+    client: Client,
+    name: &str,
+    max_records: usize,
+    record: &AuditRecord,
+) -> Result<(), Box<dyn Error>> {
+    let configmaps: Api<ConfigMap> = Api::default_namespaced(client);
+    let line = serde_json::to_string(record)?;
+    // Writes are conditional on the resourceVersion read, so retry when another write got in first
+    for _ in 0..3 {
+        let mut configmap = match configmaps.get_opt(name).await? {
+            Some(configmap) => configmap,
+            None => ConfigMap {
+                metadata: ObjectMeta {
+                    name: Some(name.to_string()),
+                    ..Default::default()
+                },
+                ..Default::default()
+            },
+        };
+        let data = configmap.data.get_or_insert_with(Default::default);
+        let log = data.entry("audit.log".to_string()).or_default();
+        let mut lines: Vec<&str> = log.lines().collect();
+        lines.push(&line);
+        let skip = lines.len().saturating_sub(max_records);
+        *log = lines[skip..].iter().map(|l| format!("{}\n", l)).collect();
+        let result = if configmap.metadata.resource_version.is_some() {
+            configmaps
+                .replace(name, &PostParams::default(), &configmap)
+                .await
+        } else {
+            configmaps.create(&PostParams::default(), &configmap).await
+        };
+        match result {
+            Ok(_) => return Ok(()),
+            Err(kube::Error::Api(resp)) if resp.code == 409 => continue,
+            Err(err) => return Err(err.into()),
+        }
+    }
+    Err(format!("ConfigMap {} kept changing, audit record not written", name).into())
+}
+
+/// Export an audit record to the configured file, ConfigMap, syslog and Splunk HEC sinks.
+/// Export failures are logged, never fatal.
+pub async fn export_audit(args: &Args, client: &Client, record: &AuditRecord) {
+    if let Some(path) = &args.audit_file {
+        if let Err(err) = append_audit_file(
+            path,
+            args.audit_file_max_bytes,
+            args.audit_file_max_files,
+            record,
+        )
+        .await
+        {
+            error!("Failed to write audit record to {}: {}", path, err);
+        }
+    }
+
+    if let Some(name) = &args.audit_configmap {
+        if let Err(err) = append_audit_configmap(
+            client.clone(),
+            name,
+            args.audit_configmap_max_records,
+            record,
+        )
+        .await
+        {
+            error!(
+                "Failed to write audit record to ConfigMap {}: {}",
+                name, err
+            );
+        }
+    }
+
+    if let Some(addr) = &args.audit_syslog_addr {
+        let payload = match args.audit_format.as_str() {
+            "json" => serde_json::to_string(record).unwrap_or_default(),
+            _ => record.to_cef(),
+        };
+        // RFC 5424, facility local0 (16), severity notice (5) or error (3)
+        let priority = if record.is_failure() { 131 } else { 133 };
+        let message = format!(
+            "<{}>1 {} {} alert-actor - audit - {}",
+            priority, record.timestamp, record.leader, payload
+        );
+        let sent = async {
+            let socket = UdpSocket::bind("0.0.0.0:0").await?;
+            socket.send_to(message.as_bytes(), addr).await
+        };
+        if let Err(err) = sent.await {
+            error!("Failed to export audit record to syslog: {}", err);
+        }
+    }
+
+    if let Some(url) = &args.audit_hec_url {
+        let sent = async {
+            let token = args
+                .audit_hec_token
+                .as_deref()
+                .map(resolve_secret)
+                .transpose()?
+                .unwrap_or_default();
+            let event = json!({
+                "time": Utc::now().timestamp(),
+                "host": record.leader,
+                "sourcetype": "alert-actor:audit",
+                "event": record,
+            });
+            notification_client()
+                .post(url)
+                .header("Authorization", format!("Splunk {}", token))
+                .json(&event)
+                .send()
+                .await?
+                .error_for_status()?;
+            Ok::<(), Box<dyn Error>>(())
+        };
+        if let Err(err) = sent.await {
+            error!("Failed to export audit record to Splunk HEC: {}", err);
+        }
+    }
+}
@@ -0,0 +1,121 @@
+//! Clients for remote clusters, picked per alert by its cluster label, and for impersonation
+
+use kube::{
+    config::{KubeConfigOptions, Kubeconfig},
+    Client, Config,
+};
+use std::{collections::HashMap, error::Error, sync::Mutex};
+
+use crate::{alertmanager::Alert, config::Args};
+
+/// User and groups a rule's actions are performed as, through impersonation headers
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub struct Impersonation {
+    pub user: String,
+    pub groups: Vec<String>,
+}
+
+/// A cluster's connection settings and the client using the controller's own identity
+pub struct Cluster {
+    pub config: Config,
+    pub client: Client,
+}
+
+/// The cluster the controller runs in plus those configured with --clusters
+pub struct Clusters {
+    pub local: Cluster,
+    pub remote: HashMap<String, Cluster>,
+    /// Clients impersonating rules' identities, by cluster (None for local) and identity
+    impersonated: Mutex<HashMap<(Option<String>, Impersonation), Client>>,
+}
+
+impl Clusters {
+    /// Connect to every cluster in --clusters. Entries are 'name=context:CONTEXT' for a context
+    /// of the default kubeconfig, or 'name=file:/path/to/kubeconfig' for a mounted secret.
+    pub async fn connect(args: &Args, local: Cluster) -> Result<Clusters, Box<dyn Error>> {
+        let mut remote = HashMap::new();
+        for entry in &args.clusters {
+            let (name, source) = entry
+                .split_once('=')
+                .ok_or_else(|| format!("invalid cluster '{}', expected name=source", entry))?;
+            let config = if let Some(context) = source.strip_prefix("context:") {
+                let options = KubeConfigOptions {
+                    context: Some(context.to_string()),
+                    ..Default::default()
+                };
+                Config::from_kubeconfig(&options).await?
+            } else if let Some(path) = source.strip_prefix("file:") {
+                let kubeconfig = Kubeconfig::read_from(path)?;
+                Config::from_custom_kubeconfig(kubeconfig, &KubeConfigOptions::default()).await?
+            } else {
+                return Err(format!(
+                    "invalid source for cluster {}, expected context: or file:",
+                    name
+                )
+                .into());
+            };
+            let client = Client::try_from(config.clone())?;
+            remote.insert(name.to_string(), Cluster { config, client });
+        }
+        Ok(Clusters {
+            local,
+            remote,
+            impersonated: Mutex::new(HashMap::new()),
+        })
+    }
+
+    /// Name of the remote cluster the alert's action runs in, or None for the local cluster.
+    /// Without --clusters everything is local; otherwise alerts without a cluster label or
+    /// labelled with --cluster-name are.
+    pub fn remote_name<'a>(args: &Args, alert: &'a Alert) -> Option<&'a str> {
+        Clusters::remote_cluster(args, alert.labels.cluster.as_deref())
+    }
+
+    /// Remote cluster a cluster label refers to, as for `remote_name`
+    pub fn remote_cluster<'a>(args: &Args, cluster: Option<&'a str>) -> Option<&'a str> {
+        if args.clusters.is_empty() {
+            return None;
+        }
+        cluster.filter(|cluster| Some(*cluster) != args.cluster_name.as_deref())
+    }
+
+    /// Client for the cluster the alert's action runs in, impersonating the given identity
+    pub fn client_for(
+        &self,
+        args: &Args,
+        alert: &Alert,
+        impersonation: Option<&Impersonation>,
+    ) -> Result<Client, Box<dyn Error>> {
+        self.client_in(Clusters::remote_name(args, alert), impersonation)
+    }
+
+    /// Client for the named remote cluster (or the local one), impersonating if asked to
+    pub fn client_in(
+        &self,
+        name: Option<&str>,
+        impersonation: Option<&Impersonation>,
+    ) -> Result<Client, Box<dyn Error>> {
+        let cluster = match name {
+            None => &self.local,
+            Some(name) => self
+                .remote
+                .get(name)
+                .ok_or_else(|| format!("unknown cluster '{}'", name))?,
+        };
+        let Some(impersonation) = impersonation else {
+            return Ok(cluster.client.clone());
+        };
+        let mut impersonated = self.impersonated.lock().unwrap();
+        let key = (name.map(str::to_string), impersonation.clone());
+        if let Some(client) = impersonated.get(&key) {
+            return Ok(client.clone());
+        }
+        let mut config = cluster.config.clone();
+        config.auth_info.impersonate = Some(impersonation.user.clone());
+        config.auth_info.impersonate_groups =
+            Some(impersonation.groups.clone()).filter(|groups| !groups.is_empty());
+        let client = Client::try_from(config)?;
+        impersonated.insert(key, client.clone());
+        Ok(client)
+    }
+}
@@ -0,0 +1,1449 @@
+//! The main loop tying polling, matching and actions together
+
+use anyhow::Result;
+use futures::{
+    future::{self, join_all},
+    StreamExt,
+};
+use k8s_openapi::api::core::v1::ConfigMap;
+use k8s_openapi::chrono::Utc;
+use kube::{
+    api::DeleteParams,
+    runtime::{reflector, watcher, WatchStreamExt},
+    Api, Client, Config, CustomResourceExt, ResourceExt,
+};
+use kube_leader_election::{LeaseLock, LeaseLockParams};
+use log::{debug, error, info, warn};
+use reqwest::Client as HttpClient;
+use serde_json::json;
+use std::{
+    collections::{hash_map::RandomState, BTreeMap, BTreeSet, HashMap, HashSet},
+    error::Error,
+    hash::BuildHasher,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex,
+    },
+    time::Instant,
+};
+use tokio::{
+    signal::unix::{signal, SignalKind},
+    sync::{watch, Semaphore},
+    time::Duration,
+};
+
+use crate::{
+    actions::{
+        action_outcome, execute_action, is_kube_action, node_taint, revert_taints, server_dry_run,
+        taint_ttl, KubeErrorKind, PlannedAction, Skipped, TimedOut, TrackedTaint,
+    },
+    alertmanager::{
+        alertmanager_auth, alertmanager_endpoint, build_alertmanager_client, create_silence,
+        get_alerts_with_retry, get_kube_state_alerts, verify_silences, Alert, SilenceContext,
+        TrackedSilence,
+    },
+    audit::{export_audit, AuditRecord},
+    clusters::{Cluster, Clusters},
+    config::Args,
+    gates::{Gates, Skip},
+    leadership::{run_leader_election, LeaseTiming},
+    metrics::{load_counters, save_counters, METRICS},
+    notify::{
+        http_client_builder, notification_client, notify_outcome, pagerduty_routing_key,
+        send_digest, send_opsgenie_alert, send_pagerduty_event, DigestEntry, NOTIFICATION_CLIENT,
+    },
+    rbac::{preflight, update_rule_status},
+    rules::{
+        check_approval, circuit_key, find_rule, pending_remediation_name,
+        prune_pending_remediations, requires_approval, stable_hash, target_key,
+        AlertRemediationRule, PendingRemediation,
+    },
+    schedule::{Scheduler, Task},
+    server::serve_http,
+    sources::{get_grafana_alerts, get_loki_alerts, get_prometheus_alerts, is_synthetic},
+    state::{
+        is_paused, load_mitigations, save_mitigations, Circuit, ExecContext, Mitigations,
+        SharedState,
+    },
+    trace::Trace,
+    validate::check_args,
+};
+
+/// Open connections to every Alertmanager and to the configured notification endpoints, so DNS,
+/// TCP and TLS setup don't add to the latency of the first actions after startup or failover
+pub async fn prewarm_connections(
+    args: &Args,
+    alertmanager_client: &HttpClient,
+    rules: &[Arc<AlertRemediationRule>],
+) {
+    let mut origins = BTreeSet::new();
+    let urls = args
+        .flap_webhook_url
+        .iter()
+        .chain(args.audit_hec_url.iter())
+        .chain(
+            rules
+                .iter()
+                .filter_map(|rule| rule.spec.labels.get("webhook_url")),
+        )
+        .chain(
+            std::iter::once(&args.pagerduty_url).filter(|_| args.pagerduty_routing_key.is_some()),
+        )
+        .chain(std::iter::once(&args.opsgenie_url).filter(|_| args.opsgenie_api_key.is_some()));
+    for url in urls {
+        if let Ok(url) = reqwest::Url::parse(url) {
+            origins.insert(url.origin().ascii_serialization());
+        }
+    }
+    let alertmanagers = args.alertmanager_url.iter().map(|url| async move {
+        let request = alertmanager_client
+            .get(alertmanager_endpoint(url, "status"))
+            .timeout(Duration::from_secs(5));
+        alertmanager_auth(args, request)
+            .map_err(|err| err.to_string())?
+            .send()
+            .await?;
+        Ok::<_, Box<dyn Error + Send + Sync>>(())
+    });
+    let notifications = origins.iter().map(|origin| async move {
+        notification_client()
+            .head(origin)
+            .timeout(Duration::from_secs(5))
+            .send()
+            .await?;
+        Ok::<_, Box<dyn Error + Send + Sync>>(())
+    });
+    let (alertmanagers, notifications) =
+        futures::join!(join_all(alertmanagers), join_all(notifications));
+    for (url, result) in args.alertmanager_url.iter().zip(alertmanagers) {
+        if let Err(err) = result {
+            warn!("Failed to pre-warm connection to {}: {}", url, err);
+        }
+    }
+    for (origin, result) in origins.iter().zip(notifications) {
+        if let Err(err) = result {
+            warn!("Failed to pre-warm connection to {}: {}", origin, err);
+        }
+    }
+}
+
+/// Probe the API server, logging and publishing only changes in whether it can be reached
+pub async fn check_kube_api(client: &Client, timeout: Duration, state: &SharedState) -> bool {
+    let error = match tokio::time::timeout(timeout, client.apiserver_version()).await {
+        Ok(Ok(_)) => None,
+        Ok(Err(err)) => Some(err.to_string()),
+        Err(_) => Some("timed out".to_string()),
+    };
+    let was_unavailable = state
+        .kube_unavailable
+        .swap(error.is_some(), Ordering::Relaxed);
+    match (&error, was_unavailable) {
+        (Some(err), false) => warn!(
+            "Kubernetes API unreachable, holding back Kubernetes actions: {}",
+            err
+        ),
+        (None, true) => info!("Kubernetes API reachable again"),
+        _ => {}
+    }
+    METRICS.set(
+        "alert_actor_kube_api_up",
+        &[],
+        if error.is_none() { 1.0 } else { 0.0 },
+    );
+    error.is_none()
+}
+
+/// Resolve once SIGTERM or SIGINT is received
+pub async fn shutdown_signal() {
+    let mut sigterm = match signal(SignalKind::terminate()) {
+        Ok(sigterm) => sigterm,
+        Err(err) => {
+            error!("Failed to install SIGTERM handler: {}", err);
+            return std::future::pending().await;
+        }
+    };
+    tokio::select! {
+        _ = sigterm.recv() => info!("received SIGTERM"),
+        _ = tokio::signal::ctrl_c() => info!("received SIGINT"),
+    }
+}
+
+/// Key under which the evaluation interval of a rule with its own intervalSecs is tracked
+fn interval_key(rule: &AlertRemediationRule) -> String {
+    format!(
+        "{}/{}",
+        rule.namespace().unwrap_or_default(),
+        rule.name_any()
+    )
+}
+
+/// Run the controller with the given configuration until it is asked to shut down
+pub async fn run(args: Args) -> Result<()> {
+    if args.print_crd {
+        let crds = json!({
+            "apiVersion": "v1",
+            "kind": "List",
+            "items": [AlertRemediationRule::crd(), PendingRemediation::crd()],
+        });
+        println!("{}", serde_json::to_string_pretty(&crds)?);
+        return Ok(());
+    }
+    // The same checks `validate` runs, so a bad flag stops the controller before it acts
+    let errors = check_args(&args);
+    if !errors.is_empty() {
+        anyhow::bail!("invalid configuration: {}", errors.join("; "));
+    }
+    let alertmanager_client = build_alertmanager_client(&args)
+        .map_err(|err| anyhow::anyhow!("invalid Alertmanager client configuration: {}", err))?;
+    NOTIFICATION_CLIENT
+        .set(
+            http_client_builder(&args, args.notification_proxy.as_deref())
+                .map_err(|err| {
+                    anyhow::anyhow!("invalid notification client configuration: {}", err)
+                })?
+                .build()?,
+        )
+        .map_err(|_| anyhow::anyhow!("notification client already initialized"))?;
+    let webhook_template = args
+        .webhook_template_file
+        .as_ref()
+        .map(std::fs::read_to_string)
+        .transpose()?;
+    // Polling starts right away; maintenance runs at a slower pace in between
+    let mut scheduler = Scheduler::new();
+    scheduler.add(
+        Task::Poll,
+        Duration::from_secs(args.interval),
+        args.poll_jitter_percent as f64 / 100.0,
+        // Instances started together (e.g. by one rollout) also spread their first poll
+        Duration::from_secs(args.interval).mul_f64(
+            args.poll_jitter_percent as f64 / 100.0
+                * (RandomState::new().hash_one("first poll") as f64 / u64::MAX as f64),
+        ),
+    );
+    scheduler.add(
+        Task::Gc,
+        Duration::from_secs(60),
+        0.1,
+        Duration::from_secs(60),
+    );
+    scheduler.add(
+        Task::Mitigations,
+        Duration::from_secs(30),
+        0.1,
+        Duration::from_secs(30),
+    );
+    if args.remediation_rules && args.rule_status_interval_secs > 0 {
+        scheduler.add(
+            Task::RuleStatus,
+            Duration::from_secs(args.rule_status_interval_secs),
+            0.1,
+            // Give the rule watcher time to list the rules first
+            Duration::from_secs(args.interval),
+        );
+    }
+    if args.metrics_state_configmap.is_some() {
+        scheduler.add(
+            Task::PersistMetrics,
+            Duration::from_secs(args.interval),
+            0.1,
+            Duration::from_secs(args.interval),
+        );
+    }
+
+    let state = Arc::new(SharedState::default());
+    let (server_args, server_state) = (Arc::new(args.clone()), state.clone());
+    tokio::spawn(async move {
+        if let Err(err) = serve_http(server_args, server_state).await {
+            error!("Metrics server failed: {}", err);
+        }
+    });
+
+    let config = Config::infer().await?;
+    let client = Client::try_from(config.clone())?;
+    let namespace = client.default_namespace();
+    let local = Cluster {
+        config,
+        client: client.clone(),
+    };
+    let clusters = Clusters::connect(&args, local)
+        .await
+        .map_err(|err| anyhow::anyhow!("invalid cluster configuration: {}", err))?;
+    preflight(&args, &client)
+        .await
+        .map_err(|err| anyhow::anyhow!("RBAC preflight failed: {}", err))?;
+
+    let identity = args
+        .pod_name
+        .clone()
+        .or_else(|| std::env::var("HOSTNAME").ok())
+        .unwrap_or_else(|| "alert-actor".to_string());
+    if let Some(name) = &args.metrics_state_configmap {
+        match load_counters(client.clone(), name, &identity).await {
+            Ok(saved) => METRICS.restore(saved),
+            Err(err) => warn!(
+                "Failed to restore counters from ConfigMap {}: {}",
+                name, err
+            ),
+        }
+    }
+
+    // Rules are mirrored into a local store by a watcher that reconnects with backoff on errors
+    let rule_store = if args.remediation_rules {
+        let (reader, writer) = reflector::store();
+        let rules = reflector(
+            writer,
+            watcher(
+                Api::<AlertRemediationRule>::all(client.clone()),
+                watcher::Config::default(),
+            ),
+        )
+        .default_backoff()
+        .touched_objects();
+        tokio::spawn(rules.for_each(|event| {
+            if let Err(err) = event {
+                warn!("AlertRemediationRule watch failed: {}", err);
+            }
+            future::ready(())
+        }));
+        Some(reader)
+    } else {
+        None
+    };
+
+    // The kill switch is watched rather than polled so pausing takes effect on the next alert
+    let pause_store = args.pause_configmap.as_ref().map(|name| {
+        let (reader, writer) = reflector::store();
+        let configmaps = reflector(
+            writer,
+            watcher(
+                Api::<ConfigMap>::default_namespaced(client.clone()),
+                watcher::Config::default().fields(&format!("metadata.name={}", name)),
+            ),
+        )
+        .default_backoff()
+        .touched_objects();
+        tokio::spawn(configmaps.for_each(|event| {
+            if let Err(err) = event {
+                warn!("Pause ConfigMap watch failed: {}", err);
+            }
+            future::ready(())
+        }));
+        reader
+    });
+
+    // Followers stay up as warm standbys and only act on the shards this reports as owned
+    let shards = args.shards as usize;
+    let (leader_tx, is_leader) = watch::channel(vec![false; shards]);
+    let (release_tx, release_rx) = watch::channel(false);
+    // Without an election the sender is kept alive for the whole run, so leadership never reads as lost
+    let (elections, _standalone_leader) = match &args.pod_name {
+        Some(pod_name) if !args.no_leader_election => {
+            let timing = LeaseTiming::from_args(&args)
+                .map_err(|err| anyhow::anyhow!("invalid lease timing: {}", err))?;
+            info!(
+                "Lease timing: TTL {:?}, renew every {:?}, deadline {:?}, retry every {:?}",
+                timing.ttl, timing.renew_period, timing.renew_deadline, timing.retry_period
+            );
+            let elections: Vec<_> = (0..shards)
+                .map(|shard| {
+                    let lease_name = if shards == 1 {
+                        args.lease_name.clone()
+                    } else {
+                        format!("{}-{}", args.lease_name, shard)
+                    };
+                    let leadership = LeaseLock::new(
+                        client.clone(),
+                        namespace,
+                        LeaseLockParams {
+                            holder_id: pod_name.clone(),
+                            lease_name: lease_name.clone(),
+                            lease_ttl: Duration::from_secs(args.lease_secs),
+                        },
+                    );
+                    tokio::spawn(run_leader_election(
+                        leadership,
+                        lease_name,
+                        shard,
+                        timing,
+                        leader_tx.clone(),
+                        release_rx.clone(),
+                    ))
+                })
+                .collect();
+            (elections, None)
+        }
+        _ => {
+            info!("leader election disabled, acting as the only replica");
+            leader_tx.send_replace(vec![true; shards]);
+            (Vec::new(), Some(leader_tx))
+        }
+    };
+    // Dashboards alert on a remediator that never leads, which otherwise does nothing silently
+    let mut leader_gauge = is_leader.clone();
+    tokio::spawn(async move {
+        loop {
+            let owned = leader_gauge
+                .borrow_and_update()
+                .iter()
+                .filter(|o| **o)
+                .count();
+            METRICS.set("alert_actor_is_leader", &[], (owned > 0) as u8 as f64);
+            METRICS.set("alert_actor_owned_shards", &[], owned as f64);
+            if leader_gauge.changed().await.is_err() {
+                break;
+            }
+        }
+    });
+    // Warm up at startup and again whenever leadership is acquired, by which time idle pooled
+    // connections have long been closed
+    let (warm_args, warm_client, warm_rules, mut warm_leader) = (
+        Arc::new(args.clone()),
+        alertmanager_client.clone(),
+        rule_store.clone(),
+        is_leader.clone(),
+    );
+    tokio::spawn(async move {
+        loop {
+            let rules = warm_rules
+                .as_ref()
+                .map(|store| store.state())
+                .unwrap_or_default();
+            prewarm_connections(&warm_args, &warm_client, &rules).await;
+            if warm_leader
+                .wait_for(|owned| !owned.contains(&true))
+                .await
+                .is_err()
+                || warm_leader
+                    .wait_for(|owned| owned.contains(&true))
+                    .await
+                    .is_err()
+            {
+                return;
+            }
+        }
+    });
+
+    let shutdown = shutdown_signal();
+    tokio::pin!(shutdown);
+
+    // Cooldown tracking for all alert actions - HashMap to store last processed timestamp for each alert fingerprint
+    let mut alert_cooldown: HashMap<String, Instant> = HashMap::new();
+    // Consecutive polls each active alert was firing in
+    let mut firing_polls: HashMap<String, u64> = HashMap::new();
+    // When alerts were last evaluated, per rule with its own interval ("" for everything else)
+    let mut last_evaluated: HashMap<String, Instant> = HashMap::new();
+    // Recent action timestamps per target, and targets suppressed for flapping until the given instant
+    let mut flap_history: HashMap<String, Vec<Instant>> = HashMap::new();
+    let mut flap_suppressed: HashMap<String, Instant> = HashMap::new();
+    let flap_window = Duration::from_secs(args.flap_window_secs);
+    // Downstreams failing repeatedly, keyed by `circuit_key`
+    let mut circuits: HashMap<String, Circuit> = HashMap::new();
+    // Open PagerDuty incidents keyed by alert fingerprint, so they can be resolved once the alert clears
+    let mut pagerduty_triggered: HashMap<String, String> = HashMap::new();
+    // Open OpsGenie alerts by fingerprint (their alias), closed once the alert clears
+    let mut opsgenie_created: HashSet<String> = HashSet::new();
+    // Kubernetes actions held back while the API server is unreachable, until they expire
+    let mut kube_queue: HashMap<String, Instant> = HashMap::new();
+    let kube_action_ttl = Duration::from_secs(args.kube_action_ttl_secs);
+    // Silences watched for early expiry and taints with a TTL, plus what was last persisted to
+    // --mitigations-configmap and whether this replica has read it since becoming leader, by shard
+    let mut mitigations = vec![Mitigations::default(); shards];
+    let mut saved_mitigations = vec![Mitigations::default(); shards];
+    let mut mitigations_loaded = vec![false; shards];
+    // Whether PendingRemediations may exist, so resolved ones have to be cleaned up
+    let mut approvals_used = !args.approval_actions.is_empty();
+
+    let mut cycle: u64 = 0;
+    // Counters as last persisted to --metrics-state-configmap
+    let mut saved_counters = BTreeMap::new();
+    // main loop; a signal is only honoured between cycles, so in-flight actions always finish
+    loop {
+        // In reactive mode pushed alerts start a cycle of their own, while the interval becomes a
+        // reconciliation sweep catching anything a push missed
+        let pushed = tokio::select! {
+            task = scheduler.next() => match task {
+                Task::Poll => None,
+                Task::Gc => {
+                    let now = Instant::now();
+                    flap_suppressed.retain(|_, until| *until > now);
+                    flap_history.retain(|_, history| {
+                        history.last().is_some_and(|at| now.duration_since(*at) < flap_window)
+                    });
+                    state.acknowledged.lock().unwrap().retain(|_, until| *until > now);
+                    // Cooldowns can be as long as the longest rule's, all shorter ones have expired
+                    let longest_cooldown = rule_store
+                        .as_ref()
+                        .map(|store| store.state())
+                        .unwrap_or_default()
+                        .iter()
+                        .filter_map(|rule| rule.spec.cooldown_secs)
+                        .chain([args.cooldown_secs, args.emergency_cooldown_secs])
+                        .max()
+                        .unwrap_or_default();
+                    alert_cooldown.retain(|_, at| {
+                        now.duration_since(*at) < Duration::from_secs(longest_cooldown)
+                    });
+                    continue;
+                }
+                Task::PersistMetrics => {
+                    // Every replica saves its own counters, and only when something was counted
+                    let counters = METRICS.counters.lock().unwrap().clone();
+                    if let Some(name) = &args.metrics_state_configmap {
+                        if counters != saved_counters {
+                            let saved = tokio::time::timeout(
+                                Duration::from_secs(args.interval),
+                                save_counters(client.clone(), name, &identity, &counters),
+                            )
+                            .await;
+                            match saved {
+                                Ok(Ok(())) => saved_counters = counters,
+                                Ok(Err(err)) => Scheduler::failed(Task::PersistMetrics, &err),
+                                Err(err) => Scheduler::failed(Task::PersistMetrics, &err),
+                            }
+                        }
+                    }
+                    continue;
+                }
+                Task::Mitigations => {
+                    let owned = is_leader.borrow().clone();
+                    for shard in 0..shards {
+                        if !owned[shard] {
+                            // The shard's new leader resumes its timers from the ConfigMap
+                            if args.mitigations_configmap.is_some() {
+                                mitigations[shard] = Mitigations::default();
+                                saved_mitigations[shard] = Mitigations::default();
+                            }
+                            mitigations_loaded[shard] = false;
+                            continue;
+                        }
+                        if let (Some(name), false) =
+                            (&args.mitigations_configmap, mitigations_loaded[shard])
+                        {
+                            match load_mitigations(client.clone(), name, shard).await {
+                                Ok(loaded) => {
+                                    info!(
+                                        "Resuming {} silences and {} taints of shard {} recorded in ConfigMap {}",
+                                        loaded.silences.len(),
+                                        loaded.taints.len(),
+                                        shard,
+                                        name
+                                    );
+                                    saved_mitigations[shard] = loaded.clone();
+                                    mitigations[shard].merge(loaded);
+                                    mitigations_loaded[shard] = true;
+                                }
+                                Err(err) => {
+                                    Scheduler::failed(Task::Mitigations, &err);
+                                    continue;
+                                }
+                            }
+                        }
+                        let current = &mut mitigations[shard];
+                        verify_silences(&args, &alertmanager_client, &mut current.silences).await;
+                        revert_taints(&args, &clusters, &mut current.taints).await;
+                        if let Some(name) = &args.mitigations_configmap {
+                            if *current != saved_mitigations[shard] {
+                                match save_mitigations(client.clone(), name, shard, current).await
+                                {
+                                    Ok(()) => saved_mitigations[shard] = current.clone(),
+                                    Err(err) => Scheduler::failed(Task::Mitigations, &err),
+                                }
+                            }
+                        }
+                    }
+                    continue;
+                }
+                Task::RuleStatus => {
+                    // One writer is enough, the replica leading the first shard
+                    let leads_first_shard = is_leader.borrow()[0];
+                    if let (Some(store), true) = (&rule_store, leads_first_shard) {
+                        if let Err(err) = update_rule_status(
+                            &args,
+                            &client,
+                            &clusters,
+                            &store.state(),
+                            namespace,
+                        )
+                        .await
+                        {
+                            Scheduler::failed(Task::RuleStatus, &err);
+                        }
+                    }
+                    continue;
+                }
+            },
+            _ = state.pushed_notify.notified(), if args.reactive => {
+                // Let a burst of notifications settle, so each alert is handled once per burst
+                tokio::time::sleep(Duration::from_millis(args.reactive_debounce_ms)).await;
+                Some(std::mem::take(&mut *state.pushed.lock().unwrap()))
+            }
+            _ = &mut shutdown => break,
+        };
+        // Followers probe too, so readiness reflects the API server on every replica
+        let mut kube_available = match pushed {
+            None => check_kube_api(&client, Duration::from_secs(args.interval), &state).await,
+            Some(_) => !state.kube_unavailable.load(Ordering::Relaxed),
+        };
+        if !is_leader.borrow().contains(&true) {
+            continue;
+        }
+        // Everything in this cycle has to finish before the next one is due
+        cycle += 1;
+        let ctx = ExecContext {
+            deadline: tokio::time::Instant::now() + Duration::from_secs(args.interval),
+            correlation_id: format!("{:x}-{}", Utc::now().timestamp(), cycle),
+            dry_run: args.dry_run,
+            leadership: is_leader.clone(),
+            identity: identity.clone(),
+            trace: Arc::new(Trace::new()),
+        };
+        info!("Checking for alerts (cycle {})...", ctx.correlation_id);
+        // Emergency mode trades safety limits for aggressive cleanup until it expires
+        let emergency = state.emergency_active();
+        let cooldown_duration = if emergency {
+            warn!("Emergency mode active");
+            Duration::from_secs(args.emergency_cooldown_secs)
+        } else {
+            Duration::from_secs(args.cooldown_secs) // Configurable cooldown duration
+        };
+        // A push only carries the alerts it is about, so it says nothing about what resolved
+        let mut polled = false;
+        let alerts: Vec<Alert> = if let Some(pushed) = pushed {
+            let mut deduplicated: HashMap<String, Alert> = HashMap::new();
+            for alert in pushed {
+                deduplicated.insert(alert.fingerprint.clone(), alert);
+            }
+            info!("Handling {} pushed alerts", deduplicated.len());
+            deduplicated.into_values().collect()
+        } else {
+            // Poll every Alertmanager replica and merge their views, keeping one copy per fingerprint
+            let (ctx, args, alertmanager_client) = (&ctx, &args, &alertmanager_client);
+            let results = join_all(args.alertmanager_url.iter().map(|url| async move {
+                ctx.traced(
+                    "get_alerts",
+                    &[("alert_actor.alertmanager", url.as_str())],
+                    get_alerts_with_retry(args, alertmanager_client, url),
+                )
+                .await
+            }))
+            .await;
+            let mut deduplicated: HashMap<String, Alert> = HashMap::new();
+            for (url, result) in args.alertmanager_url.iter().zip(results) {
+                match result {
+                    Ok(alerts) => {
+                        polled = true;
+                        for alert in alerts {
+                            deduplicated
+                                .entry(alert.fingerprint.clone())
+                                .or_insert(alert);
+                        }
+                    }
+                    Err(err) => error!("Failed to get alerts from {}: {}", url, err),
+                }
+            }
+            // Without Alertmanagers, the other sources are all there is to poll
+            let failures = if polled || args.alertmanager_url.is_empty() {
+                state.poll_failures.store(0, Ordering::Relaxed);
+                0
+            } else {
+                Scheduler::failed(Task::Poll, &"no Alertmanager could be polled");
+                state.poll_failures.fetch_add(1, Ordering::Relaxed) + 1
+            };
+            METRICS.set(
+                "alert_actor_poll_consecutive_failures",
+                &[],
+                failures as f64,
+            );
+            let mut alerts: Vec<Alert> = deduplicated.into_values().collect();
+            if args.kube_state_source && kube_available {
+                match ctx
+                    .traced(
+                        "get_kube_state_alerts",
+                        &[],
+                        get_kube_state_alerts(
+                            client.clone(),
+                            Duration::from_secs(args.crashloop_threshold_secs),
+                            Duration::from_secs(args.pending_threshold_secs),
+                        ),
+                    )
+                    .await
+                {
+                    Ok(mut kube_state_alerts) => alerts.append(&mut kube_state_alerts),
+                    Err(err) => error!("Failed to get kube-state alerts: {}", err),
+                }
+            }
+            if !args.prometheus_queries.is_empty() {
+                match ctx
+                    .traced(
+                        "get_prometheus_alerts",
+                        &[],
+                        get_prometheus_alerts(args, notification_client()),
+                    )
+                    .await
+                {
+                    Ok(mut prometheus_alerts) => {
+                        polled |= args.alertmanager_url.is_empty();
+                        alerts.append(&mut prometheus_alerts)
+                    }
+                    Err(err) => error!("Failed to get Prometheus alerts: {}", err),
+                }
+            }
+            if !args.loki_queries.is_empty() {
+                match ctx
+                    .traced(
+                        "get_loki_alerts",
+                        &[],
+                        get_loki_alerts(args, notification_client()),
+                    )
+                    .await
+                {
+                    Ok(mut loki_alerts) => {
+                        polled |= args.alertmanager_url.is_empty();
+                        alerts.append(&mut loki_alerts)
+                    }
+                    Err(err) => error!("Failed to get Loki alerts: {}", err),
+                }
+            }
+            if args.grafana_url.is_some() {
+                match ctx
+                    .traced(
+                        "get_grafana_alerts",
+                        &[],
+                        get_grafana_alerts(args, notification_client()),
+                    )
+                    .await
+                {
+                    Ok(mut grafana_alerts) => {
+                        polled |= args.alertmanager_url.is_empty();
+                        alerts.append(&mut grafana_alerts)
+                    }
+                    Err(err) => error!("Failed to get Grafana alerts: {}", err),
+                }
+            }
+            alerts
+        };
+
+        let rules = rule_store
+            .as_ref()
+            .map(|store| store.state())
+            .unwrap_or_default();
+
+        // Poll as often as the most eager rule wants; everything else keeps its own pace.
+        // Half a poll period of slack keeps jitter from pushing evaluations a whole poll late.
+        let poll_every = rules
+            .iter()
+            .filter_map(|rule| rule.spec.interval_secs)
+            .chain([args.interval])
+            .min()
+            .map(|secs| Duration::from_secs(secs.max(1)))
+            .unwrap_or_default();
+        scheduler.set_period(Task::Poll, poll_every);
+        let now = Instant::now();
+        let intervals = rules
+            .iter()
+            .filter_map(|rule| Some((interval_key(rule), rule.spec.interval_secs?)))
+            .chain([(String::new(), args.interval)]);
+        let mut due: HashSet<String> = HashSet::new();
+        for (key, secs) in intervals {
+            // Pushed alerts (the only ones in a cycle without a poll) are handled right away
+            let elapsed = last_evaluated.get(&key).map(|at| now.duration_since(*at));
+            if !polled
+                || elapsed
+                    .is_none_or(|elapsed| elapsed + poll_every / 2 >= Duration::from_secs(secs))
+            {
+                due.insert(key);
+            }
+        }
+        if polled {
+            for key in &due {
+                last_evaluated.insert(key.clone(), now);
+            }
+            METRICS.set(
+                "alert_actor_last_successful_poll_timestamp_seconds",
+                &[],
+                Utc::now().timestamp() as f64,
+            );
+        }
+
+        let active_fingerprints: HashSet<String> = alerts
+            .iter()
+            .filter(|alert| alert.status.state == "active")
+            .map(|alert| alert.fingerprint.clone())
+            .collect();
+
+        // Queued actions are dropped once their alert stops firing, and confirmations start over
+        if polled {
+            kube_queue.retain(|fingerprint, _| active_fingerprints.contains(fingerprint));
+            firing_polls.retain(|fingerprint, _| active_fingerprints.contains(fingerprint));
+            for fingerprint in &active_fingerprints {
+                *firing_polls.entry(fingerprint.clone()).or_default() += 1;
+            }
+        }
+        METRICS.set("alert_actor_kube_queue_depth", &[], kube_queue.len() as f64);
+        let paused = is_paused(pause_store.as_ref());
+        METRICS.set("alert_actor_paused", &[], if paused { 1.0 } else { 0.0 });
+
+        // The pause ConfigMap is read again for each alert so a pause takes effect mid-cycle
+        let paused_now = || is_paused(pause_store.as_ref());
+        let mut planned: Vec<PlannedAction> = Vec::new();
+        for mut alert in alerts {
+            alert.original_labels = alert.labels.to_map();
+            if alert.labels.cluster.is_none() {
+                alert.labels.cluster = args.cluster_name.clone();
+            }
+            if let Err(err) = alert.apply_target_paths(
+                args.target_paths
+                    .iter()
+                    .filter_map(|entry| entry.split_once('=')),
+            ) {
+                error!(
+                    "Failed to resolve target paths of alert {}: {}",
+                    alert.fingerprint, err
+                );
+                continue;
+            }
+            let rule = find_rule(&rules, &alert, namespace);
+            match &rule {
+                Some(rule) => debug!(
+                    "Alert {} ({}) matches rule {}/{}",
+                    alert.fingerprint,
+                    alert.labels.alertname,
+                    rule.namespace().unwrap_or_default(),
+                    rule.name_any()
+                ),
+                None if !args.alert_names.contains(&alert.labels.alertname) => debug!(
+                    "Ignoring alert {} ({}) - no rule or --alert-names entry matches",
+                    alert.fingerprint, alert.labels.alertname
+                ),
+                None => {}
+            }
+            // Only check for alerts that match the provided alert name or a rule
+            if (rule.is_some() || args.alert_names.contains(&alert.labels.alertname))
+                && alert.status.state == "active"
+            {
+                // Alerts of shards owned by other replicas are theirs to handle
+                let shard = (stable_hash(&alert.labels, &args.hash_labels) % args.shards) as usize;
+                if !is_leader.borrow()[shard] {
+                    continue;
+                }
+
+                // Rules with their own interval are evaluated at their pace, the rest every --interval
+                let key = match &rule {
+                    Some(rule) if rule.spec.interval_secs.is_some() => interval_key(rule),
+                    _ => String::new(),
+                };
+                if !due.contains(&key) {
+                    debug!(
+                        "Not evaluating alert {} this cycle - its interval hasn't passed",
+                        alert.fingerprint
+                    );
+                    continue;
+                }
+
+                let now = Instant::now();
+                let gates = Gates {
+                    args: &args,
+                    now,
+                    wall_clock: Utc::now(),
+                    emergency,
+                    cooldown: cooldown_duration,
+                    alert_cooldown: &alert_cooldown,
+                    acknowledged: &state.acknowledged,
+                    flap_suppressed: &flap_suppressed,
+                    firing_polls: &firing_polls,
+                    circuits: &circuits,
+                    paused: &paused_now,
+                };
+                let rule_cooldown_secs = rule.as_ref().and_then(|rule| rule.spec.cooldown_secs);
+                if let Err(skip) = gates.before_rule(&alert, rule_cooldown_secs) {
+                    skip.log(&alert);
+                    continue;
+                }
+
+                // A demoted leader stops acting immediately; the new leader takes over
+                if !is_leader.borrow()[shard] {
+                    if !is_leader.borrow().contains(&true) {
+                        warn!("Lost leadership mid-cycle, stopping");
+                        break;
+                    }
+                    warn!(
+                        "Lost shard {} mid-cycle, skipping alert {}",
+                        shard, alert.fingerprint
+                    );
+                    continue;
+                }
+
+                if let Err(err) = alert.labels.fill_from_annotations(&alert.annotations) {
+                    error!(
+                        "Failed to read parameters from annotations of alert {}: {}",
+                        alert.fingerprint, err
+                    );
+                    continue;
+                }
+
+                // A matching rule's action and labels take precedence over the alert's own
+                if let Some(rule) = &rule {
+                    if let Err(err) = rule.apply(&mut alert, namespace) {
+                        error!(
+                            "Failed to apply rule {} to alert {}: {}",
+                            rule.name_any(),
+                            alert.fingerprint,
+                            err
+                        );
+                        continue;
+                    }
+                }
+
+                // Annotations and the rule's target paths can name the target, so it is only
+                // known from here on
+                let target = target_key(&alert);
+                // Check for action label - default to delete_pod if not specified
+                let action = alert.labels.action.as_deref().unwrap_or("delete_pod");
+                match gates.after_rule(&alert, action, &target, &planned) {
+                    Ok(()) => {}
+                    Err(skip @ Skip::ProtectedNamespace(_)) => {
+                        skip.log(&alert);
+                        METRICS.inc(
+                            "alert_actor_actions_total",
+                            &[("action", action), ("result", "refused")],
+                        );
+                        let mut record = AuditRecord::new(&ctx, &alert, action, &target, &Ok(()));
+                        record.result = "refused".to_string();
+                        record.message = skip.to_string();
+                        export_audit(&args, &client, &record).await;
+                        alert_cooldown.insert(alert.fingerprint.clone(), now);
+                        continue;
+                    }
+                    Err(skip @ Skip::Duplicate(_)) => {
+                        skip.log(&alert);
+                        METRICS.inc(
+                            "alert_actor_actions_total",
+                            &[("action", action), ("result", "deduplicated")],
+                        );
+                        alert_cooldown.insert(alert.fingerprint.clone(), now);
+                        continue;
+                    }
+                    Err(skip) => {
+                        skip.log(&alert);
+                        continue;
+                    }
+                }
+
+                // Without the API server, Kubernetes actions wait in a queue until it is back or
+                // their TTL runs out, while HTTP-only actions and other clusters carry on
+                if is_kube_action(action)
+                    && !kube_available
+                    && Clusters::remote_name(&args, &alert).is_none()
+                {
+                    let expires = *kube_queue
+                        .entry(alert.fingerprint.clone())
+                        .or_insert(now + kube_action_ttl);
+                    METRICS.set("alert_actor_kube_queue_depth", &[], kube_queue.len() as f64);
+                    if now < expires {
+                        info!(
+                            "Queued action {} for alert {} until the Kubernetes API is reachable",
+                            action, alert.fingerprint
+                        );
+                        continue;
+                    }
+                    warn!(
+                        "Dropping queued action {} for alert {}, Kubernetes API unreachable for {}s",
+                        action, alert.fingerprint, args.kube_action_ttl_secs
+                    );
+                    kube_queue.remove(&alert.fingerprint);
+                    let mut record = AuditRecord::new(&ctx, &alert, action, &target, &Ok(()));
+                    record.result = "expired".to_string();
+                    record.message = "Kubernetes API unreachable".to_string();
+                    export_audit(&args, &client, &record).await;
+                    alert_cooldown.insert(alert.fingerprint.clone(), now);
+                    continue;
+                }
+                kube_queue.remove(&alert.fingerprint);
+
+                // Destructive actions can be held until someone approves the PendingRemediation
+                let require_approval = requires_approval(&args, &alert.labels, action);
+                if require_approval && !ctx.dry_run {
+                    approvals_used = true;
+                    match ctx
+                        .run(check_approval(
+                            client.clone(),
+                            namespace,
+                            &alert,
+                            action,
+                            &target,
+                        ))
+                        .await
+                    {
+                        Ok(true) => {
+                            info!("Action {} for alert {} approved", action, alert.fingerprint)
+                        }
+                        Ok(false) => {
+                            info!("Skipping alert {} - awaiting approval", alert.fingerprint);
+                            continue;
+                        }
+                        Err(err) => {
+                            error!(
+                                "Failed to check approval for alert {}: {}",
+                                alert.fingerprint, err
+                            );
+                            continue;
+                        }
+                    }
+                }
+
+                planned.push(PlannedAction {
+                    action: action.to_string(),
+                    alert,
+                    target,
+                    rule,
+                    require_approval,
+                    shard,
+                });
+            }
+        }
+
+        // Actions run concurrently up to --max-concurrency, while those on the same target run one
+        // after another in the order their alerts came in
+        let mut by_target: Vec<Vec<PlannedAction>> = Vec::new();
+        let mut group_of_target: HashMap<String, usize> = HashMap::new();
+        for planned in planned {
+            match group_of_target.get(&planned.target) {
+                Some(&group) => by_target[group].push(planned),
+                None => {
+                    group_of_target.insert(planned.target.clone(), by_target.len());
+                    by_target.push(vec![planned]);
+                }
+            }
+        }
+        let semaphore = Semaphore::new(args.max_concurrency as usize);
+        let kube_lost = AtomicBool::new(false);
+        let created_silences = Mutex::new(Vec::new());
+        let executed = {
+            let (ctx, args, clusters, alertmanager_client) =
+                (&ctx, &args, &clusters, &alertmanager_client);
+            let (semaphore, kube_lost, created_silences, webhook_template) = (
+                &semaphore,
+                &kube_lost,
+                &created_silences,
+                webhook_template.as_deref(),
+            );
+            join_all(by_target.into_iter().map(|group| async move {
+                let mut executed = Vec::new();
+                for planned in group {
+                    // Later Kubernetes actions of this cycle are queued instead of failing one by one
+                    if is_kube_action(&planned.action)
+                        && kube_lost.load(Ordering::Relaxed)
+                        && Clusters::remote_name(args, &planned.alert).is_none()
+                    {
+                        executed.push((planned, None));
+                        continue;
+                    }
+                    let _permit = semaphore
+                        .acquire()
+                        .await
+                        .expect("semaphore is never closed");
+                    let alert = &planned.alert;
+                    let impersonation = match &planned.rule {
+                        Some(rule) => rule.impersonation(namespace),
+                        None => Ok(None),
+                    };
+                    let client = impersonation.and_then(|impersonation| {
+                        clusters.client_for(args, alert, impersonation.as_ref())
+                    });
+                    let result = match client {
+                        Ok(client) => {
+                            execute_action(
+                                ctx,
+                                &planned.action,
+                                alert,
+                                args,
+                                &client,
+                                webhook_template,
+                            )
+                            .await
+                        }
+                        Err(err) => Err(err),
+                    };
+                    // Only the local API server going away holds back Kubernetes actions
+                    if KubeErrorKind::of(&result) == Some(KubeErrorKind::Unavailable)
+                        && Clusters::remote_name(args, alert).is_none()
+                    {
+                        kube_lost.store(true, Ordering::Relaxed);
+                    }
+                    // Synthetic alerts have no Alertmanager counterpart to silence, and
+                    // a server-side dry-run changed nothing that would resolve the alert
+                    if result.is_ok()
+                        && args.silence_duration_secs > 0
+                        && !ctx.dry_run
+                        && !is_synthetic(alert)
+                        && !(is_kube_action(&planned.action) && server_dry_run(args, &alert.labels))
+                    {
+                        let rule_name = planned.rule.as_ref().map(|rule| {
+                            format!(
+                                "{}/{}",
+                                rule.namespace().unwrap_or_default(),
+                                rule.name_any()
+                            )
+                        });
+                        match ctx
+                            .traced(
+                                "create_silence",
+                                &[("alert_actor.fingerprint", &alert.fingerprint)],
+                                create_silence(
+                                    args,
+                                    alertmanager_client,
+                                    alert,
+                                    &SilenceContext {
+                                        action: &planned.action,
+                                        target: &planned.target,
+                                        rule: rule_name.as_deref(),
+                                        correlation_id: &ctx.correlation_id,
+                                    },
+                                ),
+                            )
+                            .await
+                        {
+                            Ok(id) => {
+                                info!("Created silence {} for alert {}", id, alert.fingerprint);
+                                if args.expire_resolved_silences {
+                                    let now = Utc::now().timestamp();
+                                    created_silences.lock().unwrap().push((
+                                        planned.shard,
+                                        TrackedSilence {
+                                            id,
+                                            fingerprint: alert.fingerprint.clone(),
+                                            labels: alert.original_labels.clone(),
+                                            created_at: now,
+                                            ends_at: now + args.silence_duration_secs as i64,
+                                        },
+                                    ));
+                                }
+                            }
+                            Err(err) => error!(
+                                "Failed to create silence for alert {}: {}",
+                                alert.fingerprint, err
+                            ),
+                        }
+                    }
+                    if !ctx.dry_run {
+                        let outcome = action_outcome(&result);
+                        notify_outcome(args, alert, &planned.action, outcome).await;
+                    }
+                    executed.push((planned, Some(result)));
+                }
+                executed
+            }))
+            .await
+        };
+        if kube_lost.into_inner() {
+            kube_available = false;
+        }
+        for (shard, silence) in created_silences.into_inner().unwrap() {
+            mitigations[shard].silences.push(silence);
+        }
+
+        let mut digest = Vec::new();
+        for (planned, result) in executed.into_iter().flatten() {
+            let PlannedAction {
+                alert,
+                action,
+                target,
+                rule,
+                require_approval,
+                shard,
+            } = planned;
+            let action = action.as_str();
+            let now = Instant::now();
+            let rule_name = rule.as_ref().map(|rule| {
+                format!(
+                    "{}/{}",
+                    rule.namespace().unwrap_or_default(),
+                    rule.name_any()
+                )
+            });
+            if !args.digest_channels.is_empty() {
+                digest.push(DigestEntry {
+                    alertname: alert.labels.alertname.clone(),
+                    fingerprint: alert.fingerprint.clone(),
+                    rule: rule_name.clone(),
+                    action: action.to_string(),
+                    target: target.clone(),
+                    result: result.as_ref().map_or("queued", action_outcome).to_string(),
+                    error: result
+                        .as_ref()
+                        .and_then(|result| result.as_ref().err())
+                        .map(|err| err.to_string()),
+                });
+            }
+            let Some(result) = result else {
+                kube_queue.insert(alert.fingerprint.clone(), now + kube_action_ttl);
+                info!(
+                    "Queued action {} for alert {} until the Kubernetes API is reachable",
+                    action, alert.fingerprint
+                );
+                continue;
+            };
+            let kube_error = KubeErrorKind::of(&result);
+            if let Some(kind) = kube_error {
+                METRICS.inc("alert_actor_kube_errors_total", &[("kind", kind.as_str())]);
+            }
+            let outcome = action_outcome(&result);
+            METRICS.inc(
+                "alert_actor_actions_total",
+                &[("action", action), ("result", outcome)],
+            );
+            METRICS.set(
+                "alert_actor_last_action_timestamp_seconds",
+                &[("action", action), ("result", outcome)],
+                Utc::now().timestamp() as f64,
+            );
+            if let Some(rule_name) = &rule_name {
+                METRICS.inc(
+                    "alert_actor_rule_actions_total",
+                    &[("rule", rule_name), ("result", outcome)],
+                );
+            }
+            match (&result, kube_error) {
+                (Ok(()), _) => {
+                    if action == "pagerduty" {
+                        if let Some(routing_key) = pagerduty_routing_key(&alert, &args) {
+                            pagerduty_triggered
+                                .insert(alert.fingerprint.clone(), routing_key.to_string());
+                        }
+                    }
+                    if action == "opsgenie" {
+                        opsgenie_created.insert(alert.fingerprint.clone());
+                    }
+                    // A real taint with a TTL is taken off again by the mitigations task
+                    let ttl = taint_ttl(&args, &alert.labels).unwrap_or_default();
+                    if let (Some(node), "taint_node", true) = (
+                        &alert.labels.node,
+                        action,
+                        ttl > 0 && !ctx.dry_run && !server_dry_run(&args, &alert.labels),
+                    ) {
+                        let taint = node_taint(&args, &alert.labels);
+                        let tracked = TrackedTaint {
+                            cluster: Clusters::remote_name(&args, &alert).map(str::to_string),
+                            node: node.clone(),
+                            key: taint.key,
+                            effect: taint.effect,
+                            expires_at: Utc::now().timestamp() + ttl as i64,
+                        };
+                        mitigations[shard].track_taint(tracked);
+                    }
+                }
+                (Err(err), _) if err.is::<Skipped>() => {
+                    info!("Skipping alert {} - {}", alert.fingerprint, err)
+                }
+                (Err(err), _) if err.is::<TimedOut>() => error!(
+                    "Action {} for alert {} {}, abandoning it",
+                    action, alert.fingerprint, err
+                ),
+                (Err(_), Some(KubeErrorKind::NotFound)) => info!(
+                    "Target {} of alert {} no longer exists",
+                    target, alert.fingerprint
+                ),
+                (Err(err), Some(KubeErrorKind::Forbidden)) => error!(
+                    "Action {} forbidden for alert {}, check RBAC: {}",
+                    action, alert.fingerprint, err
+                ),
+                (Err(err), Some(kind)) if kind.is_retryable() => warn!(
+                    "Action {} hit a transient {} error for alert {}, retrying next cycle: {}",
+                    action,
+                    kind.as_str(),
+                    alert.fingerprint,
+                    err
+                ),
+                (Err(err), _) => error!(
+                    "Action {} failed for alert {}: {}",
+                    action, alert.fingerprint, err
+                ),
+            }
+            export_audit(
+                &args,
+                &client,
+                &AuditRecord::new(&ctx, &alert, action, &target, &result),
+            )
+            .await;
+
+            if args.circuit_failure_threshold > 0 {
+                let key = circuit_key(action, &alert, &args);
+                let downstream_failed = result.as_ref().is_err_and(|err| !err.is::<Skipped>())
+                    && kube_error != Some(KubeErrorKind::NotFound);
+                if downstream_failed {
+                    let circuit = circuits.entry(key.clone()).or_default();
+                    circuit.failures += 1;
+                    // A half-open circuit reopens on the first failure of its trial action
+                    if circuit.failures >= args.circuit_failure_threshold {
+                        warn!(
+                            "{} failed {} times in a row, opening its circuit for {}s",
+                            key, circuit.failures, args.circuit_open_secs
+                        );
+                        METRICS.inc("alert_actor_circuit_opened_total", &[]);
+                        circuit.open_until =
+                            Some(now + Duration::from_secs(args.circuit_open_secs));
+                    }
+                } else if result.is_ok()
+                    && circuits
+                        .remove(&key)
+                        .is_some_and(|circuit| circuit.open_until.is_some())
+                {
+                    info!("{} recovered, closing its circuit", key);
+                }
+                let open = circuits
+                    .values()
+                    .filter(|circuit| circuit.open_until.is_some_and(|until| now < until))
+                    .count();
+                METRICS.set("alert_actor_circuits_open", &[], open as f64);
+            }
+
+            // Transient API failures and skipped targets are retried on the next cycle
+            // instead of cooling down
+            if kube_error.is_some_and(|kind| kind.is_retryable())
+                || result.as_ref().is_err_and(|err| err.is::<Skipped>())
+            {
+                continue;
+            }
+
+            // An approval covers a single run of the action
+            if require_approval && !ctx.dry_run {
+                let api: Api<PendingRemediation> = Api::namespaced(client.clone(), namespace);
+                let name = pending_remediation_name(&alert);
+                if let Err(err) = api.delete(&name, &DeleteParams::default()).await {
+                    error!("Failed to remove PendingRemediation {}: {}", name, err);
+                }
+            }
+
+            // Mark this alert as processed (add to cooldown)
+            alert_cooldown.insert(alert.fingerprint.clone(), now);
+
+            // Repeated actions on one target mean the remediation isn't sticking, so back off
+            if args.flap_threshold > 0 && !emergency {
+                let history = flap_history.entry(target.clone()).or_default();
+                history.retain(|at| now.duration_since(*at) < flap_window);
+                history.push(now);
+                if history.len() >= args.flap_threshold {
+                    warn!(
+                        "Target {} acted on {} times in {}s, suppressing for {}s",
+                        target,
+                        history.len(),
+                        args.flap_window_secs,
+                        args.flap_suppress_secs
+                    );
+                    if let Some(url) = &args.flap_webhook_url {
+                        let notification = json!({
+                            "target": target,
+                            "alertname": alert.labels.alertname,
+                            "fingerprint": alert.fingerprint,
+                            "actions": history.len(),
+                            "window_secs": args.flap_window_secs,
+                            "suppressed_secs": args.flap_suppress_secs,
+                        });
+                        if let Err(err) = notification_client()
+                            .post(url)
+                            .json(&notification)
+                            .send()
+                            .await
+                        {
+                            error!("Failed to send flap notification: {}", err);
+                        }
+                    }
+                    let mut record = AuditRecord::new(&ctx, &alert, "suppress", &target, &Ok(()));
+                    record.result = "suppressed".to_string();
+                    record.message = format!(
+                        "{} actions in {}s, suppressed for {}s",
+                        history.len(),
+                        args.flap_window_secs,
+                        args.flap_suppress_secs
+                    );
+                    export_audit(&args, &client, &record).await;
+                    history.clear();
+                    flap_suppressed
+                        .insert(target, now + Duration::from_secs(args.flap_suppress_secs));
+                }
+            }
+        }
+        send_digest(&args, &ctx.correlation_id, ctx.dry_run, &digest).await;
+        METRICS.set("alert_actor_kube_queue_depth", &[], kube_queue.len() as f64);
+
+        if approvals_used && polled && kube_available {
+            if let Err(err) = ctx
+                .run(prune_pending_remediations(
+                    client.clone(),
+                    namespace,
+                    &active_fingerprints,
+                ))
+                .await
+            {
+                error!("Failed to prune PendingRemediations: {}", err);
+            }
+        }
+
+        // Resolve PagerDuty incidents and close OpsGenie alerts whose alert is no longer firing. Skipped when the poll failed,
+        // since an empty alert list then says nothing about what resolved.
+        if polled && is_leader.borrow().contains(&true) {
+            let resolved: Vec<String> = pagerduty_triggered
+                .keys()
+                .filter(|fingerprint| !active_fingerprints.contains(*fingerprint))
+                .cloned()
+                .collect();
+            for fingerprint in resolved {
+                let routing_key = &pagerduty_triggered[&fingerprint];
+                match ctx
+                    .traced(
+                        "resolve_pagerduty",
+                        &[("alert_actor.fingerprint", &fingerprint)],
+                        send_pagerduty_event(&args.pagerduty_url, routing_key, &fingerprint, None),
+                    )
+                    .await
+                {
+                    Ok(()) => {
+                        info!("Resolved PagerDuty event for alert {}", fingerprint);
+                        pagerduty_triggered.remove(&fingerprint);
+                    }
+                    Err(err) => error!("Failed to resolve PagerDuty event: {}", err),
+                }
+            }
+            let closed: Vec<String> = opsgenie_created
+                .iter()
+                .filter(|fingerprint| !active_fingerprints.contains(*fingerprint))
+                .cloned()
+                .collect();
+            for fingerprint in closed {
+                match ctx
+                    .traced(
+                        "close_opsgenie",
+                        &[("alert_actor.fingerprint", &fingerprint)],
+                        send_opsgenie_alert(&args, &fingerprint, None),
+                    )
+                    .await
+                {
+                    Ok(()) => {
+                        info!("Closed OpsGenie alert for alert {}", fingerprint);
+                        opsgenie_created.remove(&fingerprint);
+                    }
+                    Err(err) => error!("Failed to close OpsGenie alert: {}", err),
+                }
+            }
+        }
+
+        if let Some(endpoint) = &args.otlp_endpoint {
+            if let Err(err) = ctx.trace.export(endpoint, &ctx.correlation_id).await {
+                warn!(
+                    "Failed to export trace of cycle {}: {}",
+                    ctx.correlation_id, err
+                );
+            }
+        }
+    }
+
+    info!("shutting down...");
+    release_tx.send_replace(true);
+    for result in join_all(elections).await {
+        if let Err(err) = result {
+            warn!("leader election task failed: {}", err);
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,580 @@
+//! A small CEL-like expression language for matching alerts in remediation rules, e.g.
+//! `labels.severity == "critical" && labels.namespace.startsWith("prod-")`
+
+use regex::Regex;
+use serde_json::{Number, Value};
+use std::error::Error;
+
+#[derive(Clone, Debug, PartialEq)]
+enum Token {
+    Ident(String),
+    Str(String),
+    Num(f64),
+    Op(&'static str),
+}
+
+/// A parsed expression, evaluated against an alert (or any JSON value)
+#[derive(Clone, Debug)]
+pub enum Expr {
+    Literal(Value),
+    List(Vec<Expr>),
+    Ident(String),
+    Field(Box<Expr>, String),
+    Index(Box<Expr>, Box<Expr>),
+    Call(Option<Box<Expr>>, String, Vec<Expr>),
+    Not(Box<Expr>),
+    Neg(Box<Expr>),
+    Binary(&'static str, Box<Expr>, Box<Expr>),
+}
+
+/// Functions and how many arguments they take, a method's receiver included
+const FUNCTIONS: [(&str, usize); 10] = [
+    ("has", 1),
+    ("size", 1),
+    ("int", 1),
+    ("double", 1),
+    ("startsWith", 2),
+    ("endsWith", 2),
+    ("contains", 2),
+    ("matches", 2),
+    ("lowerAscii", 1),
+    ("upperAscii", 1),
+];
+
+const OPERATORS: [&str; 17] = [
+    "&&", "||", "==", "!=", "<=", ">=", "<", ">", "!", "-", "(", ")", "[", "]", ".", ",", "+",
+];
+
+fn tokenize(source: &str) -> Result<Vec<Token>, Box<dyn Error>> {
+    let mut tokens = Vec::new();
+    let chars: Vec<char> = source.chars().collect();
+    let mut i = 0;
+    while i < chars.len() {
+        let c = chars[i];
+        if c.is_whitespace() {
+            i += 1;
+        } else if c == '"' || c == '\'' {
+            let mut value = String::new();
+            i += 1;
+            loop {
+                match chars.get(i) {
+                    None => return Err("unterminated string".into()),
+                    Some(&q) if q == c => break,
+                    Some('\\') => {
+                        i += 1;
+                        match chars.get(i) {
+                            Some('n') => value.push('\n'),
+                            Some('t') => value.push('\t'),
+                            Some(&other) => value.push(other),
+                            None => return Err("unterminated string".into()),
+                        }
+                    }
+                    Some(&other) => value.push(other),
+                }
+                i += 1;
+            }
+            i += 1;
+            tokens.push(Token::Str(value));
+        } else if c.is_ascii_digit() {
+            let start = i;
+            while i < chars.len() && (chars[i].is_ascii_digit() || chars[i] == '.') {
+                i += 1;
+            }
+            let text: String = chars[start..i].iter().collect();
+            tokens.push(Token::Num(text.parse()?));
+        } else if c.is_alphabetic() || c == '_' {
+            let start = i;
+            while i < chars.len() && (chars[i].is_alphanumeric() || chars[i] == '_') {
+                i += 1;
+            }
+            tokens.push(Token::Ident(chars[start..i].iter().collect()));
+        } else {
+            let rest: String = chars[i..chars.len().min(i + 2)].iter().collect();
+            let op = OPERATORS
+                .iter()
+                .find(|op| rest.starts_with(**op))
+                .ok_or_else(|| format!("unexpected character '{c}'"))?;
+            i += op.len();
+            tokens.push(Token::Op(op));
+        }
+    }
+    Ok(tokens)
+}
+
+struct Parser {
+    tokens: Vec<Token>,
+    pos: usize,
+}
+
+impl Parser {
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.pos)
+    }
+
+    fn eat(&mut self, op: &str) -> bool {
+        if matches!(self.peek(), Some(Token::Op(o)) if *o == op) {
+            self.pos += 1;
+            true
+        } else {
+            false
+        }
+    }
+
+    fn expect(&mut self, op: &str) -> Result<(), Box<dyn Error>> {
+        if self.eat(op) {
+            Ok(())
+        } else {
+            Err(format!("expected '{op}' at token {}", self.pos + 1).into())
+        }
+    }
+
+    fn or(&mut self) -> Result<Expr, Box<dyn Error>> {
+        let mut left = self.and()?;
+        while self.eat("||") {
+            left = Expr::Binary("||", Box::new(left), Box::new(self.and()?));
+        }
+        Ok(left)
+    }
+
+    fn and(&mut self) -> Result<Expr, Box<dyn Error>> {
+        let mut left = self.comparison()?;
+        while self.eat("&&") {
+            left = Expr::Binary("&&", Box::new(left), Box::new(self.comparison()?));
+        }
+        Ok(left)
+    }
+
+    fn comparison(&mut self) -> Result<Expr, Box<dyn Error>> {
+        let left = self.sum()?;
+        for op in ["==", "!=", "<=", ">=", "<", ">"] {
+            if self.eat(op) {
+                return Ok(Expr::Binary(op, Box::new(left), Box::new(self.sum()?)));
+            }
+        }
+        if self.peek() == Some(&Token::Ident("in".to_string())) {
+            self.pos += 1;
+            return Ok(Expr::Binary("in", Box::new(left), Box::new(self.sum()?)));
+        }
+        Ok(left)
+    }
+
+    fn sum(&mut self) -> Result<Expr, Box<dyn Error>> {
+        let mut left = self.unary()?;
+        loop {
+            if self.eat("+") {
+                left = Expr::Binary("+", Box::new(left), Box::new(self.unary()?));
+            } else if self.eat("-") {
+                left = Expr::Binary("-", Box::new(left), Box::new(self.unary()?));
+            } else {
+                return Ok(left);
+            }
+        }
+    }
+
+    fn unary(&mut self) -> Result<Expr, Box<dyn Error>> {
+        if self.eat("!") {
+            Ok(Expr::Not(Box::new(self.unary()?)))
+        } else if self.eat("-") {
+            Ok(Expr::Neg(Box::new(self.unary()?)))
+        } else {
+            self.postfix()
+        }
+    }
+
+    fn arguments(&mut self, close: &str) -> Result<Vec<Expr>, Box<dyn Error>> {
+        let mut args = Vec::new();
+        if self.eat(close) {
+            return Ok(args);
+        }
+        loop {
+            args.push(self.or()?);
+            if self.eat(close) {
+                return Ok(args);
+            }
+            self.expect(",")?;
+        }
+    }
+
+    fn postfix(&mut self) -> Result<Expr, Box<dyn Error>> {
+        let mut expr = self.primary()?;
+        loop {
+            if self.eat(".") {
+                let Some(Token::Ident(name)) = self.peek().cloned() else {
+                    return Err(format!("expected a field name at token {}", self.pos + 1).into());
+                };
+                self.pos += 1;
+                expr = if self.eat("(") {
+                    Expr::Call(Some(Box::new(expr)), name, self.arguments(")")?)
+                } else {
+                    Expr::Field(Box::new(expr), name)
+                };
+            } else if self.eat("[") {
+                let index = self.or()?;
+                self.expect("]")?;
+                expr = Expr::Index(Box::new(expr), Box::new(index));
+            } else {
+                return Ok(expr);
+            }
+        }
+    }
+
+    fn primary(&mut self) -> Result<Expr, Box<dyn Error>> {
+        let token = self.peek().cloned().ok_or("unexpected end of expression")?;
+        self.pos += 1;
+        match token {
+            Token::Str(value) => Ok(Expr::Literal(Value::String(value))),
+            Token::Num(value) => Ok(Expr::Literal(json_number(value))),
+            Token::Ident(name) => match name.as_str() {
+                "true" => Ok(Expr::Literal(Value::Bool(true))),
+                "false" => Ok(Expr::Literal(Value::Bool(false))),
+                "null" => Ok(Expr::Literal(Value::Null)),
+                _ if self.eat("(") => Ok(Expr::Call(None, name, self.arguments(")")?)),
+                _ => Ok(Expr::Ident(name)),
+            },
+            Token::Op("(") => {
+                let expr = self.or()?;
+                self.expect(")")?;
+                Ok(expr)
+            }
+            Token::Op("[") => Ok(Expr::List(self.arguments("]")?)),
+            Token::Op(op) => Err(format!("unexpected '{op}' at token {}", self.pos).into()),
+        }
+    }
+}
+
+fn json_number(value: f64) -> Value {
+    Number::from_f64(value).map_or(Value::Null, Value::Number)
+}
+
+fn as_number(value: &Value) -> Option<f64> {
+    match value {
+        Value::Number(n) => n.as_f64(),
+        _ => None,
+    }
+}
+
+fn as_str<'a>(value: &'a Value, what: &str) -> Result<&'a str, String> {
+    value
+        .as_str()
+        .ok_or_else(|| format!("{what} needs a string, got {value}"))
+}
+
+fn equal(left: &Value, right: &Value) -> bool {
+    match (as_number(left), as_number(right)) {
+        (Some(l), Some(r)) => l == r,
+        _ => left == right,
+    }
+}
+
+fn truthy(value: &Value, what: &str) -> Result<bool, String> {
+    value
+        .as_bool()
+        .ok_or_else(|| format!("{what} needs a bool, got {value}"))
+}
+
+impl Expr {
+    /// Parse an expression
+    pub fn parse(source: &str) -> Result<Expr, Box<dyn Error>> {
+        let mut parser = Parser {
+            tokens: tokenize(source)?,
+            pos: 0,
+        };
+        let expr = parser.or()?;
+        if parser.pos < parser.tokens.len() {
+            return Err(format!("unexpected trailing input at token {}", parser.pos + 1).into());
+        }
+        Ok(expr)
+    }
+
+    /// Find mistakes that would otherwise only surface while evaluating: unknown functions, wrong
+    /// argument counts and invalid regex literals
+    pub fn check(&self) -> Result<(), String> {
+        match self {
+            Expr::Literal(_) | Expr::Ident(_) => Ok(()),
+            Expr::List(items) => items.iter().try_for_each(Expr::check),
+            Expr::Field(object, _) | Expr::Not(object) | Expr::Neg(object) => object.check(),
+            Expr::Index(left, right) | Expr::Binary(_, left, right) => {
+                left.check()?;
+                right.check()
+            }
+            Expr::Call(receiver, name, args) => {
+                let arguments: Vec<&Expr> = receiver.iter().map(|r| &**r).chain(args).collect();
+                let (_, arity) = FUNCTIONS
+                    .iter()
+                    .find(|(function, _)| function == name)
+                    .ok_or_else(|| format!("unknown function {name}()"))?;
+                if arguments.len() != *arity {
+                    return Err(format!("{name}() takes {arity} argument(s)"));
+                }
+                if let ("matches", Some(Expr::Literal(Value::String(pattern)))) =
+                    (name.as_str(), arguments.last())
+                {
+                    Regex::new(pattern).map_err(|e| format!("invalid regex '{pattern}': {e}"))?;
+                }
+                arguments.into_iter().try_for_each(Expr::check)
+            }
+        }
+    }
+
+    /// Whether the expression holds for the value. Errors (a missing field used as a string,
+    /// a comparison of mismatched types, ...) are returned rather than treated as false.
+    pub fn matches(&self, root: &Value) -> Result<bool, String> {
+        truthy(&self.eval(root)?, "expression")
+    }
+
+    fn eval(&self, root: &Value) -> Result<Value, String> {
+        match self {
+            Expr::Literal(value) => Ok(value.clone()),
+            Expr::List(items) => Ok(Value::Array(
+                items
+                    .iter()
+                    .map(|item| item.eval(root))
+                    .collect::<Result<_, _>>()?,
+            )),
+            Expr::Ident(name) => Ok(root.get(name).cloned().unwrap_or(Value::Null)),
+            Expr::Field(object, name) => {
+                Ok(object.eval(root)?.get(name).cloned().unwrap_or(Value::Null))
+            }
+            Expr::Index(object, index) => {
+                let object = object.eval(root)?;
+                Ok(match index.eval(root)? {
+                    Value::String(key) => object.get(&key).cloned(),
+                    Value::Number(n) => n
+                        .as_f64()
+                        .filter(|i| *i >= 0.0 && i.fract() == 0.0)
+                        .and_then(|i| object.get(i as usize))
+                        .cloned(),
+                    other => return Err(format!("cannot index with {other}")),
+                }
+                .unwrap_or(Value::Null))
+            }
+            Expr::Not(inner) => Ok(Value::Bool(!truthy(&inner.eval(root)?, "'!'")?)),
+            Expr::Neg(inner) => as_number(&inner.eval(root)?)
+                .map(|n| json_number(-n))
+                .ok_or_else(|| "'-' needs a number".to_string()),
+            Expr::Binary("&&", left, right) => Ok(Value::Bool(
+                truthy(&left.eval(root)?, "'&&'")? && truthy(&right.eval(root)?, "'&&'")?,
+            )),
+            Expr::Binary("||", left, right) => Ok(Value::Bool(
+                truthy(&left.eval(root)?, "'||'")? || truthy(&right.eval(root)?, "'||'")?,
+            )),
+            Expr::Binary(op, left, right) => {
+                let (left, right) = (left.eval(root)?, right.eval(root)?);
+                binary(op, &left, &right)
+            }
+            Expr::Call(receiver, name, args) => {
+                let mut values = Vec::new();
+                if let Some(receiver) = receiver {
+                    values.push(receiver.eval(root)?);
+                }
+                for arg in args {
+                    values.push(arg.eval(root)?);
+                }
+                call(name, &values)
+            }
+        }
+    }
+}
+
+fn binary(op: &str, left: &Value, right: &Value) -> Result<Value, String> {
+    let ordering = || match (left, right) {
+        (Value::String(l), Value::String(r)) => Ok(l.cmp(r)),
+        _ => match (as_number(left), as_number(right)) {
+            (Some(l), Some(r)) => l
+                .partial_cmp(&r)
+                .ok_or_else(|| "cannot compare NaN".to_string()),
+            _ => Err(format!("cannot compare {left} and {right} with '{op}'")),
+        },
+    };
+    Ok(match op {
+        "==" => Value::Bool(equal(left, right)),
+        "!=" => Value::Bool(!equal(left, right)),
+        "<" => Value::Bool(ordering()?.is_lt()),
+        "<=" => Value::Bool(ordering()?.is_le()),
+        ">" => Value::Bool(ordering()?.is_gt()),
+        ">=" => Value::Bool(ordering()?.is_ge()),
+        "in" => Value::Bool(match right {
+            Value::Array(items) => items.iter().any(|item| equal(left, item)),
+            Value::Object(map) => map.contains_key(as_str(left, "'in'")?),
+            Value::Null => false,
+            _ => return Err(format!("'in' needs a list or map, got {right}")),
+        }),
+        "+" => match (left, right) {
+            (Value::String(l), Value::String(r)) => Value::String(format!("{l}{r}")),
+            _ => match (as_number(left), as_number(right)) {
+                (Some(l), Some(r)) => json_number(l + r),
+                _ => return Err(format!("cannot add {left} and {right}")),
+            },
+        },
+        "-" => match (as_number(left), as_number(right)) {
+            (Some(l), Some(r)) => json_number(l - r),
+            _ => return Err(format!("cannot subtract {right} from {left}")),
+        },
+        _ => return Err(format!("unknown operator '{op}'")),
+    })
+}
+
+fn call(name: &str, args: &[Value]) -> Result<Value, String> {
+    let arity = |n: usize| {
+        if args.len() == n {
+            Ok(())
+        } else {
+            Err(format!("{name}() takes {n} argument(s)"))
+        }
+    };
+    match name {
+        "has" => {
+            arity(1)?;
+            Ok(Value::Bool(!args[0].is_null()))
+        }
+        "size" => {
+            arity(1)?;
+            let size = match &args[0] {
+                Value::String(s) => s.chars().count(),
+                Value::Array(items) => items.len(),
+                Value::Object(map) => map.len(),
+                Value::Null => 0,
+                other => return Err(format!("size() of {other}")),
+            };
+            Ok(json_number(size as f64))
+        }
+        "int" | "double" => {
+            arity(1)?;
+            let number = match &args[0] {
+                Value::Number(n) => n.as_f64(),
+                Value::String(s) => s.trim().parse().ok(),
+                _ => None,
+            }
+            .ok_or_else(|| format!("{name}() of {}", args[0]))?;
+            Ok(json_number(if name == "int" {
+                number.trunc()
+            } else {
+                number
+            }))
+        }
+        "startsWith" | "endsWith" | "contains" | "matches" => {
+            arity(2)?;
+            let (subject, arg) = (as_str(&args[0], name)?, as_str(&args[1], name)?);
+            Ok(Value::Bool(match name {
+                "startsWith" => subject.starts_with(arg),
+                "endsWith" => subject.ends_with(arg),
+                "contains" => subject.contains(arg),
+                _ => Regex::new(arg)
+                    .map_err(|e| format!("invalid regex '{arg}': {e}"))?
+                    .is_match(subject),
+            }))
+        }
+        "lowerAscii" | "upperAscii" => {
+            arity(1)?;
+            let subject = as_str(&args[0], name)?;
+            Ok(Value::String(if name == "lowerAscii" {
+                subject.to_ascii_lowercase()
+            } else {
+                subject.to_ascii_uppercase()
+            }))
+        }
+        _ => Err(format!("unknown function {name}()")),
+    }
+}
+
+enum Step<'a> {
+    Key(&'a str),
+    Index(usize),
+}
+
+/// Resolve a JSONPath-style reference such as `$.labels.pod_name`,
+/// `$.annotations['kubernetes.io/pod']` or `$.receivers[0].name`. Only child and index steps
+/// are supported; a step into something that isn't there resolves to `None`.
+pub fn json_path<'a>(root: &'a Value, path: &str) -> Result<Option<&'a Value>, Box<dyn Error>> {
+    let invalid = || format!("invalid path '{path}'");
+    let mut steps = Vec::new();
+    let mut rest = path.trim();
+    rest = rest.strip_prefix('$').unwrap_or(rest);
+    while !rest.is_empty() {
+        if let Some(after) = rest.strip_prefix('.') {
+            let end = after.find(['.', '[']).unwrap_or(after.len());
+            if end == 0 {
+                return Err(invalid().into());
+            }
+            steps.push(Step::Key(&after[..end]));
+            rest = &after[end..];
+        } else if let Some(after) = rest.strip_prefix('[') {
+            let end = after.find(']').ok_or_else(invalid)?;
+            let step = after[..end].trim();
+            steps.push(match step.chars().next() {
+                Some(quote @ ('\'' | '"')) => Step::Key(
+                    step.strip_prefix(quote)
+                        .and_then(|s| s.strip_suffix(quote))
+                        .ok_or_else(invalid)?,
+                ),
+                _ => Step::Index(step.parse().map_err(|_| invalid())?),
+            });
+            rest = &after[end + 1..];
+        } else {
+            return Err(invalid().into());
+        }
+    }
+    Ok(steps
+        .into_iter()
+        .try_fold(root, |current, step| match step {
+            Step::Key(key) => current.get(key),
+            Step::Index(index) => current.get(index),
+        }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn matches(source: &str, root: &Value) -> Result<bool, String> {
+        Expr::parse(source)
+            .map_err(|e| e.to_string())?
+            .matches(root)
+    }
+
+    #[test]
+    fn evaluates_comparisons_and_logic() {
+        let alert = json!({
+            "labels": { "severity": "critical", "namespace": "prod-payments", "replicas": 3 },
+        });
+        assert_eq!(
+            matches(r#"labels.severity == "critical""#, &alert),
+            Ok(true)
+        );
+        assert_eq!(
+            matches(
+                r#"labels.severity == "critical" && labels.namespace.startsWith("prod-")"#,
+                &alert
+            ),
+            Ok(true)
+        );
+        assert_eq!(
+            matches(
+                r#"labels.severity == "warning" || !labels.namespace.startsWith("kube-")"#,
+                &alert
+            ),
+            Ok(true)
+        );
+        assert_eq!(matches("labels.replicas > 5", &alert), Ok(false));
+        assert_eq!(
+            matches(r#"labels["severity"] != "critical""#, &alert),
+            Ok(false)
+        );
+    }
+
+    #[test]
+    fn reports_errors_instead_of_false() {
+        let alert = json!({ "labels": { "severity": "critical" } });
+        assert!(matches(r#"labels.missing.startsWith("x")"#, &alert).is_err());
+        assert!(matches("labels.severity", &alert).is_err());
+    }
+
+    #[test]
+    fn rejects_invalid_source() {
+        assert!(Expr::parse("labels.severity ==").is_err());
+        assert!(Expr::parse(r#"labels.severity == "critical" )"#).is_err());
+        let unknown = Expr::parse(r#"labels.severity.frobnicate("x")"#).unwrap();
+        assert!(unknown.check().is_err());
+    }
+}
@@ -0,0 +1,472 @@
+//! The checks an alert passes before its action is planned, in the order the controller runs them
+
+use k8s_openapi::chrono::{DateTime, Utc};
+use log::{error, info};
+use std::{
+    collections::HashMap,
+    fmt,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use crate::{
+    actions::{protected_namespace, PlannedAction},
+    alertmanager::Alert,
+    config::Args,
+    rules::{circuit_key, outside_windows, required_confirmations, stable_hash},
+    state::Circuit,
+};
+
+/// Why an alert is left alone this cycle
+#[derive(Debug, Clone, PartialEq)]
+pub enum Skip {
+    /// Acted on less than the cooldown ago
+    Cooldown,
+    /// A human acknowledged it as handled externally
+    Acknowledged,
+    /// Not in the --canary-percent sample
+    OutsideCanary(u64),
+    /// Its target resolves and fires again too often
+    Flapping(String),
+    /// Fewer consecutive firing polls than required
+    Unconfirmed { seen: u64, required: u64 },
+    /// Its confirmations label can't be parsed
+    InvalidConfirmations(String),
+    /// The pause ConfigMap suspends all actions
+    Paused,
+    /// A quiet window matches or no active window does
+    OutsideWindow,
+    /// Its windows can't be parsed, which leaves it alone as well
+    InvalidWindow(String),
+    /// The circuit of its downstream is open
+    CircuitOpen(String),
+    /// A destructive action would touch a protected namespace
+    ProtectedNamespace(String),
+    /// The same action on the same target is already planned for another alert
+    Duplicate(String),
+}
+
+impl Skip {
+    /// Short name of the reason, e.g. for metrics
+    pub fn reason(&self) -> &'static str {
+        match self {
+            Skip::Cooldown => "cooldown",
+            Skip::Acknowledged => "acknowledged",
+            Skip::OutsideCanary(_) => "canary",
+            Skip::Flapping(_) => "flapping",
+            Skip::Unconfirmed { .. } => "unconfirmed",
+            Skip::InvalidConfirmations(_) => "invalid_confirmations",
+            Skip::Paused => "paused",
+            Skip::OutsideWindow => "outside_window",
+            Skip::InvalidWindow(_) => "invalid_window",
+            Skip::CircuitOpen(_) => "circuit_open",
+            Skip::ProtectedNamespace(_) => "protected_namespace",
+            Skip::Duplicate(_) => "deduplicated",
+        }
+    }
+
+    /// Log the skip, as an error when configuration or the alert is at fault
+    pub fn log(&self, alert: &Alert) {
+        match self {
+            Skip::InvalidConfirmations(err) => error!("{} for alert {}", err, alert.fingerprint),
+            Skip::InvalidWindow(err) => {
+                error!("Invalid window for alert {}: {}", alert.fingerprint, err)
+            }
+            Skip::ProtectedNamespace(_) => {
+                error!("Refusing to act on alert {}, {}", alert.fingerprint, self)
+            }
+            _ => info!("Skipping alert {} - {}", alert.fingerprint, self),
+        }
+    }
+}
+
+impl fmt::Display for Skip {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Skip::Cooldown => write!(f, "on cooldown"),
+            Skip::Acknowledged => write!(f, "acknowledged as handled externally"),
+            Skip::OutsideCanary(percent) => write!(f, "outside the {}% canary sample", percent),
+            Skip::Flapping(target) => write!(f, "target {} is suppressed for flapping", target),
+            Skip::Unconfirmed { seen, required } => write!(
+                f,
+                "firing in {} of {} required consecutive polls",
+                seen, required
+            ),
+            Skip::InvalidConfirmations(err) => write!(f, "{}", err),
+            Skip::Paused => write!(f, "actions are paused"),
+            Skip::OutsideWindow => write!(f, "outside of its action windows"),
+            Skip::InvalidWindow(err) => write!(f, "invalid window: {}", err),
+            Skip::CircuitOpen(circuit) => write!(f, "circuit for {} is open", circuit),
+            Skip::ProtectedNamespace(namespace) => {
+                write!(f, "namespace {} is protected", namespace)
+            }
+            Skip::Duplicate(first) => write!(f, "already planned for alert {}", first),
+        }
+    }
+}
+
+/// What the gates of one cycle look at. The controller owns the state; gates only read it.
+pub struct Gates<'a> {
+    pub args: &'a Args,
+    pub now: Instant,
+    pub wall_clock: DateTime<Utc>,
+    /// Emergency mode ignores rule cooldowns and flap suppression
+    pub emergency: bool,
+    /// Cooldown of alerts whose rule has none of its own
+    pub cooldown: Duration,
+    /// When each alert was last acted on
+    pub alert_cooldown: &'a HashMap<String, Instant>,
+    /// Acknowledgements, which can arrive over HTTP mid-cycle
+    pub acknowledged: &'a Mutex<HashMap<String, Instant>>,
+    /// Targets suppressed for flapping, until when
+    pub flap_suppressed: &'a HashMap<String, Instant>,
+    /// Consecutive polls each alert was firing in
+    pub firing_polls: &'a HashMap<String, u64>,
+    pub circuits: &'a HashMap<String, Circuit>,
+    /// Whether actions are paused right now
+    pub paused: &'a dyn Fn() -> bool,
+}
+
+impl Gates<'_> {
+    /// Checks on the alert as it came in, before annotations and its rule fill in parameters
+    pub fn before_rule(&self, alert: &Alert, rule_cooldown_secs: Option<u64>) -> Result<(), Skip> {
+        let cooldown = match rule_cooldown_secs {
+            Some(secs) if !self.emergency => Duration::from_secs(secs),
+            _ => self.cooldown,
+        };
+        if self
+            .alert_cooldown
+            .get(&alert.fingerprint)
+            .is_some_and(|last| self.now.duration_since(*last) <= cooldown)
+        {
+            return Err(Skip::Cooldown);
+        }
+        // Humans mid-incident can tell us to stand down on specific alerts
+        if self
+            .acknowledged
+            .lock()
+            .unwrap()
+            .get(&alert.fingerprint)
+            .is_some_and(|until| self.now < *until)
+        {
+            return Err(Skip::Acknowledged);
+        }
+        let canary = self.args.canary_percent;
+        if canary < 100 && stable_hash(&alert.labels, &self.args.hash_labels) % 100 >= canary {
+            return Err(Skip::OutsideCanary(canary));
+        }
+        Ok(())
+    }
+
+    /// Checks once the action and its target are known, against the actions planned so far
+    pub fn after_rule(
+        &self,
+        alert: &Alert,
+        action: &str,
+        target: &str,
+        planned: &[PlannedAction],
+    ) -> Result<(), Skip> {
+        if !self.emergency
+            && self
+                .flap_suppressed
+                .get(target)
+                .is_some_and(|until| self.now < *until)
+        {
+            return Err(Skip::Flapping(target.to_string()));
+        }
+        // Flapping alerts that resolve within a few polls are left to resolve
+        let required =
+            required_confirmations(self.args, &alert.labels).map_err(Skip::InvalidConfirmations)?;
+        let seen = self
+            .firing_polls
+            .get(&alert.fingerprint)
+            .copied()
+            .unwrap_or_default();
+        if seen < required {
+            return Err(Skip::Unconfirmed { seen, required });
+        }
+        if (self.paused)() {
+            return Err(Skip::Paused);
+        }
+        // Maintenance windows pause actions, active windows confine them
+        if outside_windows(self.args, &alert.labels, self.wall_clock)
+            .map_err(Skip::InvalidWindow)?
+        {
+            return Err(Skip::OutsideWindow);
+        }
+        // A failing downstream is given a rest instead of being hit every cycle
+        let circuit = circuit_key(action, alert, self.args);
+        if self
+            .circuits
+            .get(&circuit)
+            .and_then(|circuit| circuit.open_until)
+            .is_some_and(|until| self.now < until)
+        {
+            return Err(Skip::CircuitOpen(circuit));
+        }
+        // Defense in depth against alerting rules pointing at control-plane pods
+        if let Some(namespace) = protected_namespace(self.args, action, &alert.labels) {
+            return Err(Skip::ProtectedNamespace(namespace.to_string()));
+        }
+        // Several alerts often point at the same pod; act on it once per cycle
+        if let Some(first) = planned
+            .iter()
+            .find(|planned| planned.target == target && planned.action == action)
+        {
+            return Err(Skip::Duplicate(first.alert.fingerprint.clone()));
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use clap::Parser;
+    use serde_json::json;
+
+    fn args(flags: &[&str]) -> Args {
+        Args::try_parse_from(
+            [
+                "alert-actor",
+                "--alertmanager-url",
+                "http://alertmanager:9093",
+                "--no-leader-election",
+            ]
+            .iter()
+            .chain(flags),
+        )
+        .unwrap()
+    }
+
+    fn alert(labels: serde_json::Value) -> Alert {
+        serde_json::from_value(json!({
+            "fingerprint": "abc123",
+            "status": { "state": "active" },
+            "labels": labels,
+        }))
+        .unwrap()
+    }
+
+    /// Cycle state everything passes, for tests to spoil one part of
+    #[derive(Default)]
+    struct State {
+        alert_cooldown: HashMap<String, Instant>,
+        acknowledged: Mutex<HashMap<String, Instant>>,
+        flap_suppressed: HashMap<String, Instant>,
+        firing_polls: HashMap<String, u64>,
+        circuits: HashMap<String, Circuit>,
+        paused: bool,
+    }
+
+    impl State {
+        fn new() -> State {
+            State {
+                firing_polls: HashMap::from([("abc123".to_string(), 1)]),
+                ..Default::default()
+            }
+        }
+
+        fn gates<'a>(&'a self, args: &'a Args, paused: &'a dyn Fn() -> bool) -> Gates<'a> {
+            Gates {
+                args,
+                now: Instant::now(),
+                wall_clock: Utc::now(),
+                emergency: false,
+                cooldown: Duration::from_secs(args.cooldown_secs),
+                alert_cooldown: &self.alert_cooldown,
+                acknowledged: &self.acknowledged,
+                flap_suppressed: &self.flap_suppressed,
+                firing_polls: &self.firing_polls,
+                circuits: &self.circuits,
+                paused,
+            }
+        }
+    }
+
+    fn pod_alert() -> Alert {
+        alert(json!({ "alertname": "A", "namespace": "payments", "pod": "api-1" }))
+    }
+
+    fn check(
+        state: &State,
+        args: &Args,
+        alert: &Alert,
+        planned: &[PlannedAction],
+    ) -> Result<(), Skip> {
+        let paused = || state.paused;
+        let gates = state.gates(args, &paused);
+        gates.before_rule(alert, None)?;
+        gates.after_rule(alert, "delete_pod", "payments/api-1", planned)
+    }
+
+    #[test]
+    fn a_fresh_alert_passes() {
+        let args = args(&[]);
+        assert_eq!(check(&State::new(), &args, &pod_alert(), &[]), Ok(()));
+    }
+
+    #[test]
+    fn cooldown_follows_the_rule_unless_in_emergency() {
+        let args = args(&[]);
+        let mut state = State::new();
+        let now = Instant::now();
+        state
+            .alert_cooldown
+            .insert("abc123".to_string(), now - Duration::from_secs(60));
+        let paused = || false;
+        let mut gates = state.gates(&args, &paused);
+        gates.now = now;
+        assert_eq!(gates.before_rule(&pod_alert(), None), Err(Skip::Cooldown));
+        assert_eq!(gates.before_rule(&pod_alert(), Some(30)), Ok(()));
+        gates.emergency = true;
+        gates.cooldown = Duration::from_secs(10);
+        assert_eq!(gates.before_rule(&pod_alert(), Some(600)), Ok(()));
+    }
+
+    #[test]
+    fn skip_reasons() {
+        let args = args(&["--protected-namespaces", "payments"]);
+        let state = State::new();
+        assert_eq!(
+            check(&state, &args, &pod_alert(), &[]),
+            Err(Skip::ProtectedNamespace("payments".to_string()))
+        );
+
+        let args = self::args(&["--canary-percent", "0"]);
+        assert_eq!(
+            check(&state, &args, &pod_alert(), &[]),
+            Err(Skip::OutsideCanary(0))
+        );
+
+        let args = self::args(&[]);
+        let mut unconfirmed = pod_alert();
+        unconfirmed.labels.confirmations = Some("3".to_string());
+        assert_eq!(
+            check(&state, &args, &unconfirmed, &[]),
+            Err(Skip::Unconfirmed {
+                seen: 1,
+                required: 3
+            })
+        );
+        unconfirmed.labels.confirmations = Some("many".to_string());
+        assert!(matches!(
+            check(&state, &args, &unconfirmed, &[]),
+            Err(Skip::InvalidConfirmations(_))
+        ));
+
+        let mut quiet = pod_alert();
+        quiet.labels.quiet_window = Some("* * * * *".to_string());
+        assert_eq!(check(&state, &args, &quiet, &[]), Err(Skip::OutsideWindow));
+        quiet.labels.quiet_window = Some("* * *".to_string());
+        assert!(matches!(
+            check(&state, &args, &quiet, &[]),
+            Err(Skip::InvalidWindow(_))
+        ));
+
+        let mut circuit = State::new();
+        circuit.circuits.insert(
+            "namespace:payments".to_string(),
+            Circuit {
+                failures: 5,
+                open_until: Some(Instant::now() + Duration::from_secs(60)),
+            },
+        );
+        assert_eq!(
+            check(&circuit, &args, &pod_alert(), &[]),
+            Err(Skip::CircuitOpen("namespace:payments".to_string()))
+        );
+
+        let first = PlannedAction {
+            alert: alert(json!({ "alertname": "B", "namespace": "payments", "pod": "api-1" })),
+            action: "delete_pod".to_string(),
+            target: "payments/api-1".to_string(),
+            rule: None,
+            require_approval: false,
+            shard: 0,
+        };
+        assert_eq!(
+            check(&state, &args, &pod_alert(), &[first]),
+            Err(Skip::Duplicate("abc123".to_string()))
+        );
+    }
+
+    #[test]
+    fn gates_run_in_order() {
+        // Every gate would skip the alert; each one removed reveals the next
+        let args = args(&[
+            "--protected-namespaces",
+            "payments",
+            "--canary-percent",
+            "0",
+        ]);
+        let later = Instant::now() + Duration::from_secs(60);
+        let mut state = State {
+            alert_cooldown: HashMap::from([("abc123".to_string(), Instant::now())]),
+            acknowledged: Mutex::new(HashMap::from([("abc123".to_string(), later)])),
+            flap_suppressed: HashMap::from([("payments/api-1".to_string(), later)]),
+            firing_polls: HashMap::new(),
+            circuits: HashMap::from([(
+                "namespace:payments".to_string(),
+                Circuit {
+                    failures: 5,
+                    open_until: Some(later),
+                },
+            )]),
+            paused: true,
+        };
+        let mut alert = pod_alert();
+        alert.labels.quiet_window = Some("* * * * *".to_string());
+        let planned = [PlannedAction {
+            alert: pod_alert(),
+            action: "delete_pod".to_string(),
+            target: "payments/api-1".to_string(),
+            rule: None,
+            require_approval: false,
+            shard: 0,
+        }];
+        let mut reasons = Vec::new();
+        let mut args = args;
+        loop {
+            let skip = match check(&state, &args, &alert, &planned) {
+                Ok(()) => break,
+                Err(skip) => skip,
+            };
+            reasons.push(skip.reason());
+            match skip {
+                Skip::Cooldown => state.alert_cooldown.clear(),
+                Skip::Acknowledged => state.acknowledged.lock().unwrap().clear(),
+                Skip::OutsideCanary(_) => args.canary_percent = 100,
+                Skip::Flapping(_) => state.flap_suppressed.clear(),
+                Skip::Unconfirmed { .. } => {
+                    state.firing_polls.insert("abc123".to_string(), 1);
+                }
+                Skip::Paused => state.paused = false,
+                Skip::OutsideWindow => alert.labels.quiet_window = None,
+                Skip::CircuitOpen(_) => state.circuits.clear(),
+                Skip::ProtectedNamespace(_) => args.protected_namespaces.clear(),
+                Skip::Duplicate(_) => return assert_order(reasons),
+                other => panic!("unexpected {:?}", other),
+            }
+        }
+        panic!("the duplicate gate never ran: {:?}", reasons);
+    }
+
+    fn assert_order(reasons: Vec<&str>) {
+        assert_eq!(
+            reasons,
+            [
+                "cooldown",
+                "acknowledged",
+                "canary",
+                "flapping",
+                "unconfirmed",
+                "paused",
+                "outside_window",
+                "circuit_open",
+                "protected_namespace",
+                "deduplicated",
+            ]
+        );
+    }
+}
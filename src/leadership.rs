@@ -0,0 +1,152 @@
+//! Leader election over one Lease per shard
+
+use kube_leader_election::LeaseLock;
+use log::{info, warn};
+use std::time::Instant;
+use tokio::{sync::watch, time::Duration};
+
+use crate::{config::Args, metrics::METRICS};
+
+/// How often leases are renewed and retried, derived from the TTL unless set explicitly
+#[derive(Clone, Copy, Debug)]
+pub struct LeaseTiming {
+    pub ttl: Duration,
+    /// Pause between successful renewals of a held lease
+    pub renew_period: Duration,
+    /// A leader that hasn't renewed for this long steps down, safely before the lease expires
+    /// and another replica can take it
+    pub renew_deadline: Duration,
+    /// Pause between attempts of followers, and of leaders after a failed renewal
+    pub retry_period: Duration,
+}
+
+impl LeaseTiming {
+    /// Timing from --lease-secs and the optional overrides. By default leaders renew every third
+    /// of the TTL and give up after two thirds, and retries happen every fifth (at most a second).
+    pub fn from_args(args: &Args) -> Result<LeaseTiming, String> {
+        let ttl = Duration::from_secs(args.lease_secs);
+        let millis = |ms: Option<u64>, default: Duration| ms.map_or(default, Duration::from_millis);
+        let timing = LeaseTiming {
+            ttl,
+            renew_period: millis(args.lease_renew_period_ms, ttl / 3),
+            renew_deadline: millis(args.lease_renew_deadline_ms, ttl * 2 / 3),
+            retry_period: millis(
+                args.lease_retry_period_ms,
+                (ttl / 5).min(Duration::from_secs(1)),
+            ),
+        };
+        if timing.retry_period.is_zero() || timing.renew_period.is_zero() {
+            return Err("lease renew and retry periods must be positive".to_string());
+        }
+        if timing.renew_deadline >= ttl {
+            return Err(format!(
+                "lease renew deadline {:?} must be shorter than the lease TTL {:?}",
+                timing.renew_deadline, ttl
+            ));
+        }
+        if timing.renew_period >= timing.renew_deadline
+            || timing.retry_period >= timing.renew_deadline
+        {
+            return Err(format!(
+                "lease renew period {:?} and retry period {:?} must be shorter than the renew deadline {:?}",
+                timing.renew_period, timing.retry_period, timing.renew_deadline
+            ));
+        }
+        Ok(timing)
+    }
+}
+
+/// Keep competing for one shard's lease, publishing whether we currently hold it. A leader that
+/// fails to renew within the renew deadline steps down and rejoins the election instead of exiting.
+/// Once `release` fires, the lease is given up so a replacement can take over without waiting for
+/// the TTL.
+pub async fn run_leader_election(
+    leadership: LeaseLock,
+    lease_name: String,
+    shard: usize,
+    timing: LeaseTiming,
+    leader_tx: watch::Sender<Vec<bool>>,
+    mut release: watch::Receiver<bool>,
+) {
+    info!("waiting for lock {}...", lease_name);
+    let mut last_renewed: Option<Instant> = None;
+    loop {
+        let mut renewed = false;
+        let started = Instant::now();
+        let attempt = leadership.try_acquire_or_renew().await;
+        METRICS.set(
+            "alert_actor_lease_renew_duration_seconds",
+            &[("lease", &lease_name)],
+            started.elapsed().as_secs_f64(),
+        );
+        let leading = match attempt {
+            Ok(lease) => {
+                if lease.acquired_lease {
+                    last_renewed = Some(Instant::now());
+                    renewed = true;
+                }
+                lease.acquired_lease
+            }
+            Err(err) => {
+                warn!("lease {} error: {}", lease_name, err);
+                METRICS.inc(
+                    "alert_actor_lease_renew_failures_total",
+                    &[("lease", &lease_name)],
+                );
+                // Still leader only while the last successful renewal is within the deadline
+                last_renewed.is_some_and(|at| at.elapsed() < timing.renew_deadline)
+            }
+        };
+        if leading != leader_tx.borrow()[shard] {
+            let transition = if leading {
+                info!("acquired lock {}!", lease_name);
+                "acquired"
+            } else {
+                match last_renewed {
+                    Some(at) => warn!(
+                        "lost lease {} after {:?} without renewal, standing by...",
+                        lease_name,
+                        at.elapsed()
+                    ),
+                    None => warn!("lost lease {}, standing by...", lease_name),
+                }
+                last_renewed = None;
+                "lost"
+            };
+            METRICS.inc(
+                "alert_actor_lease_transitions_total",
+                &[("lease", &lease_name), ("transition", transition)],
+            );
+            leader_tx.send_modify(|owned| owned[shard] = leading);
+        }
+        // Leaders renew well within the deadline and retry quickly after a failure; followers poll
+        // for a free lease, backing off for every shard they already own so that free shards
+        // spread across replicas
+        let period = if leading && renewed {
+            timing.renew_period
+        } else if leading {
+            timing.retry_period
+        } else {
+            let owned = leader_tx.borrow().iter().filter(|owned| **owned).count();
+            timing.retry_period * (1 + owned as u32)
+        };
+        let released = tokio::select! {
+            _ = tokio::time::sleep(period) => false,
+            released = release.wait_for(|release| *release) => released.is_ok(),
+        };
+        if released {
+            if leader_tx.borrow()[shard] {
+                match leadership.step_down().await {
+                    Ok(()) => info!("released lease {}", lease_name),
+                    Err(err) => warn!("failed to release lease {}: {}", lease_name, err),
+                }
+                METRICS.inc(
+                    "alert_actor_lease_transitions_total",
+                    &[("lease", &lease_name), ("transition", "released")],
+                );
+                leader_tx.send_modify(|owned| owned[shard] = false);
+            }
+            return;
+        }
+    }
+}
@@ -0,0 +1,25 @@
+//! alert-actor listens to Alertmanager for alerts and performs actions, mostly on Kubernetes pods,
+//! based on the alert labels. The binary is a thin wrapper around [`controller::run`]; the
+//! modules are public so the matching and action engine can be tested and embedded on its own.
+
+pub mod actions;
+pub mod alertmanager;
+pub mod audit;
+pub mod clusters;
+pub mod config;
+pub mod controller;
+pub mod expr;
+pub mod gates;
+pub mod leadership;
+pub mod metrics;
+pub mod notify;
+pub mod rbac;
+pub mod rules;
+pub mod scaffold;
+pub mod schedule;
+pub mod server;
+pub mod simulate;
+pub mod sources;
+pub mod state;
+pub mod trace;
+pub mod validate;
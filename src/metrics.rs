@@ -0,0 +1,172 @@
+//! Prometheus metrics and their persistence across restarts
+
+use anyhow::Result;
+use k8s_openapi::api::core::v1::ConfigMap;
+use kube::{
+    api::{Patch, PatchParams},
+    Api, Client,
+};
+use serde_json::{json, Value};
+use std::{collections::BTreeMap, error::Error, sync::Mutex};
+
+/// Counters and gauges exposed in Prometheus text format, keyed by metric name and then by label set
+pub struct Metrics {
+    pub counters: Mutex<BTreeMap<String, BTreeMap<String, u64>>>,
+    pub gauges: Mutex<BTreeMap<String, BTreeMap<String, f64>>>,
+}
+
+pub static METRICS: Metrics = Metrics {
+    counters: Mutex::new(BTreeMap::new()),
+    gauges: Mutex::new(BTreeMap::new()),
+};
+
+/// Render a label set as `k="v",...` with values escaped
+pub fn metric_labels(labels: &[(&str, &str)]) -> String {
+    labels
+        .iter()
+        .map(|(k, v)| format!("{}=\"{}\"", k, v.replace('\\', "\\\\").replace('"', "\\\"")))
+        .collect::<Vec<_>>()
+        .join(",")
+}
+
+impl Metrics {
+    pub fn inc(&self, name: &str, labels: &[(&str, &str)]) {
+        let mut counters = self.counters.lock().unwrap();
+        *counters
+            .entry(name.to_string())
+            .or_default()
+            .entry(metric_labels(labels))
+            .or_default() += 1;
+    }
+
+    pub fn set(&self, name: &str, labels: &[(&str, &str)], value: f64) {
+        self.gauges
+            .lock()
+            .unwrap()
+            .entry(name.to_string())
+            .or_default()
+            .insert(metric_labels(labels), value);
+    }
+
+    /// Restore counters saved by an earlier run, never lowering a counter
+    pub fn restore(&self, saved: BTreeMap<String, BTreeMap<String, u64>>) {
+        let mut counters = self.counters.lock().unwrap();
+        for (name, series) in saved {
+            let current = counters.entry(name).or_default();
+            for (labels, value) in series {
+                let counter = current.entry(labels).or_default();
+                *counter = (*counter).max(value);
+            }
+        }
+    }
+
+    pub fn render(&self) -> String {
+        let mut out = String::new();
+        for (name, series) in self.counters.lock().unwrap().iter() {
+            render_series(&mut out, name, "counter", series);
+        }
+        for (name, series) in self.gauges.lock().unwrap().iter() {
+            render_series(&mut out, name, "gauge", series);
+        }
+        out
+    }
+}
+
+/// Append one metric family with all of its series
+pub fn render_series<T: std::fmt::Display>(
+    out: &mut String,
+    name: &str,
+    kind: &str,
+    series: &BTreeMap<String, T>,
+) {
+    out.push_str(&format!("# TYPE {} {}\n", name, kind));
+    for (labels, value) in series {
+        if labels.is_empty() {
+            out.push_str(&format!("{} {}\n", name, value));
+        } else {
+            out.push_str(&format!("{}{{{}}} {}\n", name, labels, value));
+        }
+    }
+}
+
+/// Key of the metrics state ConfigMap holding a replica's counters. Counters are per process,
+/// like the series scraped from it, so each replica keeps its own and none overwrites another's.
+pub fn counters_key(replica: &str) -> String {
+    format!("counters-{}.json", replica)
+}
+
+/// Server-side apply of one replica's key, under a field manager of its own so the keys of other
+/// replicas are left alone
+fn counters_patch(
+    name: &str,
+    replica: &str,
+    counters: &BTreeMap<String, BTreeMap<String, u64>>,
+) -> Result<(PatchParams, Value), Box<dyn Error>> {
+    let configmap = json!({
+        "apiVersion": "v1",
+        "kind": "ConfigMap",
+        "metadata": { "name": name },
+        "data": { counters_key(replica): serde_json::to_string(counters)? },
+    });
+    let params = PatchParams::apply(&format!("alert-actor-counters-{}", replica)).force();
+    Ok((params, configmap))
+}
+
+/// Load the counters `save_counters` persisted for this replica, if any
+pub async fn load_counters(
+    client: Client,
+    name: &str,
+    replica: &str,
+) -> Result<BTreeMap<String, BTreeMap<String, u64>>, Box<dyn Error>> {
+    let configmaps: Api<ConfigMap> = Api::default_namespaced(client);
+    let saved = configmaps
+        .get_opt(name)
+        .await?
+        .and_then(|configmap| configmap.data?.remove(&counters_key(replica)));
+    match saved {
+        Some(saved) => Ok(serde_json::from_str(&saved)?),
+        None => Ok(BTreeMap::new()),
+    }
+}
+
+/// Persist all of this replica's counters, so it continues from them after a restart instead of
+/// from zero
+pub async fn save_counters(
+    client: Client,
+    name: &str,
+    replica: &str,
+    counters: &BTreeMap<String, BTreeMap<String, u64>>,
+) -> Result<(), Box<dyn Error>> {
+    let configmaps: Api<ConfigMap> = Api::default_namespaced(client);
+    let (params, configmap) = counters_patch(name, replica, counters)?;
+    configmaps
+        .patch(name, &params, &Patch::Apply(&configmap))
+        .await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn replicas_save_to_their_own_key_and_field_manager() {
+        let counters = BTreeMap::from([(
+            "alert_actor_actions_total".to_string(),
+            BTreeMap::from([("action=\"delete_pod\"".to_string(), 3)]),
+        )]);
+        let (params, configmap) = counters_patch("metrics", "alert-actor-1", &counters).unwrap();
+        assert_eq!(
+            params.field_manager.as_deref(),
+            Some("alert-actor-counters-alert-actor-1")
+        );
+        let data = configmap["data"].as_object().unwrap();
+        assert_eq!(
+            data.keys().collect::<Vec<_>>(),
+            ["counters-alert-actor-1.json"]
+        );
+        let saved: BTreeMap<String, BTreeMap<String, u64>> =
+            serde_json::from_str(data["counters-alert-actor-1.json"].as_str().unwrap()).unwrap();
+        assert_eq!(saved, counters);
+    }
+}
@@ -0,0 +1,1073 @@
+//! Outbound notifications: webhooks, PagerDuty, OpsGenie and email
+
+use anyhow::Result;
+use base64::{engine::general_purpose::STANDARD as BASE64, Engine};
+use k8s_openapi::chrono::Utc;
+use log::{error, info, warn};
+use reqwest::{
+    header::{HeaderMap, HeaderName, HeaderValue, CONTENT_TYPE},
+    Certificate, Client as HttpClient, ClientBuilder, NoProxy, Proxy,
+};
+use serde::Serialize;
+use serde_json::{json, Value};
+use sha2::{Digest, Sha256};
+use std::{
+    collections::BTreeMap,
+    error::Error,
+    sync::{Mutex, OnceLock},
+};
+use tokio::{
+    io::{AsyncBufReadExt, AsyncRead, AsyncWrite, AsyncWriteExt, BufReader},
+    net::TcpStream,
+    time::Duration,
+};
+use tokio_native_tls::{native_tls, TlsConnector};
+
+use crate::{alertmanager::Alert, config::Args, metrics::METRICS};
+
+/// Client for webhooks, PagerDuty and other outbound notifications. Shared so that connections,
+/// including those opened ahead of time by `prewarm_connections`, are reused across actions.
+/// Set up in main with the configured timeouts.
+pub static NOTIFICATION_CLIENT: OnceLock<HttpClient> = OnceLock::new();
+
+pub fn notification_client() -> &'static HttpClient {
+    NOTIFICATION_CLIENT.get_or_init(HttpClient::new)
+}
+
+/// Render `{{ path.to.field }}` placeholders against the serialized alert.
+/// Missing fields render as empty strings, objects render as JSON, and `\n` in the template text
+/// (not in substituted values) becomes a newline.
+/// Appending `| json` renders the value JSON-encoded, for embedding strings in JSON templates.
+pub fn render_template(template: &str, alert: &Alert) -> String {
+    render_value(
+        template,
+        &serde_json::to_value(alert).unwrap_or(Value::Null),
+    )
+}
+
+/// Render a template like `render_template` against any JSON value, e.g. the alert with extra
+/// context added
+pub fn render_value(template: &str, value: &Value) -> String {
+    let mut rendered = String::new();
+    let mut rest = template;
+    while let Some(start) = rest.find("{{") {
+        let Some(end) = rest[start..].find("}}") else {
+            break;
+        };
+        rendered.push_str(&rest[..start].replace("\\n", "\n"));
+        let expr = rest[start + 2..start + end].trim();
+        let (path, as_json) = match expr.strip_suffix("| json") {
+            Some(path) => (path.trim(), true),
+            None => (expr, false),
+        };
+        let field = path
+            .split('.')
+            .try_fold(value, |v, key| v.get(key))
+            .unwrap_or(&Value::Null);
+        match field {
+            _ if as_json => rendered.push_str(&field.to_string()),
+            Value::Null => {}
+            Value::String(s) => rendered.push_str(s),
+            other => rendered.push_str(&other.to_string()),
+        }
+        rest = &rest[start + end + 2..];
+    }
+    rendered.push_str(&rest.replace("\\n", "\n"));
+    rendered
+}
+
+/// Resolve a configured value that may reference a secret via 'env:VAR' or 'file:/path'
+pub fn resolve_secret(value: &str) -> Result<String, Box<dyn Error>> {
+    if let Some(var) = value.strip_prefix("env:") {
+        std::env::var(var).map_err(|err| format!("env var {}: {}", var, err).into())
+    } else if let Some(path) = value.strip_prefix("file:") {
+        let contents =
+            std::fs::read_to_string(path).map_err(|err| format!("file {}: {}", path, err))?;
+        Ok(contents.trim_end().to_string())
+    } else {
+        Ok(value.to_string())
+    }
+}
+
+/// Build the configured extra webhook headers, resolving secret references
+pub fn webhook_headers(headers: &[String]) -> Result<HeaderMap, Box<dyn Error>> {
+    let mut map = HeaderMap::new();
+    for header in headers {
+        let (name, value) = header.split_once(':').ok_or_else(|| {
+            format!(
+                "invalid webhook header '{}', expected 'Name: value'",
+                header
+            )
+        })?;
+        map.insert(
+            HeaderName::from_bytes(name.trim().as_bytes())?,
+            HeaderValue::from_str(&resolve_secret(value.trim())?)?,
+        );
+    }
+    Ok(map)
+}
+
+/// HMAC-SHA256 (RFC 2104) of a message, hex encoded
+pub fn hmac_sha256_hex(key: &[u8], message: &[u8]) -> String {
+    const BLOCK_SIZE: usize = 64;
+    let mut block = [0u8; BLOCK_SIZE];
+    if key.len() > BLOCK_SIZE {
+        block[..32].copy_from_slice(&Sha256::digest(key));
+    } else {
+        block[..key.len()].copy_from_slice(key);
+    }
+    let ipad: Vec<u8> = block.iter().map(|b| b ^ 0x36).collect();
+    let opad: Vec<u8> = block.iter().map(|b| b ^ 0x5c).collect();
+    let inner = Sha256::new()
+        .chain_update(ipad)
+        .chain_update(message)
+        .finalize();
+    let outer = Sha256::new()
+        .chain_update(opad)
+        .chain_update(inner)
+        .finalize();
+    outer.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+/// An explicit proxy, bypassed for --no-proxy hosts
+pub fn proxy(args: &Args, url: &str) -> Result<Proxy, Box<dyn Error>> {
+    // reqwest is built without SOCKS support, which would otherwise only fail on first use
+    if !(url.starts_with("http://") || url.starts_with("https://")) {
+        return Err(format!("proxy '{}' must be an http:// or https:// URL", url).into());
+    }
+    let no_proxy = args.no_proxy.as_deref().and_then(NoProxy::from_string);
+    Ok(Proxy::all(url)?.no_proxy(no_proxy))
+}
+
+/// Start an HTTP client with the configured timeouts and keep-alive settings, going through
+/// `proxy_url` if set and through the environment's proxies otherwise
+pub fn http_client_builder(
+    args: &Args,
+    proxy_url: Option<&str>,
+) -> Result<ClientBuilder, Box<dyn Error>> {
+    let mut builder = HttpClient::builder()
+        .connect_timeout(Duration::from_secs(args.http_connect_timeout_secs))
+        .timeout(Duration::from_secs(args.http_timeout_secs))
+        .pool_idle_timeout(Duration::from_secs(args.http_pool_idle_timeout_secs))
+        .tcp_keepalive(Duration::from_secs(args.http_tcp_keepalive_secs));
+    if let Some(path) = &args.ca_cert {
+        for cert in ca_certificates(path)? {
+            builder = builder.add_root_certificate(cert);
+        }
+    }
+    if let Some(url) = proxy_url {
+        builder = builder.proxy(proxy(args, url)?);
+    }
+    Ok(builder)
+}
+
+/// Certificates of a PEM CA bundle
+pub fn ca_certificates(path: &str) -> Result<Vec<Certificate>, Box<dyn Error>> {
+    let pem = std::fs::read(path).map_err(|err| format!("{}: {}", path, err))?;
+    let certs = Certificate::from_pem_bundle(&pem).map_err(|err| format!("{}: {}", path, err))?;
+    if certs.is_empty() {
+        return Err(format!("{}: no PEM certificates found", path).into());
+    }
+    Ok(certs)
+}
+
+/// Clients trusting the CA bundle of an alert's `ca_cert` label, by bundle path. Built once, so a
+/// changed bundle takes a restart.
+static CA_CLIENTS: Mutex<BTreeMap<String, HttpClient>> = Mutex::new(BTreeMap::new());
+
+/// Client for requests to the alert's own targets: the shared one, or one that also trusts the
+/// CA bundle named by its `ca_cert` label
+pub fn alert_client(args: &Args, alert: &Alert) -> Result<HttpClient, Box<dyn Error>> {
+    let Some(path) = &alert.labels.ca_cert else {
+        return Ok(notification_client().clone());
+    };
+    if let Some(client) = CA_CLIENTS.lock().unwrap().get(path) {
+        return Ok(client.clone());
+    }
+    let mut builder = http_client_builder(args, args.notification_proxy.as_deref())?;
+    for cert in ca_certificates(path)? {
+        builder = builder.add_root_certificate(cert);
+    }
+    let client = builder.build()?;
+    CA_CLIENTS
+        .lock()
+        .unwrap()
+        .insert(path.clone(), client.clone());
+    Ok(client)
+}
+
+/// Facts summarizing an alert for chat notifications, with the action's outcome if it ran
+pub fn alert_facts(alert: &Alert, outcome: Option<(&str, &str)>) -> Vec<(&'static str, String)> {
+    let labels = &alert.labels;
+    let mut facts = vec![
+        ("Alert", labels.alertname.clone()),
+        ("State", alert.status.state.clone()),
+    ];
+    let optional = [
+        ("Severity", labels.severity.as_ref()),
+        ("Namespace", labels.namespace.as_ref()),
+        ("Pod", labels.pod.as_ref()),
+        ("Node", labels.node.as_ref()),
+        ("Summary", alert.annotations.get("summary")),
+        ("Runbook", alert.annotations.get("runbook_url")),
+    ];
+    for (name, value) in optional {
+        if let Some(value) = value {
+            facts.push((name, value.clone()));
+        }
+    }
+    if let Some((action, result)) = outcome {
+        facts.push(("Action", action.to_string()));
+        facts.push(("Result", result.to_string()));
+    }
+    facts.push(("Fingerprint", alert.fingerprint.clone()));
+    facts
+}
+
+fn teams_webhook_url<'a>(alert: &'a Alert, args: &'a Args) -> Option<&'a str> {
+    alert
+        .labels
+        .teams_webhook_url
+        .as_deref()
+        .or(args.teams_webhook_url.as_deref())
+}
+
+/// Post an Adaptive Card summarizing the alert, and the action's outcome if given, to the
+/// alert's Microsoft Teams incoming webhook
+pub async fn send_teams(
+    args: &Args,
+    alert: &Alert,
+    outcome: Option<(&str, &str)>,
+) -> Result<(), Box<dyn Error>> {
+    let url = teams_webhook_url(alert, args).ok_or("no Teams webhook URL for alert")?;
+    let title = match outcome {
+        Some((action, result)) => format!("{}: {} {}", alert.labels.alertname, action, result),
+        None => format!("{} is {}", alert.labels.alertname, alert.status.state),
+    };
+    let facts: Vec<Value> = alert_facts(alert, outcome)
+        .into_iter()
+        .map(|(title, value)| json!({ "title": title, "value": value }))
+        .collect();
+    let mut body = vec![
+        json!({
+            "type": "TextBlock",
+            "size": "Medium",
+            "weight": "Bolder",
+            "wrap": true,
+            "text": title,
+        }),
+        json!({ "type": "FactSet", "facts": facts }),
+    ];
+    if let Some(url) = &alert.generator_url {
+        body.push(
+            json!({ "type": "TextBlock", "wrap": true, "text": format!("[Source]({})", url) }),
+        );
+    }
+    let message = json!({
+        "type": "message",
+        "attachments": [{
+            "contentType": "application/vnd.microsoft.card.adaptive",
+            "content": {
+                "$schema": "http://adaptivecards.io/schemas/adaptive-card.json",
+                "type": "AdaptiveCard",
+                "version": "1.4",
+                "body": body,
+            },
+        }],
+    });
+    notification_client()
+        .post(url)
+        .json(&message)
+        .send()
+        .await?
+        .error_for_status()?;
+    Ok(())
+}
+
+fn discord_webhook_url<'a>(alert: &'a Alert, args: &'a Args) -> Option<&'a str> {
+    alert
+        .labels
+        .discord_webhook_url
+        .as_deref()
+        .or(args.discord_webhook_url.as_deref())
+}
+
+/// Post an embed summarizing the alert, and the action's outcome if given, to the alert's
+/// Discord webhook
+pub async fn send_discord(
+    args: &Args,
+    alert: &Alert,
+    outcome: Option<(&str, &str)>,
+) -> Result<(), Box<dyn Error>> {
+    let url = discord_webhook_url(alert, args).ok_or("no Discord webhook URL for alert")?;
+    let (title, color) = match outcome {
+        Some((action, "success")) => (
+            format!("{}: {} succeeded", alert.labels.alertname, action),
+            0x2ecc71,
+        ),
+        Some((action, result)) => (
+            format!("{}: {} {}", alert.labels.alertname, action, result),
+            0xe74c3c,
+        ),
+        None => (
+            format!("{} is {}", alert.labels.alertname, alert.status.state),
+            0xe67e22,
+        ),
+    };
+    let fields: Vec<Value> = alert_facts(alert, outcome)
+        .into_iter()
+        .map(|(name, value)| {
+            json!({
+                "name": name,
+                "value": value,
+                "inline": !matches!(name, "Summary" | "Runbook"),
+            })
+        })
+        .collect();
+    let mut embed = json!({
+        "title": title,
+        "color": color,
+        "fields": fields,
+        "timestamp": Utc::now().to_rfc3339(),
+    });
+    if let Some(url) = &alert.generator_url {
+        embed["url"] = json!(url);
+    }
+    notification_client()
+        .post(url)
+        .json(&json!({ "username": "alert-actor", "embeds": [embed] }))
+        .send()
+        .await?
+        .error_for_status()?;
+    Ok(())
+}
+
+/// Tell the channels in the alert's notify label how its action went. Failures are logged,
+/// they don't change the action's outcome.
+pub async fn notify_outcome(args: &Args, alert: &Alert, action: &str, result: &str) {
+    let Some(channels) = &alert.labels.notify else {
+        return;
+    };
+    for channel in channels.split(',').map(str::trim) {
+        let sent = match channel {
+            "teams" => send_teams(args, alert, Some((action, result))).await,
+            "discord" => send_discord(args, alert, Some((action, result))).await,
+            _ => Err(format!("unknown notify channel '{}'", channel).into()),
+        };
+        if let Err(err) = sent {
+            error!(
+                "Failed to notify {} about alert {}: {}",
+                channel, alert.fingerprint, err
+            );
+        }
+    }
+}
+
+/// One action of a poll cycle, as listed in the cycle's digest
+#[derive(Debug, Clone, Serialize)]
+pub struct DigestEntry {
+    pub alertname: String,
+    pub fingerprint: String,
+    /// Matching rule as namespace/name
+    pub rule: Option<String>,
+    pub action: String,
+    pub target: String,
+    /// Outcome as in alert_actor_actions_total, or queued while the Kubernetes API is away
+    pub result: String,
+    pub error: Option<String>,
+}
+
+/// Lines of a digest beyond which chat messages only give a count
+const DIGEST_MAX_LINES: usize = 50;
+
+/// Headline and one line per action of a cycle's digest
+fn digest_text(
+    correlation_id: &str,
+    dry_run: bool,
+    entries: &[DigestEntry],
+) -> (String, Vec<String>) {
+    let succeeded = entries.iter().filter(|e| e.result == "success").count();
+    let failed = entries
+        .iter()
+        .filter(|e| !["success", "skipped", "queued"].contains(&e.result.as_str()))
+        .count();
+    let headline = format!(
+        "alert-actor cycle {}{}: {} action(s), {} succeeded, {} failed",
+        correlation_id,
+        if dry_run { " (dry-run)" } else { "" },
+        entries.len(),
+        succeeded,
+        failed
+    );
+    let lines = entries
+        .iter()
+        .map(|entry| {
+            let mut line = format!(
+                "{} on {} for {} ({}): {}",
+                entry.action, entry.target, entry.alertname, entry.fingerprint, entry.result
+            );
+            if let Some(rule) = &entry.rule {
+                line.push_str(&format!(" [rule {}]", rule));
+            }
+            if let Some(err) = &entry.error {
+                line.push_str(&format!(" - {}", err));
+            }
+            line
+        })
+        .collect();
+    (headline, lines)
+}
+
+/// Send one summary of the actions a poll cycle took to each of --digest-channels. Failures are
+/// logged, like those of per-action notifications.
+pub async fn send_digest(
+    args: &Args,
+    correlation_id: &str,
+    dry_run: bool,
+    entries: &[DigestEntry],
+) {
+    if entries.is_empty() {
+        return;
+    }
+    let (headline, lines) = digest_text(correlation_id, dry_run, entries);
+    for channel in &args.digest_channels {
+        let sent: Result<(), Box<dyn Error>> = match channel.as_str() {
+            "log" => {
+                info!("{}", headline);
+                for line in &lines {
+                    info!("  {}", line);
+                }
+                Ok(())
+            }
+            "webhook" => match &args.digest_webhook_url {
+                Some(url) => notification_client()
+                    .post(url)
+                    .json(&json!({
+                        "correlationId": correlation_id,
+                        "timestamp": Utc::now().to_rfc3339(),
+                        "dryRun": dry_run,
+                        "summary": headline,
+                        "actions": entries,
+                    }))
+                    .send()
+                    .await
+                    .and_then(|response| response.error_for_status())
+                    .map(|_| ())
+                    .map_err(Into::into),
+                None => Err("--digest-webhook-url is not set".into()),
+            },
+            "slack" => match &args.slack_webhook_url {
+                Some(url) => {
+                    let mut text = format!("*{}*", headline);
+                    for line in lines.iter().take(DIGEST_MAX_LINES) {
+                        text.push_str(&format!("\n• {}", line));
+                    }
+                    if lines.len() > DIGEST_MAX_LINES {
+                        text.push_str(&format!("\n…and {} more", lines.len() - DIGEST_MAX_LINES));
+                    }
+                    notification_client()
+                        .post(url)
+                        .json(&json!({ "text": text }))
+                        .send()
+                        .await
+                        .and_then(|response| response.error_for_status())
+                        .map(|_| ())
+                        .map_err(Into::into)
+                }
+                None => Err("--slack-webhook-url is not set".into()),
+            },
+            other => Err(format!("unknown digest channel '{}'", other).into()),
+        };
+        if let Err(err) = sent {
+            error!(
+                "Failed to send the digest of cycle {} to {}: {}",
+                correlation_id, channel, err
+            );
+        }
+    }
+}
+
+/// Send a trigger (with alert details) or resolve event to the PagerDuty Events API v2
+pub async fn send_pagerduty_event(
+    url: &str,
+    routing_key: &str,
+    fingerprint: &str,
+    alert: Option<&Alert>,
+) -> Result<(), Box<dyn Error>> {
+    let event = match alert {
+        Some(alert) => {
+            // PagerDuty only accepts these four severities
+            let severity = alert
+                .labels
+                .severity
+                .as_deref()
+                .filter(|s| ["critical", "error", "warning", "info"].contains(s))
+                .unwrap_or("error");
+            let mut summary = alert.labels.alertname.clone();
+            if let (Some(pod), Some(namespace)) = (&alert.labels.pod, &alert.labels.namespace) {
+                summary = format!("{} on pod {}/{}", summary, namespace, pod);
+            }
+            let links: Vec<Value> = alert
+                .annotations
+                .get("runbook_url")
+                .map(|url| json!({ "href": url, "text": "Runbook" }))
+                .into_iter()
+                .collect();
+            json!({
+                "routing_key": routing_key,
+                "event_action": "trigger",
+                "dedup_key": fingerprint,
+                "payload": {
+                    "summary": summary,
+                    "source": "alert-actor",
+                    "severity": severity,
+                    "custom_details": alert.labels,
+                },
+                "links": links,
+            })
+        }
+        None => json!({
+            "routing_key": routing_key,
+            "event_action": "resolve",
+            "dedup_key": fingerprint,
+        }),
+    };
+
+    notification_client()
+        .post(url)
+        .json(&event)
+        .send()
+        .await?
+        .error_for_status()?;
+    Ok(())
+}
+
+/// Create an OpsGenie alert for the alert, or close it when `alert` is None. The fingerprint is the
+/// OpsGenie alias, so a repeated create is deduplicated and a close finds the right alert.
+pub async fn send_opsgenie_alert(
+    args: &Args,
+    fingerprint: &str,
+    alert: Option<&Alert>,
+) -> Result<(), Box<dyn Error>> {
+    let api_key = args
+        .opsgenie_api_key
+        .as_deref()
+        .ok_or("--opsgenie-api-key is not set")?;
+    let base = args.opsgenie_url.trim_end_matches('/');
+    let request = match alert {
+        Some(alert) => {
+            // OpsGenie rejects messages over 130 characters and unknown priorities
+            let message: String = render_template(&args.opsgenie_message_template, alert)
+                .chars()
+                .take(130)
+                .collect();
+            let priority = render_template(&args.opsgenie_priority, alert);
+            let priority = match priority.trim() {
+                p @ ("P1" | "P2" | "P3" | "P4" | "P5") => p.to_string(),
+                _ => "P3".to_string(),
+            };
+            let tags: Vec<String> = args
+                .opsgenie_tags
+                .iter()
+                .map(|tag| render_template(tag, alert).trim().to_string())
+                .filter(|tag| !tag.is_empty())
+                .collect();
+            let description = alert
+                .annotations
+                .get("description")
+                .or(alert.annotations.get("summary"));
+            notification_client()
+                .post(format!("{}/v2/alerts", base))
+                .json(&json!({
+                    "message": message,
+                    "alias": fingerprint,
+                    "description": description,
+                    "tags": tags,
+                    "priority": priority,
+                    "source": "alert-actor",
+                    "details": alert.labels.to_map(),
+                }))
+        }
+        None => notification_client()
+            .post(format!("{}/v2/alerts/{}/close", base, fingerprint))
+            .query(&[("identifierType", "alias")])
+            .json(&json!({
+                "source": "alert-actor",
+                "note": "Alert stopped firing",
+            })),
+    };
+    request
+        .header(
+            "Authorization",
+            format!("GenieKey {}", resolve_secret(api_key)?),
+        )
+        .send()
+        .await?
+        .error_for_status()?;
+    Ok(())
+}
+
+/// Send one SMTP command (or just read the greeting) and check the reply code
+pub async fn smtp_command<S: AsyncRead + AsyncWrite + Unpin>(
+    stream: &mut BufReader<S>,
+    command: Option<&str>,
+    expected: &str,
+) -> Result<(), Box<dyn Error>> {
+    if let Some(command) = command {
+        stream.write_all(command.as_bytes()).await?;
+        stream.write_all(b"\r\n").await?;
+        stream.flush().await?;
+    }
+    // Multi-line replies use "250-" on every line but the last, which uses "250 "
+    loop {
+        let mut line = String::new();
+        if stream.read_line(&mut line).await? == 0 {
+            return Err("SMTP connection closed unexpectedly".into());
+        }
+        if !line.starts_with(expected) {
+            return Err(format!("unexpected SMTP reply: {}", line.trim_end()).into());
+        }
+        if line.as_bytes().get(3) != Some(&b'-') {
+            return Ok(());
+        }
+    }
+}
+
+/// Deliver a message over an SMTP connection. After STARTTLS there is no new greeting to read,
+/// so `greeted` skips it.
+pub async fn send_smtp_message<S: AsyncRead + AsyncWrite + Unpin>(
+    stream: S,
+    args: &Args,
+    to: &[String],
+    message: &str,
+    greeted: bool,
+) -> Result<(), Box<dyn Error>> {
+    let mut stream = BufReader::new(stream);
+    if !greeted {
+        smtp_command(&mut stream, None, "220").await?;
+    }
+    smtp_command(&mut stream, Some("EHLO alert-actor"), "250").await?;
+    if let Some(username) = &args.smtp_username {
+        let password = match &args.smtp_password {
+            Some(password) => resolve_secret(password)?,
+            None => String::new(),
+        };
+        let credentials = BASE64.encode(format!("\0{}\0{}", username, password));
+        let command = format!("AUTH PLAIN {}", credentials);
+        smtp_command(&mut stream, Some(&command), "235").await?;
+    }
+    let command = format!("MAIL FROM:<{}>", args.email_from);
+    smtp_command(&mut stream, Some(&command), "250").await?;
+    for recipient in to {
+        let command = format!("RCPT TO:<{}>", recipient);
+        smtp_command(&mut stream, Some(&command), "25").await?;
+    }
+    smtp_command(&mut stream, Some("DATA"), "354").await?;
+    smtp_command(&mut stream, Some(&format!("{}\r\n.", message)), "250").await?;
+    smtp_command(&mut stream, Some("QUIT"), "221").await?;
+    Ok(())
+}
+
+/// Refuse addresses that could end the SMTP command or header they are written into, e.g. an
+/// email_to label smuggling in another RCPT TO or a Bcc header
+pub fn check_email_address(address: &str) -> Result<(), Box<dyn Error>> {
+    if address.is_empty() || address.contains(['\r', '\n', '<', '>']) {
+        return Err(format!("invalid email address {:?}", address).into());
+    }
+    Ok(())
+}
+
+/// Send the rendered email for an alert through the configured SMTP server
+pub async fn send_email(args: &Args, to: &[String], alert: &Alert) -> Result<(), Box<dyn Error>> {
+    let host = args.smtp_host.as_deref().ok_or("no SMTP host configured")?;
+    check_email_address(&args.email_from)?;
+    for recipient in to {
+        check_email_address(recipient)?;
+    }
+    let subject = render_template(&args.email_subject_template, alert);
+    let body = render_template(&args.email_body_template, alert);
+    // Normalize line endings and dot-stuff lines so the body can't end the DATA section early
+    let body: Vec<String> = body
+        .lines()
+        .map(|line| {
+            if line.starts_with('.') {
+                format!(".{}", line)
+            } else {
+                line.to_string()
+            }
+        })
+        .collect();
+    let message = format!(
+        "From: {}\r\nTo: {}\r\nSubject: {}\r\nDate: {}\r\nContent-Type: text/plain; charset=utf-8\r\n\r\n{}",
+        args.email_from,
+        to.join(", "),
+        subject.replace(['\r', '\n'], " "),
+        Utc::now().to_rfc2822(),
+        body.join("\r\n"),
+    );
+
+    // AUTH PLAIN is only base64, never send it over an unencrypted connection
+    if args.smtp_username.is_some() && !args.smtp_tls && !args.smtp_starttls {
+        return Err(
+            "refusing to send SMTP credentials unencrypted, enable --smtp-tls or --smtp-starttls"
+                .into(),
+        );
+    }
+    let stream = TcpStream::connect((host, args.smtp_port)).await?;
+    if args.smtp_tls {
+        let connector = TlsConnector::from(native_tls::TlsConnector::new()?);
+        let stream = connector.connect(host, stream).await?;
+        send_smtp_message(stream, args, to, &message, false).await
+    } else if args.smtp_starttls {
+        let mut plain = BufReader::new(stream);
+        smtp_command(&mut plain, None, "220").await?;
+        smtp_command(&mut plain, Some("EHLO alert-actor"), "250").await?;
+        smtp_command(&mut plain, Some("STARTTLS"), "220").await?;
+        let connector = TlsConnector::from(native_tls::TlsConnector::new()?);
+        let stream = connector.connect(host, plain.into_inner()).await?;
+        send_smtp_message(stream, args, to, &message, true).await
+    } else {
+        send_smtp_message(stream, args, to, &message, false).await
+    }
+}
+
+/// Wrap an alert in the notification envelope Alertmanager sends to webhook receivers (version 4),
+/// so receivers built for Alertmanager accept it unmodified
+pub fn alertmanager_envelope(args: &Args, alert: &Alert) -> Value {
+    let labels = alert.labels.to_map();
+    let group_labels = json!({ "alertname": alert.labels.alertname });
+    json!({
+        "version": "4",
+        "groupKey": format!("{{}}:{{alertname=\"{}\"}}", alert.labels.alertname),
+        "truncatedAlerts": 0,
+        "status": "firing",
+        "receiver": "alert-actor",
+        "groupLabels": group_labels,
+        "commonLabels": labels,
+        "commonAnnotations": alert.annotations,
+        "externalURL": args.alertmanager_url.first(),
+        "alerts": [{
+            "status": "firing",
+            "labels": labels,
+            "annotations": alert.annotations,
+            "startsAt": alert.starts_at,
+            "endsAt": alert.ends_at,
+            "generatorURL": alert.generator_url,
+            "fingerprint": alert.fingerprint,
+        }],
+    })
+}
+
+/// Flatten a JSON value into `a.b.0=value` pairs for form encoding
+pub fn flatten_value(prefix: &str, value: &Value, fields: &mut Vec<(String, String)>) {
+    let key = |name: &str| {
+        if prefix.is_empty() {
+            name.to_string()
+        } else {
+            format!("{}.{}", prefix, name)
+        }
+    };
+    match value {
+        Value::Object(map) => map
+            .iter()
+            .for_each(|(name, value)| flatten_value(&key(name), value, fields)),
+        Value::Array(items) => items
+            .iter()
+            .enumerate()
+            .for_each(|(i, value)| flatten_value(&key(&i.to_string()), value, fields)),
+        Value::Null => {}
+        Value::String(string) => fields.push((prefix.to_string(), string.clone())),
+        other => fields.push((prefix.to_string(), other.to_string())),
+    }
+}
+
+pub fn protobuf_varint(buf: &mut Vec<u8>, mut value: u64) {
+    while value >= 0x80 {
+        buf.push(value as u8 | 0x80);
+        value >>= 7;
+    }
+    buf.push(value as u8);
+}
+
+/// Append a length-delimited field (wire type 2)
+pub fn protobuf_bytes(buf: &mut Vec<u8>, field: u64, bytes: &[u8]) {
+    protobuf_varint(buf, field << 3 | 2);
+    protobuf_varint(buf, bytes.len() as u64);
+    buf.extend_from_slice(bytes);
+}
+
+/// Encode an alert as protobuf with this schema:
+///
+/// ```proto
+/// message Alert {
+///   string fingerprint = 1;
+///   string state = 2;
+///   map<string, string> labels = 3;
+///   map<string, string> annotations = 4;
+///   string starts_at = 5;
+///   string ends_at = 6;
+///   string generator_url = 7;
+/// }
+/// ```
+pub fn encode_protobuf_alert(alert: &Alert) -> Vec<u8> {
+    let mut buf = Vec::new();
+    protobuf_bytes(&mut buf, 1, alert.fingerprint.as_bytes());
+    protobuf_bytes(&mut buf, 2, alert.status.state.as_bytes());
+    // Map fields are repeated entry messages with the key as field 1 and the value as field 2
+    let labels = alert.labels.to_map();
+    let annotations: BTreeMap<&String, &String> = alert.annotations.iter().collect();
+    let entries = labels
+        .iter()
+        .map(|entry| (3, entry))
+        .chain(annotations.into_iter().map(|entry| (4, entry)));
+    for (field, (key, value)) in entries {
+        let mut entry = Vec::new();
+        protobuf_bytes(&mut entry, 1, key.as_bytes());
+        protobuf_bytes(&mut entry, 2, value.as_bytes());
+        protobuf_bytes(&mut buf, field, &entry);
+    }
+    for (field, value) in [
+        (5, &alert.starts_at),
+        (6, &alert.ends_at),
+        (7, &alert.generator_url),
+    ] {
+        if let Some(value) = value {
+            protobuf_bytes(&mut buf, field, value.as_bytes());
+        }
+    }
+    buf
+}
+
+/// Encode a webhook payload, returning the body and its Content-Type
+pub fn encode_webhook_body(
+    encoding: &str,
+    payload: &Value,
+    alert: &Alert,
+) -> Result<(Vec<u8>, &'static str), Box<dyn Error>> {
+    match encoding {
+        "json" => Ok((serde_json::to_vec(payload)?, "application/json")),
+        "form" => {
+            let mut fields = Vec::new();
+            flatten_value("", payload, &mut fields);
+            Ok((
+                serde_urlencoded::to_string(fields)?.into_bytes(),
+                "application/x-www-form-urlencoded",
+            ))
+        }
+        "ndjson" => {
+            // An Alertmanager envelope becomes one line per alert
+            let lines = match payload.get("alerts") {
+                Some(Value::Array(alerts)) => alerts.iter().collect(),
+                _ => vec![payload],
+            };
+            let mut body = Vec::new();
+            for line in lines {
+                serde_json::to_writer(&mut body, line)?;
+                body.push(b'\n');
+            }
+            Ok((body, "application/x-ndjson"))
+        }
+        "protobuf" => Ok((encode_protobuf_alert(alert), "application/x-protobuf")),
+        other => Err(format!("unknown webhook encoding '{}'", other).into()),
+    }
+}
+
+/// Headers from an alert's or rule's webhook_headers label, one 'Name: value' per line. Secret
+/// references are only resolved when listed in --webhook-header-secrets.
+pub fn label_webhook_headers(args: &Args, headers: &str) -> Result<HeaderMap, Box<dyn Error>> {
+    let headers: Vec<String> = headers
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty())
+        .map(str::to_string)
+        .collect();
+    for header in &headers {
+        let value = header.split_once(':').map_or("", |(_, value)| value.trim());
+        if (value.starts_with("env:") || value.starts_with("file:"))
+            && !args
+                .webhook_header_secrets
+                .iter()
+                .any(|secret| secret == value)
+        {
+            return Err(format!(
+                "webhook header secret '{}' is not in --webhook-header-secrets",
+                value
+            )
+            .into());
+        }
+    }
+    webhook_headers(&headers)
+}
+
+/// Send the alert (or its rendered template) to a webhook, with configured headers and signature
+pub async fn send_webhook(
+    args: &Args,
+    url: &str,
+    alert: &Alert,
+    webhook_template: Option<&str>,
+) -> Result<(), Box<dyn Error>> {
+    let mut headers = webhook_headers(&args.webhook_headers)?;
+    // The alert's own headers win over the configured ones, but not over the signature
+    if let Some(label) = &alert.labels.webhook_headers {
+        headers.extend(label_webhook_headers(args, label)?);
+    }
+    let format = alert
+        .labels
+        .webhook_format
+        .as_deref()
+        .unwrap_or(&args.webhook_format);
+    let (body, content_type) = match (webhook_template, format) {
+        (Some(template), _) => (
+            render_template(template, alert).into_bytes(),
+            args.webhook_content_type.as_str(),
+        ),
+        (None, format) => {
+            let payload = match format {
+                "alertmanager" => alertmanager_envelope(args, alert),
+                _ => serde_json::to_value(alert)?,
+            };
+            let encoding = alert
+                .labels
+                .webhook_encoding
+                .as_deref()
+                .unwrap_or(&args.webhook_encoding);
+            encode_webhook_body(encoding, &payload, alert)?
+        }
+    };
+    // A Content-Type from --webhook-headers or the webhook_headers label takes precedence
+    headers
+        .entry(CONTENT_TYPE)
+        .or_insert(HeaderValue::from_str(content_type)?);
+    if let Some(secret) = &args.webhook_secret {
+        let key = resolve_secret(secret)?;
+        let signature = format!("sha256={}", hmac_sha256_hex(key.as_bytes(), &body));
+        headers.insert(
+            HeaderName::from_bytes(args.webhook_signature_header.as_bytes())?,
+            HeaderValue::from_str(&signature)?,
+        );
+    }
+
+    let client = alert_client(args, alert)?;
+    let mut backoff = Duration::from_millis(args.webhook_backoff_ms);
+    let mut attempt = 1;
+    loop {
+        let result = client
+            .post(url)
+            .headers(headers.clone())
+            .body(body.clone())
+            .send()
+            .await
+            .and_then(|resp| resp.error_for_status());
+        let err = match result {
+            Ok(_) => return Ok(()),
+            Err(err) => err,
+        };
+        // Client errors other than 429 won't succeed on retry
+        let retryable = err
+            .status()
+            .is_none_or(|status| status.is_server_error() || status.as_u16() == 429);
+        if !retryable {
+            return Err(err.into());
+        }
+        if attempt >= args.webhook_max_attempts {
+            METRICS.inc("alert_actor_webhook_retries_exhausted_total", &[]);
+            return Err(format!("giving up after {} attempts: {}", attempt, err).into());
+        }
+        warn!(
+            "Webhook attempt {} to {} failed, retrying in {:?}: {}",
+            attempt, url, backoff, err
+        );
+        METRICS.inc("alert_actor_webhook_retries_total", &[]);
+        tokio::time::sleep(backoff).await;
+        backoff *= 2;
+        attempt += 1;
+    }
+}
+
+pub fn pagerduty_routing_key<'a>(alert: &'a Alert, args: &'a Args) -> Option<&'a str> {
+    alert
+        .labels
+        .pagerduty_routing_key
+        .as_deref()
+        .or(args.pagerduty_routing_key.as_deref())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn alert() -> Alert {
+        serde_json::from_value(json!({
+            "fingerprint": "abc123",
+            "status": { "state": "active" },
+            "labels": { "alertname": "KubePodCrashLooping", "namespace": "payments" },
+            "annotations": { "summary": "Pod \"api\" is crash looping" },
+        }))
+        .unwrap()
+    }
+
+    #[test]
+    fn renders_fields() {
+        assert_eq!(
+            render_template("{{ labels.alertname }} in {{labels.namespace}}", &alert()),
+            "KubePodCrashLooping in payments"
+        );
+        assert_eq!(
+            render_template("[{{ labels.pod }}] {{ labels.nope.deeper }}", &alert()),
+            "[] "
+        );
+        assert_eq!(
+            render_template(r"{{ fingerprint }}\n{{ status.state }}", &alert()),
+            "abc123\nactive"
+        );
+        assert_eq!(
+            render_template("unclosed {{ labels", &alert()),
+            "unclosed {{ labels"
+        );
+    }
+
+    #[test]
+    fn renders_json_encoded_values() {
+        assert_eq!(
+            render_template(r#"{"summary": {{ annotations.summary | json }}}"#, &alert()),
+            r#"{"summary": "Pod \"api\" is crash looping"}"#
+        );
+        assert_eq!(render_template("{{ labels.pod | json }}", &alert()), "null");
+    }
+
+    #[test]
+    fn unescapes_only_template_text() {
+        let alert: Alert = serde_json::from_value(json!({
+            "fingerprint": "abc123",
+            "status": { "state": "active" },
+            "labels": { "alertname": "A" },
+            "annotations": { "description": "line one\nline two", "path": "C:\\new" },
+        }))
+        .unwrap();
+        let body = render_template(
+            r#"{"description": {{ annotations.description | json }}}\n"#,
+            &alert,
+        );
+        assert_eq!(body, "{\"description\": \"line one\\nline two\"}\n");
+        let parsed: Value = serde_json::from_str(&body).unwrap();
+        assert_eq!(parsed["description"], "line one\nline two");
+        assert_eq!(render_template("{{ annotations.path }}", &alert), "C:\\new");
+    }
+
+    #[test]
+    fn hmac_matches_rfc_4231() {
+        assert_eq!(
+            hmac_sha256_hex(b"Jefe", b"what do ya want for nothing?"),
+            "5bdcc146bf60754e6a042426089575c75a003f089d2739839dec58b964ec3843"
+        );
+        // Keys longer than a block are hashed first
+        assert_eq!(
+            hmac_sha256_hex(
+                &[0xaa; 131],
+                b"Test Using Larger Than Block-Size Key - Hash Key First"
+            ),
+            "60e431591ee0b67f0d8a26aacbf5b77f8e0bc6213728c5140546040f0ee37f54"
+        );
+    }
+}
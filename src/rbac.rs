@@ -0,0 +1,304 @@
+//! RBAC preflight checks through SelfSubjectAccessReviews, so missing permissions show up at
+//! startup or as a clear skip instead of an opaque 403 mid-incident
+
+use k8s_openapi::{
+    api::authorization::v1::{
+        ResourceAttributes, SelfSubjectAccessReview, SelfSubjectAccessReviewSpec,
+    },
+    chrono::Utc,
+};
+use kube::{
+    api::{Patch, PatchParams, PostParams},
+    Api, Client, ResourceExt,
+};
+use log::{info, warn};
+use serde_json::json;
+use std::{error::Error, sync::Arc};
+
+use crate::{
+    actions::{is_kube_action, pod_guards_enabled, registry, Skipped},
+    alertmanager::Alert,
+    clusters::Clusters,
+    config::Args,
+    rules::{AlertRemediationRule, RuleCondition},
+};
+
+/// API group, resource and verb an action needs
+pub type Permission = (&'static str, &'static str, &'static str);
+
+/// Permissions an action needs whatever its options
+pub fn action_permissions(action: &str) -> Vec<Permission> {
+    match action {
+        "delete_pod" => vec![("", "pods", "delete")],
+        "delete_pods_by_selector" | "restart_daemonset_pod" | "cleanup_pods" => {
+            vec![("", "pods", "list"), ("", "pods", "delete")]
+        }
+        "taint_node" => vec![("", "nodes", "get"), ("", "nodes", "patch")],
+        "suggest_rightsizing" => vec![("metrics.k8s.io", "pods", "get"), ("", "pods", "get")],
+        "run_job" => vec![("batch", "jobs", "create")],
+        "force_finalize" => vec![
+            ("", "pods", "get"),
+            ("", "pods", "patch"),
+            ("", "pods", "delete"),
+        ],
+        "hpa_adjust" => vec![
+            ("autoscaling", "horizontalpodautoscalers", "get"),
+            ("autoscaling", "horizontalpodautoscalers", "patch"),
+        ],
+        "suspend_cronjob" | "resume_cronjob" => vec![("batch", "cronjobs", "patch")],
+        "rollback" => vec![
+            ("apps", "deployments", "get"),
+            ("apps", "deployments", "update"),
+            ("apps", "replicasets", "list"),
+        ],
+        _ => Vec::new(),
+    }
+}
+
+/// Permissions an action needs. With the alert's labels, also those of the options it uses.
+pub fn required_permissions(action: &str, args: &Args, alert: Option<&Alert>) -> Vec<Permission> {
+    let mut permissions = action_permissions(action);
+    if let Some(alert) = alert {
+        if action == "delete_pod"
+            && (pod_guards_enabled(args, &alert.labels) || alert.labels.owner_action.is_some())
+        {
+            permissions.push(("", "pods", "get"));
+        }
+        if action == "rollback" && alert.labels.deployment.is_none() {
+            permissions.push(("", "pods", "get"));
+            permissions.push(("apps", "replicasets", "get"));
+        }
+    }
+    permissions
+}
+
+/// Whether the client's identity may perform the verb, in the namespace or cluster-wide
+pub async fn allowed(
+    client: &Client,
+    (group, resource, verb): Permission,
+    namespace: Option<&str>,
+) -> Result<bool, Box<dyn Error>> {
+    let review = SelfSubjectAccessReview {
+        spec: SelfSubjectAccessReviewSpec {
+            resource_attributes: Some(ResourceAttributes {
+                group: Some(group.to_string()),
+                resource: Some(resource.to_string()),
+                verb: Some(verb.to_string()),
+                namespace: namespace.map(str::to_string),
+                ..Default::default()
+            }),
+            ..Default::default()
+        },
+        ..Default::default()
+    };
+    let reviews: Api<SelfSubjectAccessReview> = Api::all(client.clone());
+    let review = reviews.create(&PostParams::default(), &review).await?;
+    Ok(review.status.is_some_and(|status| status.allowed))
+}
+
+fn describe((group, resource, verb): Permission) -> String {
+    match group {
+        "" => format!("{} {}", verb, resource),
+        group => format!("{} {}.{}", verb, resource, group),
+    }
+}
+
+/// Check the permissions of every Kubernetes action cluster-wide at startup. --rbac-preflight
+/// 'warn' logs what is missing, 'fail' refuses to start, 'off' skips the check.
+pub async fn preflight(args: &Args, client: &Client) -> Result<(), Box<dyn Error>> {
+    let fail = match args.rbac_preflight.as_str() {
+        "off" => return Ok(()),
+        "warn" => false,
+        "fail" => true,
+        other => return Err(format!("unknown --rbac-preflight mode '{}'", other).into()),
+    };
+    let actions: Vec<&str> = if args.rbac_preflight_actions.is_empty() {
+        // force_finalize only needs its permissions once it is opted into
+        registry()
+            .names()
+            .filter(|action| is_kube_action(action))
+            .filter(|action| *action != "force_finalize" || args.allow_force_finalize)
+            .collect()
+    } else {
+        args.rbac_preflight_actions
+            .iter()
+            .map(String::as_str)
+            .collect()
+    };
+    let mut missing = Vec::new();
+    for action in actions {
+        for permission in required_permissions(action, args, None) {
+            match allowed(client, permission, None).await {
+                Ok(true) => {}
+                Ok(false) => missing.push(format!("{} needs {}", action, describe(permission))),
+                Err(err) if !fail => {
+                    warn!("RBAC preflight could not run: {}", err);
+                    return Ok(());
+                }
+                Err(err) => return Err(err),
+            }
+        }
+    }
+    if missing.is_empty() {
+        info!("RBAC preflight passed");
+        return Ok(());
+    }
+    let message = format!(
+        "service account lacks cluster-wide permissions: {}",
+        missing.join(", ")
+    );
+    if fail {
+        return Err(message.into());
+    }
+    warn!("RBAC preflight: {}", message);
+    Ok(())
+}
+
+/// Check the permissions an action needs in its target namespace right before it runs, and skip
+/// it with the missing permission as the reason
+pub async fn check_action(
+    args: &Args,
+    client: &Client,
+    action: &str,
+    alert: &Alert,
+) -> Result<(), Box<dyn Error>> {
+    for permission @ (_, resource, _) in required_permissions(action, args, Some(alert)) {
+        let namespace = match resource {
+            "nodes" => None,
+            _ => alert.labels.namespace.as_deref(),
+        };
+        if !allowed(client, permission, namespace).await? {
+            return Err(Skipped(format!(
+                "not allowed to {}{}, check RBAC",
+                describe(permission),
+                namespace
+                    .map(|namespace| format!(" in namespace {}", namespace))
+                    .unwrap_or_default()
+            ))
+            .into());
+        }
+    }
+    Ok(())
+}
+
+/// Status, reason and message of a rule's PermissionsGranted condition. Team rules are checked in
+/// their namespace, global ones cluster-wide, both as the identity the rule's action runs as.
+async fn rule_permissions(
+    args: &Args,
+    clusters: &Clusters,
+    rule: &AlertRemediationRule,
+    controller_namespace: &str,
+) -> (&'static str, &'static str, String) {
+    let action = rule.spec.action.as_str();
+    if !is_kube_action(action) {
+        return (
+            "True",
+            "NotNeeded",
+            format!("{} needs no Kubernetes permissions", action),
+        );
+    }
+    let impersonation = match rule.impersonation(controller_namespace) {
+        Ok(impersonation) => impersonation,
+        Err(err) => return ("False", "InvalidImpersonation", err.to_string()),
+    };
+    let remote = Clusters::remote_cluster(args, rule.spec.cluster.as_deref());
+    let client = match clusters.client_in(remote, impersonation.as_ref()) {
+        Ok(client) => client,
+        Err(err) => return ("Unknown", "CheckFailed", err.to_string()),
+    };
+    let rule_namespace = rule.namespace().unwrap_or_default();
+    let mut missing = Vec::new();
+    for permission @ (_, resource, _) in required_permissions(action, args, None) {
+        let namespace = match resource {
+            "nodes" => None,
+            _ => Some(rule_namespace.as_str()).filter(|ns| *ns != controller_namespace),
+        };
+        match allowed(&client, permission, namespace).await {
+            Ok(true) => {}
+            Ok(false) => missing.push(format!(
+                "{}{}",
+                describe(permission),
+                namespace
+                    .map(|namespace| format!(" in namespace {}", namespace))
+                    .unwrap_or_else(|| " cluster-wide".to_string())
+            )),
+            Err(err) => return ("Unknown", "CheckFailed", err.to_string()),
+        }
+    }
+    if missing.is_empty() {
+        ("True", "Allowed", format!("{} is allowed", action))
+    } else {
+        (
+            "False",
+            "MissingPermissions",
+            format!("{} needs {}", action, missing.join(", ")),
+        )
+    }
+}
+
+/// Check every rule's permissions and record them in its PermissionsGranted status condition, so
+/// a misconfigured rule shows up in `kubectl get` before it fails mid-incident. Conditions are
+/// only written when they change.
+pub async fn update_rule_status(
+    args: &Args,
+    client: &Client,
+    clusters: &Clusters,
+    rules: &[Arc<AlertRemediationRule>],
+    controller_namespace: &str,
+) -> Result<(), Box<dyn Error>> {
+    for rule in rules {
+        let (status, reason, message) =
+            rule_permissions(args, clusters, rule, controller_namespace).await;
+        let current = rule.status.as_ref().and_then(|status| {
+            status
+                .conditions
+                .iter()
+                .find(|condition| condition.type_ == "PermissionsGranted")
+        });
+        if current.is_some_and(|current| {
+            current.status == status
+                && current.reason == reason
+                && current.message == message
+                && current.observed_generation == rule.metadata.generation
+        }) {
+            continue;
+        }
+        let condition = RuleCondition {
+            type_: "PermissionsGranted".to_string(),
+            status: status.to_string(),
+            reason: reason.to_string(),
+            message,
+            // The transition time only moves when the status itself flips
+            last_transition_time: current
+                .filter(|current| current.status == status)
+                .map(|current| current.last_transition_time.clone())
+                .unwrap_or_else(|| Utc::now().to_rfc3339()),
+            observed_generation: rule.metadata.generation,
+        };
+        if status == "False" {
+            warn!(
+                "Rule {}/{}: {}",
+                rule.namespace().unwrap_or_default(),
+                rule.name_any(),
+                condition.message
+            );
+        }
+        let mut conditions: Vec<RuleCondition> = rule
+            .status
+            .iter()
+            .flat_map(|status| status.conditions.iter())
+            .filter(|condition| condition.type_ != "PermissionsGranted")
+            .cloned()
+            .collect();
+        conditions.push(condition);
+        let api: Api<AlertRemediationRule> =
+            Api::namespaced(client.clone(), &rule.namespace().unwrap_or_default());
+        api.patch_status(
+            &rule.name_any(),
+            &PatchParams::default(),
+            &Patch::Merge(json!({ "status": { "conditions": conditions } })),
+        )
+        .await?;
+    }
+    Ok(())
+}
@@ -0,0 +1,717 @@
+//! Matching alerts to remediation rules, approvals and targets
+
+use anyhow::Result;
+use k8s_openapi::chrono::{DateTime, Utc};
+use kube::{
+    api::{DeleteParams, ListParams, PostParams},
+    Api, Client, CustomResource, ResourceExt,
+};
+use log::{debug, info, warn};
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use std::{
+    collections::{BTreeMap, HashSet},
+    error::Error,
+    sync::Arc,
+};
+
+use crate::{
+    actions::{is_kube_action, is_namespaced_action},
+    alertmanager::{Alert, Labels},
+    clusters::Impersonation,
+    config::Args,
+    expr::Expr,
+    schedule::in_window,
+};
+
+/// Remediation rule managed as a Kubernetes resource. Rules in the controller's own namespace
+/// apply to every alert; rules anywhere else only to alerts labelled with their namespace, so
+/// teams can own the remediation of their workloads.
+#[derive(CustomResource, Clone, Debug, Deserialize, Serialize, JsonSchema)]
+#[kube(
+    group = "alert-actor.jrcichra.dev",
+    version = "v1alpha1",
+    kind = "AlertRemediationRule",
+    namespaced,
+    status = "AlertRemediationRuleStatus"
+)]
+#[serde(rename_all = "camelCase")]
+pub struct AlertRemediationRuleSpec {
+    /// Labels the alert must carry with exactly these values
+    #[serde(default)]
+    pub matchers: BTreeMap<String, String>,
+    /// Expression over the whole alert that must also hold, e.g.
+    /// `labels.severity == "critical" && labels.namespace.startsWith("prod-")`
+    pub expression: Option<String>,
+    /// Where parameters come from when the alert names them differently, as JSONPath into the
+    /// alert, e.g. `pod: $.labels.kubernetes_pod_name`. Also decides the namespace team rules see.
+    #[serde(default)]
+    pub target_paths: BTreeMap<String, String>,
+    /// Action to take, as with the 'action' label
+    pub action: String,
+    /// Labels set on matching alerts before acting, e.g. webhook_url or email_to
+    #[serde(default)]
+    pub labels: BTreeMap<String, String>,
+    /// Only match alerts from this cluster, by their cluster label
+    pub cluster: Option<String>,
+    /// Overrides --cooldown-secs for matching alerts
+    pub cooldown_secs: Option<u64>,
+    /// How often matching alerts are evaluated instead of every --interval, e.g. 15 for
+    /// crash-loop remediation or 600 for cleanup rules. Polling speeds up for the shortest one.
+    pub interval_secs: Option<u64>,
+    /// Consecutive polls matching alerts must be seen firing in before acting, overriding
+    /// --confirmations, so flapping alerts can resolve themselves
+    pub confirmations: Option<u64>,
+    /// Only validate the rule's Kubernetes changes on the API server (dryRun=All), to try out a
+    /// new rule against real admission webhooks and RBAC
+    pub server_dry_run: Option<bool>,
+    /// Propagation policy of the rule's deletions, overriding its propagation_policy label:
+    /// Background, Foreground (which also waits until the object and its dependents are gone)
+    /// or Orphan
+    pub propagation_policy: Option<String>,
+    /// User the rule's actions impersonate, e.g. system:serviceaccount:payments:remediator, so
+    /// they are bounded by its RBAC. Rules outside the controller's namespace may only
+    /// impersonate service accounts of their own namespace.
+    pub impersonate: Option<String>,
+    /// Groups impersonated along with the user; only for rules in the controller's namespace
+    #[serde(default)]
+    pub impersonate_groups: Vec<String>,
+}
+
+/// What the controller observed about a rule, kept up to date by whichever replica leads shard 0
+#[derive(Clone, Debug, Default, Deserialize, Serialize, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct AlertRemediationRuleStatus {
+    #[serde(default)]
+    pub conditions: Vec<RuleCondition>,
+}
+
+/// A status condition in the usual Kubernetes shape. `PermissionsGranted` reports whether the
+/// rule's action can run with the RBAC of the identity it acts as.
+#[derive(Clone, Debug, Deserialize, Serialize, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct RuleCondition {
+    #[serde(rename = "type")]
+    pub type_: String,
+    /// True, False or Unknown
+    pub status: String,
+    pub reason: String,
+    pub message: String,
+    pub last_transition_time: String,
+    pub observed_generation: Option<i64>,
+}
+
+impl AlertRemediationRule {
+    /// Whether the rule is in scope for the alert and all of its matchers hold
+    pub fn matches(&self, alert: &Alert, controller_namespace: &str) -> bool {
+        let rule_namespace = self.namespace().unwrap_or_default();
+        if rule_namespace != controller_namespace {
+            let alert_namespace = match self.spec.target_paths.get("namespace") {
+                Some(path) => alert.resolve_path(path).unwrap_or_else(|e| {
+                    warn!(
+                        "Rule {} has an invalid namespace path: {}",
+                        self.name_any(),
+                        e
+                    );
+                    None
+                }),
+                None => None,
+            }
+            .or_else(|| alert.labels.namespace.clone());
+            if alert_namespace.as_deref() != Some(rule_namespace.as_str()) {
+                return false;
+            }
+        }
+        if self.spec.cluster.is_some() && alert.labels.cluster != self.spec.cluster {
+            return false;
+        }
+        let labels = alert.labels.to_map();
+        if let Some((name, value)) = self
+            .spec
+            .matchers
+            .iter()
+            .find(|(name, value)| labels.get(*name) != Some(*value))
+        {
+            debug!(
+                "Rule {} does not match alert {}: {} is not {}",
+                self.name_any(),
+                alert.fingerprint,
+                name,
+                value
+            );
+            return false;
+        }
+        let Some(expression) = &self.spec.expression else {
+            return true;
+        };
+        match Expr::parse(expression).map_err(|e| e.to_string()) {
+            Ok(expr) => expr.matches(&alert.to_value()).unwrap_or_else(|e| {
+                debug!(
+                    "Rule {} does not match alert {}: {}",
+                    self.name_any(),
+                    alert.fingerprint,
+                    e
+                );
+                false
+            }),
+            Err(e) => {
+                warn!("Rule {} has an invalid expression: {}", self.name_any(), e);
+                false
+            }
+        }
+    }
+
+    /// Identity the rule's actions impersonate. Team rules may not escape their namespace through
+    /// it, so they are limited to its service accounts and can't add groups.
+    pub fn impersonation(
+        &self,
+        controller_namespace: &str,
+    ) -> Result<Option<Impersonation>, Box<dyn Error>> {
+        let Some(user) = &self.spec.impersonate else {
+            if !self.spec.impersonate_groups.is_empty() {
+                return Err(format!(
+                    "rule {} sets impersonateGroups without impersonate",
+                    self.name_any()
+                )
+                .into());
+            }
+            return Ok(None);
+        };
+        let rule_namespace = self.namespace().unwrap_or_default();
+        if rule_namespace != controller_namespace {
+            let own_account = user
+                .strip_prefix(&format!("system:serviceaccount:{}:", rule_namespace))
+                .is_some_and(|name| !name.is_empty() && !name.contains(':'));
+            if !own_account || !self.spec.impersonate_groups.is_empty() {
+                return Err(format!(
+                    "rule {} may only impersonate service accounts of namespace {}",
+                    self.name_any(),
+                    rule_namespace
+                )
+                .into());
+            }
+        }
+        Ok(Some(Impersonation {
+            user: user.clone(),
+            groups: self.spec.impersonate_groups.clone(),
+        }))
+    }
+
+    /// Apply the rule's target paths, action and labels to a matching alert. Team rules may not
+    /// point the action at another namespace, cluster or node than the alert's, and are limited
+    /// to namespaced actions without commands, Job templates or CA bundles.
+    pub fn apply(
+        &self,
+        alert: &mut Alert,
+        controller_namespace: &str,
+    ) -> Result<(), Box<dyn Error>> {
+        let rule_namespace = self.namespace().unwrap_or_default();
+        let team_rule = rule_namespace != controller_namespace;
+        if team_rule {
+            if !is_namespaced_action(&self.spec.action) {
+                return Err(format!(
+                    "rule {} may not use the cluster-wide action {} outside of namespace {}",
+                    self.name_any(),
+                    self.spec.action,
+                    controller_namespace
+                )
+                .into());
+            }
+            // Commands and Job templates run as the controller, CA bundles are read from its files
+            const SCOPE: [&str; 6] = [
+                "namespace",
+                "cluster",
+                "node",
+                "command",
+                "job_template",
+                "ca_cert",
+            ];
+            if let Some(key) = SCOPE
+                .iter()
+                .find(|key| self.spec.labels.contains_key(**key))
+            {
+                return Err(format!(
+                    "rule {} may not set the {} label outside of namespace {}",
+                    self.name_any(),
+                    key,
+                    controller_namespace
+                )
+                .into());
+            }
+            // The namespace path is what scoped the rule in `matches`, the others must not move it
+            if let Some(key) = ["cluster", "node"]
+                .iter()
+                .find(|key| self.spec.target_paths.contains_key(**key))
+            {
+                return Err(format!(
+                    "rule {} may not set a {} target path outside of namespace {}",
+                    self.name_any(),
+                    key,
+                    controller_namespace
+                )
+                .into());
+            }
+        }
+        alert.apply_target_paths(
+            self.spec
+                .target_paths
+                .iter()
+                .map(|(name, path)| (name.as_str(), path.as_str())),
+        )?;
+        let mut labels = alert.labels.to_map();
+        labels.extend(self.spec.labels.clone());
+        labels.insert("action".to_string(), self.spec.action.clone());
+        if let Some(confirmations) = self.spec.confirmations {
+            labels.insert("confirmations".to_string(), confirmations.to_string());
+        }
+        if let Some(server_dry_run) = self.spec.server_dry_run {
+            labels.insert("server_dry_run".to_string(), server_dry_run.to_string());
+        }
+        if let Some(policy) = &self.spec.propagation_policy {
+            labels.insert("propagation_policy".to_string(), policy.clone());
+        }
+        alert.labels = serde_json::from_value(json!(labels))?;
+        if team_rule && alert.labels.namespace.as_deref() != Some(rule_namespace.as_str()) {
+            return Err(format!(
+                "rule {} may only act in namespace {}",
+                self.name_any(),
+                rule_namespace
+            )
+            .into());
+        }
+        Ok(())
+    }
+}
+
+/// First rule matching the alert. Team rules take precedence over global ones, then rules are
+/// ordered by name so every replica picks the same one.
+pub fn find_rule(
+    rules: &[Arc<AlertRemediationRule>],
+    alert: &Alert,
+    controller_namespace: &str,
+) -> Option<Arc<AlertRemediationRule>> {
+    rules
+        .iter()
+        .filter(|rule| rule.matches(alert, controller_namespace))
+        .min_by_key(|rule| {
+            let namespace = rule.namespace().unwrap_or_default();
+            (
+                namespace == controller_namespace,
+                namespace,
+                rule.name_any(),
+            )
+        })
+        .cloned()
+}
+
+/// An action held back until a human (or another controller) approves it by setting
+/// `spec.approved`. Deleting it rejects the action for now; it is filed again while the alert fires.
+#[derive(CustomResource, Clone, Debug, Deserialize, Serialize, JsonSchema)]
+#[kube(
+    group = "alert-actor.jrcichra.dev",
+    version = "v1alpha1",
+    kind = "PendingRemediation",
+    namespaced
+)]
+#[serde(rename_all = "camelCase")]
+pub struct PendingRemediationSpec {
+    /// Fingerprint of the alert that triggered the action
+    pub fingerprint: String,
+    pub alertname: String,
+    /// Action that runs once approved
+    pub action: String,
+    /// Object the action is taken on, e.g. namespace/pod
+    pub target: String,
+    /// Labels of the alert when the action was requested
+    pub labels: BTreeMap<String, String>,
+    /// Set to true to let the action run
+    #[serde(default)]
+    pub approved: bool,
+}
+
+/// Name of the PendingRemediation for an alert, stable across cycles and replicas
+pub fn pending_remediation_name(alert: &Alert) -> String {
+    let mut name: String = format!("{}-{}", alert.labels.alertname, alert.fingerprint)
+        .to_lowercase()
+        .chars()
+        .map(|c| if c.is_ascii_alphanumeric() { c } else { '-' })
+        .collect();
+    name.truncate(253);
+    name.trim_matches('-').to_string()
+}
+
+/// Whether the action for an alert has been approved. Files a PendingRemediation the first time,
+/// so there is something to approve.
+pub async fn check_approval(
+    client: Client,
+    namespace: &str,
+    alert: &Alert,
+    action: &str,
+    target: &str,
+) -> Result<bool, Box<dyn Error>> {
+    let api: Api<PendingRemediation> = Api::namespaced(client, namespace);
+    let name = pending_remediation_name(alert);
+    if let Some(pending) = api.get_opt(&name).await? {
+        return Ok(pending.spec.approved && pending.spec.action == action);
+    }
+    let mut pending = PendingRemediation::new(
+        &name,
+        PendingRemediationSpec {
+            fingerprint: alert.fingerprint.clone(),
+            alertname: alert.labels.alertname.clone(),
+            action: action.to_string(),
+            target: target.to_string(),
+            labels: alert.labels.to_map(),
+            approved: false,
+        },
+    );
+    pending.metadata.labels = Some(BTreeMap::from([(
+        "app.kubernetes.io/managed-by".to_string(),
+        "alert-actor".to_string(),
+    )]));
+    api.create(&PostParams::default(), &pending).await?;
+    info!(
+        "Requested approval for action {} on {} in PendingRemediation {}/{}",
+        action, target, namespace, name
+    );
+    Ok(false)
+}
+
+/// Delete PendingRemediations whose alert is no longer firing
+pub async fn prune_pending_remediations(
+    client: Client,
+    namespace: &str,
+    active_fingerprints: &HashSet<String>,
+) -> Result<(), Box<dyn Error>> {
+    let api: Api<PendingRemediation> = Api::namespaced(client, namespace);
+    let pending = api
+        .list(&ListParams::default().labels("app.kubernetes.io/managed-by=alert-actor"))
+        .await?;
+    for pending in pending {
+        if !active_fingerprints.contains(&pending.spec.fingerprint) {
+            api.delete(&pending.name_any(), &DeleteParams::default())
+                .await?;
+            info!(
+                "Removed PendingRemediation {}, alert {} resolved",
+                pending.name_any(),
+                pending.spec.fingerprint
+            );
+        }
+    }
+    Ok(())
+}
+
+/// Consecutive polls an alert must be firing in before it is acted on
+pub fn required_confirmations(args: &Args, labels: &Labels) -> Result<u64, String> {
+    match &labels.confirmations {
+        Some(value) => value
+            .parse()
+            .map_err(|_| format!("invalid confirmations '{}'", value)),
+        None => Ok(args.confirmations),
+    }
+}
+
+/// Whether the alert's action may not run at `at`: a quiet window matches, or active windows are
+/// set and none of them matches. Windows are read at --windows-utc-offset.
+pub fn outside_windows(args: &Args, labels: &Labels, at: DateTime<Utc>) -> Result<bool, String> {
+    let at = at.with_timezone(&args.windows_utc_offset);
+    let quiet_windows = args
+        .quiet_windows
+        .iter()
+        .map(String::as_str)
+        .chain(labels.quiet_window.as_deref());
+    let active_windows: Vec<&str> = args
+        .active_windows
+        .iter()
+        .map(String::as_str)
+        .chain(labels.active_window.as_deref())
+        .collect();
+    let quiet = in_window(quiet_windows, at)?;
+    let active = in_window(active_windows.iter().copied(), at)?;
+    Ok(quiet || (!active_windows.is_empty() && !active))
+}
+
+/// Whether the action has to be approved through a PendingRemediation before it runs
+pub fn requires_approval(args: &Args, labels: &Labels, action: &str) -> bool {
+    match labels.require_approval.as_deref() {
+        Some(value) => value == "true",
+        None => args.approval_actions.iter().any(|name| name == action),
+    }
+}
+
+/// Version of the stable hashing scheme. Changing the scheme reshuffles which alerts are sampled
+/// and which replica owns them, so it must only change together with a version bump.
+pub const HASH_VERSION: &str = "v1";
+
+/// Stable 64-bit FNV-1a hash over the selected labels (all labels when none are selected).
+/// Unlike Alertmanager fingerprints it depends only on the labels and this scheme's version,
+/// so it is identical across replicas, restarts and Alertmanager upgrades.
+pub fn stable_hash(labels: &Labels, hash_labels: &[String]) -> u64 {
+    let mut input = format!("{}\n", HASH_VERSION);
+    for (name, value) in labels.to_map() {
+        if hash_labels.is_empty() || hash_labels.contains(&name) {
+            input.push_str(&format!("{}={}\n", name, value));
+        }
+    }
+    input.bytes().fold(0xcbf29ce484222325, |hash, byte| {
+        (hash ^ byte as u64).wrapping_mul(0x100000001b3)
+    })
+}
+
+/// Identify the downstream an action depends on, so repeated failures against it open one circuit
+pub fn circuit_key(action: &str, alert: &Alert, args: &Args) -> String {
+    let labels = &alert.labels;
+    match action {
+        "webhook" => format!(
+            "webhook:{}",
+            labels.webhook_url.as_deref().unwrap_or_default()
+        ),
+        "pagerduty" => format!("pagerduty:{}", args.pagerduty_url),
+        "opsgenie" => format!("opsgenie:{}", args.opsgenie_url),
+        "teams" => format!(
+            "teams:{}",
+            labels
+                .teams_webhook_url
+                .as_deref()
+                .or(args.teams_webhook_url.as_deref())
+                .unwrap_or_default()
+        ),
+        "discord" => format!(
+            "discord:{}",
+            labels
+                .discord_webhook_url
+                .as_deref()
+                .or(args.discord_webhook_url.as_deref())
+                .unwrap_or_default()
+        ),
+        "command" => format!("command:{}", labels.command.as_deref().unwrap_or_default()),
+        _ if is_kube_action(action) => match &labels.cluster {
+            Some(cluster) => format!(
+                "namespace:{}/{}",
+                cluster,
+                labels.namespace.as_deref().unwrap_or_default()
+            ),
+            None => format!(
+                "namespace:{}",
+                labels.namespace.as_deref().unwrap_or_default()
+            ),
+        },
+        _ => action.to_string(),
+    }
+}
+
+/// Identify the object an alert acts on, so repeated actions against it can be correlated
+pub fn target_key(alert: &Alert) -> String {
+    let labels = &alert.labels;
+    let key = match (
+        &labels.namespace,
+        &labels.pod,
+        &labels.selector,
+        &labels.daemonset,
+        &labels.node,
+        labels
+            .deployment
+            .as_ref()
+            .map(|name| ("deployment", name))
+            .or(labels.cronjob.as_ref().map(|name| ("cronjob", name)))
+            .or(labels.hpa.as_ref().map(|name| ("hpa", name))),
+    ) {
+        (Some(namespace), Some(pod), ..) => format!("{}/{}", namespace, pod),
+        (Some(namespace), None, Some(selector), ..) => format!("{}/{}", namespace, selector),
+        (Some(namespace), None, None, Some(daemonset), Some(node), _) => {
+            format!("{}/{}@{}", namespace, daemonset, node)
+        }
+        (_, None, None, _, Some(node), _) => format!("node/{}", node),
+        (Some(namespace), None, None, None, None, Some((kind, name))) => {
+            format!("{}/{}/{}", namespace, kind, name)
+        }
+        _ => return alert.fingerprint.clone(),
+    };
+    match &labels.cluster {
+        Some(cluster) => format!("{}:{}", cluster, key),
+        None => key,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::Value;
+
+    fn alert(labels: Value) -> Alert {
+        serde_json::from_value(json!({
+            "fingerprint": "abc123",
+            "status": { "state": "active" },
+            "labels": labels,
+        }))
+        .unwrap()
+    }
+
+    fn rule(namespace: &str, spec: Value) -> AlertRemediationRule {
+        let mut rule = AlertRemediationRule::new("test", serde_json::from_value(spec).unwrap());
+        rule.metadata.namespace = Some(namespace.to_string());
+        rule
+    }
+
+    #[test]
+    fn stable_hash_depends_only_on_selected_labels() {
+        let labels = |pod: &str| {
+            alert(
+                json!({ "alertname": "KubePodCrashLooping", "namespace": "payments", "pod": pod }),
+            )
+            .labels
+        };
+        assert_eq!(
+            stable_hash(&labels("api-1"), &[]),
+            stable_hash(&labels("api-1"), &[])
+        );
+        assert_ne!(
+            stable_hash(&labels("api-1"), &[]),
+            stable_hash(&labels("api-2"), &[])
+        );
+        let selected = ["alertname".to_string(), "namespace".to_string()];
+        assert_eq!(
+            stable_hash(&labels("api-1"), &selected),
+            stable_hash(&labels("api-2"), &selected)
+        );
+    }
+
+    #[test]
+    fn target_key_names_the_acted_on_object() {
+        let key = |labels| target_key(&alert(labels));
+        assert_eq!(
+            key(json!({ "alertname": "A", "namespace": "payments", "pod": "api-1" })),
+            "payments/api-1"
+        );
+        assert_eq!(
+            key(json!({ "alertname": "A", "namespace": "payments", "selector": "app=worker" })),
+            "payments/app=worker"
+        );
+        assert_eq!(
+            key(json!({
+                "alertname": "A",
+                "namespace": "logging",
+                "daemonset": "fluent-bit",
+                "node": "node-1",
+            })),
+            "logging/fluent-bit@node-1"
+        );
+        assert_eq!(
+            key(json!({ "alertname": "A", "node": "node-1" })),
+            "node/node-1"
+        );
+        assert_eq!(
+            key(json!({ "alertname": "A", "namespace": "payments", "deployment": "api" })),
+            "payments/deployment/api"
+        );
+        assert_eq!(key(json!({ "alertname": "A" })), "abc123");
+    }
+
+    #[test]
+    fn team_rules_only_match_their_namespace() {
+        let spec = json!({ "action": "delete_pod", "matchers": { "alertname": "A" } });
+        let payments = alert(json!({ "alertname": "A", "namespace": "payments" }));
+        let billing = alert(json!({ "alertname": "A", "namespace": "billing" }));
+        let global = rule("alert-actor", spec.clone());
+        assert!(global.matches(&payments, "alert-actor"));
+        assert!(global.matches(&billing, "alert-actor"));
+        let team = rule("payments", spec);
+        assert!(team.matches(&payments, "alert-actor"));
+        assert!(!team.matches(&billing, "alert-actor"));
+    }
+
+    #[test]
+    fn matchers_cluster_and_expression_must_all_hold() {
+        let rule = rule(
+            "alert-actor",
+            json!({
+                "action": "delete_pod",
+                "matchers": { "alertname": "A" },
+                "cluster": "prod",
+                "expression": r#"labels.namespace.startsWith("prod-")"#,
+            }),
+        );
+        let matching = json!({ "alertname": "A", "namespace": "prod-api", "cluster": "prod" });
+        assert!(rule.matches(&alert(matching), "alert-actor"));
+        for labels in [
+            json!({ "alertname": "B", "namespace": "prod-api", "cluster": "prod" }),
+            json!({ "alertname": "A", "namespace": "prod-api", "cluster": "staging" }),
+            json!({ "alertname": "A", "namespace": "dev-api", "cluster": "prod" }),
+            // The expression fails on the missing namespace, which is no match
+            json!({ "alertname": "A", "cluster": "prod" }),
+        ] {
+            assert!(!rule.matches(&alert(labels), "alert-actor"));
+        }
+    }
+
+    #[test]
+    fn team_rules_only_impersonate_their_service_accounts() {
+        let impersonating = |namespace, user: &str, groups: &[&str]| {
+            rule(
+                namespace,
+                json!({ "action": "delete_pod", "impersonate": user, "impersonateGroups": groups }),
+            )
+            .impersonation("alert-actor")
+        };
+        let own = impersonating("payments", "system:serviceaccount:payments:remediator", &[])
+            .unwrap()
+            .unwrap();
+        assert_eq!(own.user, "system:serviceaccount:payments:remediator");
+        assert!(
+            impersonating("payments", "system:serviceaccount:billing:remediator", &[]).is_err()
+        );
+        assert!(impersonating("payments", "system:serviceaccount:payments:", &[]).is_err());
+        assert!(impersonating("payments", "admin", &[]).is_err());
+        assert!(impersonating(
+            "payments",
+            "system:serviceaccount:payments:remediator",
+            &["ops"]
+        )
+        .is_err());
+        let global = impersonating("alert-actor", "admin", &["system:masters"])
+            .unwrap()
+            .unwrap();
+        assert_eq!(global.groups, ["system:masters"]);
+        let no_user = rule(
+            "alert-actor",
+            json!({ "action": "delete_pod", "impersonateGroups": ["ops"] }),
+        );
+        assert!(no_user.impersonation("alert-actor").is_err());
+        let none = rule("payments", json!({ "action": "delete_pod" }));
+        assert!(none.impersonation("alert-actor").unwrap().is_none());
+    }
+
+    #[test]
+    fn team_rules_are_limited_to_namespaced_actions() {
+        let applied = |namespace, action| {
+            let mut alert =
+                alert(json!({ "alertname": "A", "namespace": "payments", "node": "n1" }));
+            rule(namespace, json!({ "action": action })).apply(&mut alert, "alert-actor")
+        };
+        assert!(applied("payments", "delete_pod").is_ok());
+        assert!(applied("payments", "webhook").is_ok());
+        for action in ["taint_node", "restart_daemonset_pod", "run_job", "command"] {
+            assert!(applied("payments", action).is_err(), "{}", action);
+            assert!(applied("alert-actor", action).is_ok(), "{}", action);
+        }
+    }
+
+    #[test]
+    fn team_rules_may_not_set_privileged_labels() {
+        for label in ["command", "job_template", "ca_cert", "cluster", "node"] {
+            let mut alert = alert(json!({ "alertname": "A", "namespace": "payments" }));
+            let team = rule(
+                "payments",
+                json!({ "action": "webhook", "labels": { label: "x" } }),
+            );
+            assert!(team.apply(&mut alert, "alert-actor").is_err(), "{}", label);
+        }
+        let mut alert = alert(json!({ "alertname": "A", "namespace": "payments" }));
+        let global = rule(
+            "alert-actor",
+            json!({ "action": "webhook", "labels": { "ca_cert": "/etc/ca.pem" } }),
+        );
+        assert!(global.apply(&mut alert, "alert-actor").is_ok());
+    }
+}
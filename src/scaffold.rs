@@ -0,0 +1,457 @@
+//! Example configuration for new setups: a commented rules file and the RBAC for its actions,
+//! and the rules equivalent to an existing flag-based setup
+
+use std::collections::{BTreeMap, BTreeSet};
+
+use crate::{actions::is_known_action, config::Args, rbac::action_permissions};
+
+/// Alert and labels of the example rule for each action, with a comment per label
+fn example(
+    action: &str,
+) -> (
+    &'static str,
+    Vec<(&'static str, &'static str, &'static str)>,
+) {
+    match action {
+        "delete_pod" => (
+            "KubePodCrashLooping",
+            vec![(
+                "grace_period_seconds",
+                "30",
+                "Seconds the pod gets to shut down; force: \"true\" kills it immediately",
+            )],
+        ),
+        "delete_pods_by_selector" => (
+            "WorkerQueueStuck",
+            vec![(
+                "selector",
+                "app=worker",
+                "Pods in the alert's namespace to delete, refused above --selector-max-pods",
+            )],
+        ),
+        "restart_daemonset_pod" => (
+            "NodeLogShippingStalled",
+            vec![(
+                "daemonset",
+                "fluent-bit",
+                "DaemonSet in the alert's namespace whose pod on the alert's node is restarted",
+            )],
+        ),
+        "taint_node" => (
+            "NodeFilesystemErrors",
+            vec![(
+                "taint_key",
+                "alert-actor/investigate",
+                "Taint put on the alert's node; taint_value, taint_effect and taint_ttl_secs may be set too",
+            )],
+        ),
+        "suggest_rightsizing" => (
+            "ContainerCPUThrottlingHigh",
+            vec![(
+                "webhook_url",
+                "https://hooks.example.com/rightsizing",
+                "Where suggestions are posted; without it they become a pod annotation",
+            )],
+        ),
+        "run_job" => (
+            "DatabaseReplicationBroken",
+            vec![(
+                "job_template",
+                "repair-replication",
+                "Job template registered with --job-templates repair-replication=/path/job.yaml",
+            )],
+        ),
+        "rollback" => (
+            "ErrorRateSpikeAfterDeploy",
+            vec![(
+                "deployment",
+                "checkout",
+                "Deployment rolled back, if it was rolled out less than --rollback-max-age-secs ago",
+            )],
+        ),
+        "cleanup_pods" => (
+            "TooManyEvictedPods",
+            vec![(
+                "cleanup_phases",
+                "Failed",
+                "Phases of the pods deleted; Failed,Succeeded also removes completed ones",
+            )],
+        ),
+        "suspend_cronjob" => (
+            "CronJobRunaway",
+            vec![(
+                "cronjob",
+                "report-export",
+                "CronJob in the alert's namespace that is suspended",
+            )],
+        ),
+        "resume_cronjob" => (
+            "ReportBackendRecovered",
+            vec![(
+                "cronjob",
+                "report-export",
+                "CronJob in the alert's namespace that is resumed",
+            )],
+        ),
+        "force_finalize" => ("KubePodStuckTerminating", Vec::new()),
+        "hpa_adjust" => (
+            "CheckoutLatencyHigh",
+            vec![
+                (
+                    "hpa",
+                    "checkout",
+                    "HorizontalPodAutoscaler in the alert's namespace",
+                ),
+                (
+                    "hpa_min_replicas",
+                    "+2",
+                    "New minReplicas, or a change of it; hpa_max_replicas works the same way",
+                ),
+            ],
+        ),
+        "webhook" => (
+            "KubeJobFailed",
+            vec![(
+                "webhook_url",
+                "https://hooks.example.com/alerts",
+                "Receives the alert as JSON, or as rendered by --webhook-template-file",
+            )],
+        ),
+        "pagerduty" => (
+            "KubeNodeNotReady",
+            vec![(
+                "pagerduty_routing_key",
+                "env:PAGERDUTY_ROUTING_KEY",
+                "Integration key of the PagerDuty service, overriding --pagerduty-routing-key",
+            )],
+        ),
+        "opsgenie" => (
+            "KubeNodeNotReady",
+            vec![(
+                "opsgenie_priority",
+                "P2",
+                "Available to --opsgenie-priority as {{ labels.opsgenie_priority }}",
+            )],
+        ),
+        "teams" => (
+            "KubeDeploymentReplicasMismatch",
+            vec![(
+                "teams_webhook_url",
+                "https://example.webhook.office.com/webhookb2/...",
+                "Teams incoming webhook, overriding --teams-webhook-url",
+            )],
+        ),
+        "discord" => (
+            "KubeDeploymentReplicasMismatch",
+            vec![(
+                "discord_webhook_url",
+                "https://discord.com/api/webhooks/...",
+                "Discord webhook, overriding --discord-webhook-url",
+            )],
+        ),
+        "email" => (
+            "KubePersistentVolumeFillingUp",
+            vec![(
+                "email_to",
+                "oncall@example.com",
+                "Recipient, overriding --email-to",
+            )],
+        ),
+        "command" => (
+            "KubePodNotReady",
+            vec![(
+                "command",
+                "collect-diagnostics",
+                "Executable registered with --commands collect-diagnostics=/path/to/script",
+            )],
+        ),
+        _ => ("Watchdog", Vec::new()),
+    }
+}
+
+/// Caveat shown above the action of the example rule, for actions that need more than a rule
+fn note(action: &str) -> Option<&'static str> {
+    match action {
+        "force_finalize" => Some(
+            "Refused unless --allow-force-finalize is set; only releases pods that have been\n  \
+             # terminating for longer than --force-finalize-after-secs",
+        ),
+        "opsgenie" => Some("Needs --opsgenie-api-key; the alert is closed once it resolves"),
+        "pagerduty" => Some("Resolves the PagerDuty incident once the alert resolves"),
+        _ => None,
+    }
+}
+
+/// A commented AlertRemediationRule per action, in `namespace`. Rules in the controller's own
+/// namespace apply to alerts from every namespace.
+pub fn rules_file(actions: &[String], namespace: &str) -> Result<String, String> {
+    if let Some(action) = actions.iter().find(|action| !is_known_action(action)) {
+        return Err(format!("unknown action '{}'", action));
+    }
+    let mut out = String::from(
+        "# Example AlertRemediationRules, generated by `alert-actor generate-config`.\n\
+         # Load the CRDs with `alert-actor --print-crd | kubectl apply -f -`, run the controller\n\
+         # with --remediation-rules, and check changes with `alert-actor validate --rules-file`.\n",
+    );
+    for (index, action) in actions.iter().enumerate() {
+        let (alertname, labels) = example(action);
+        let name = action.replace('_', "-");
+        out.push_str(&format!(
+            "---\n\
+             apiVersion: alert-actor.jrcichra.dev/v1alpha1\n\
+             kind: AlertRemediationRule\n\
+             metadata:\n  \
+               name: {name}\n  \
+               namespace: {namespace}\n\
+             spec:\n  \
+               # Labels the alert must carry with exactly these values\n  \
+               matchers:\n    \
+                 alertname: {alertname}\n"
+        ));
+        // The optional fields are explained once, on the first rule
+        if index == 0 {
+            out.push_str(
+                "  # Expression over the whole alert that must also hold\n  \
+                 # expression: 'labels.severity == \"critical\" && !labels.namespace.startsWith(\"kube-\")'\n  \
+                 # JSONPaths for parameters the alert names differently\n  \
+                 # targetPaths:\n  \
+                 #   pod: $.labels.kubernetes_pod_name\n  \
+                 # Only match alerts carrying this cluster label\n  \
+                 # cluster: prod-eu\n  \
+                 # Seconds before the same alert is acted on again (--cooldown-secs)\n  \
+                 # cooldownSecs: 600\n  \
+                 # How often matching alerts are evaluated, instead of every --interval\n  \
+                 # intervalSecs: 60\n  \
+                 # Polls the alert must be seen firing in before acting (--confirmations)\n  \
+                 # confirmations: 2\n  \
+                 # Validate Kubernetes changes on the API server without applying them\n  \
+                 # serverDryRun: true\n  \
+                 # Wait for deleted pods and their dependents to be gone (Background, Orphan)\n  \
+                 # propagationPolicy: Foreground\n  \
+                 # Act as this user, so actions are bounded by its RBAC\n  \
+                 # impersonate: system:serviceaccount:payments:remediator\n",
+            );
+        }
+        if let Some(note) = note(action) {
+            out.push_str(&format!("  # {}\n", note));
+        }
+        out.push_str(&format!("  action: {}\n", action));
+        if labels.is_empty() {
+            out.push_str("  labels: {}\n");
+        } else {
+            out.push_str(
+                "  # Parameters of the action, set on matching alerts before acting\n  labels:\n",
+            );
+        }
+        for (label, value, comment) in labels {
+            out.push_str(&format!("    # {}\n    {}: {:?}\n", comment, label, value));
+        }
+    }
+    Ok(out)
+}
+
+/// Kubernetes name for a rule from an alert name and action, e.g. KubePodCrashLooping and
+/// delete_pod become kubepodcrashlooping-delete-pod
+fn rule_name(parts: &[&str]) -> String {
+    let name: String = parts
+        .join("-")
+        .chars()
+        .map(|c| match c.to_ascii_lowercase() {
+            c @ ('a'..='z' | '0'..='9') => c,
+            _ => '-',
+        })
+        .collect();
+    name.split('-')
+        .filter(|part| !part.is_empty())
+        .collect::<Vec<_>>()
+        .join("-")
+}
+
+/// Rules in `namespace` doing what the flags and environment in `args` do for alerts that name
+/// one of `actions` in their action label: one rule per --alert-names entry and action, carrying
+/// the cooldown, confirmations, server-side dry-run and the action's defaults from the flags
+pub fn migrate_rules(args: &Args, actions: &[String], namespace: &str) -> Result<String, String> {
+    if let Some(action) = actions.iter().find(|action| !is_known_action(action)) {
+        return Err(format!("unknown action '{}'", action));
+    }
+    let mut out = String::from(
+        "# AlertRemediationRules equivalent to the flags and environment of this controller,\n\
+         # generated by `alert-actor migrate-config`. Run the controller with --remediation-rules.\n\
+         # Labels set by a rule replace the alert's own labels of the same name, so drop those\n\
+         # that alerts should still be able to override.\n",
+    );
+    // Without --alert-names every alert was acted on
+    let alertnames: Vec<Option<&str>> = if args.alert_names.is_empty() {
+        vec![None]
+    } else {
+        args.alert_names
+            .iter()
+            .map(|name| Some(name.as_str()))
+            .collect()
+    };
+    for alertname in &alertnames {
+        for action in actions {
+            let name = rule_name(&[alertname.unwrap_or_default(), action]);
+            out.push_str(&format!(
+                "---\n\
+                 apiVersion: alert-actor.jrcichra.dev/v1alpha1\n\
+                 kind: AlertRemediationRule\n\
+                 metadata:\n  \
+                   name: {name}\n  \
+                   namespace: {namespace}\n\
+                 spec:\n  \
+                   matchers:\n"
+            ));
+            if let Some(alertname) = alertname {
+                out.push_str(&format!("    alertname: {:?}\n", alertname));
+            }
+            // The alert picked the action with its label, rules keep acting on just those
+            out.push_str(&format!("    action: {:?}\n", action));
+            out.push_str(&format!("  action: {}\n", action));
+            out.push_str(&format!("  cooldownSecs: {}\n", args.cooldown_secs));
+            out.push_str(&format!("  confirmations: {}\n", args.confirmations));
+            if args.server_dry_run {
+                out.push_str("  serverDryRun: true\n");
+            }
+            let mut labels = vec![("action_timeout_secs", args.action_timeout_secs.to_string())];
+            match action.as_str() {
+                "taint_node" => {
+                    labels.push(("taint_key", args.taint_key.clone()));
+                    if !args.taint_value.is_empty() {
+                        labels.push(("taint_value", args.taint_value.clone()));
+                    }
+                    labels.push(("taint_effect", args.taint_effect.clone()));
+                    if args.taint_ttl_secs > 0 {
+                        labels.push(("taint_ttl_secs", args.taint_ttl_secs.to_string()));
+                    }
+                }
+                "email" if !args.email_to.is_empty() => {
+                    labels.push(("email_to", args.email_to.join(",")));
+                }
+                _ => {}
+            }
+            out.push_str("  labels:\n");
+            for (label, value) in labels {
+                out.push_str(&format!("    {}: {:?}\n", label, value));
+            }
+        }
+    }
+    Ok(out)
+}
+
+/// ServiceAccount, ClusterRole and bindings the controller needs to run `actions`, with the
+/// ServiceAccount and its Lease permissions in `namespace`
+pub fn rbac(
+    actions: &[String],
+    namespace: &str,
+    remediation_rules: bool,
+    leader_election: bool,
+) -> Result<String, String> {
+    if let Some(action) = actions.iter().find(|action| !is_known_action(action)) {
+        return Err(format!("unknown action '{}'", action));
+    }
+    // Verbs per API group and resource, so each gets a single rule
+    let mut permissions: BTreeMap<(&str, &str), BTreeSet<&str>> = BTreeMap::new();
+    for action in actions {
+        for (group, resource, verb) in action_permissions(action) {
+            permissions
+                .entry((group, resource))
+                .or_default()
+                .insert(verb);
+        }
+    }
+    if remediation_rules {
+        for verb in ["get", "list", "watch"] {
+            permissions
+                .entry(("alert-actor.jrcichra.dev", "alertremediationrules"))
+                .or_default()
+                .insert(verb);
+        }
+        // For the PermissionsGranted condition, and the reviews behind it
+        permissions
+            .entry(("alert-actor.jrcichra.dev", "alertremediationrules/status"))
+            .or_default()
+            .insert("patch");
+        permissions
+            .entry(("authorization.k8s.io", "selfsubjectaccessreviews"))
+            .or_default()
+            .insert("create");
+    }
+    let mut rules = String::new();
+    for ((group, resource), verbs) in &permissions {
+        let verbs: Vec<&str> = verbs.iter().copied().collect();
+        rules.push_str(&format!(
+            "  - apiGroups: [{:?}]\n    resources: [{:?}]\n    verbs: [{}]\n",
+            group,
+            resource,
+            verbs
+                .iter()
+                .map(|verb| format!("{:?}", verb))
+                .collect::<Vec<_>>()
+                .join(", ")
+        ));
+    }
+    if rules.is_empty() {
+        rules.push_str("  []\n");
+    }
+    let mut out = format!(
+        "# RBAC for alert-actor running {actions}, generated by `alert-actor generate-config --rbac`\n\
+         apiVersion: v1\n\
+         kind: ServiceAccount\n\
+         metadata:\n  \
+           name: alert-actor\n  \
+           namespace: {namespace}\n\
+         ---\n\
+         # Actions target any namespace (and nodes), so their permissions are cluster-wide\n\
+         apiVersion: rbac.authorization.k8s.io/v1\n\
+         kind: ClusterRole\n\
+         metadata:\n  \
+           name: alert-actor\n\
+         rules:\n\
+         {rules}\
+         ---\n\
+         apiVersion: rbac.authorization.k8s.io/v1\n\
+         kind: ClusterRoleBinding\n\
+         metadata:\n  \
+           name: alert-actor\n\
+         roleRef:\n  \
+           apiGroup: rbac.authorization.k8s.io\n  \
+           kind: ClusterRole\n  \
+           name: alert-actor\n\
+         subjects:\n  \
+           - kind: ServiceAccount\n    \
+             name: alert-actor\n    \
+             namespace: {namespace}\n",
+        actions = actions.join(", "),
+    );
+    if leader_election {
+        out.push_str(&format!(
+            "---\n\
+             # Leader election Leases, in the controller's namespace only\n\
+             apiVersion: rbac.authorization.k8s.io/v1\n\
+             kind: Role\n\
+             metadata:\n  \
+               name: alert-actor-leader-election\n  \
+               namespace: {namespace}\n\
+             rules:\n  \
+               - apiGroups: [\"coordination.k8s.io\"]\n    \
+                 resources: [\"leases\"]\n    \
+                 verbs: [\"create\", \"get\", \"patch\"]\n\
+             ---\n\
+             apiVersion: rbac.authorization.k8s.io/v1\n\
+             kind: RoleBinding\n\
+             metadata:\n  \
+               name: alert-actor-leader-election\n  \
+               namespace: {namespace}\n\
+             roleRef:\n  \
+               apiGroup: rbac.authorization.k8s.io\n  \
+               kind: Role\n  \
+               name: alert-actor-leader-election\n\
+             subjects:\n  \
+               - kind: ServiceAccount\n    \
+                 name: alert-actor\n    \
+                 namespace: {namespace}\n"
+        ));
+    }
+    Ok(out)
+}
@@ -0,0 +1,238 @@
+//! Cron windows and the scheduler of periodic tasks
+
+use anyhow::Result;
+use k8s_openapi::chrono::{DateTime, Datelike, TimeZone, Timelike, Utc};
+use log::warn;
+use std::{collections::hash_map::RandomState, hash::BuildHasher};
+use tokio::time::Duration;
+
+use crate::metrics::METRICS;
+
+/// Whether one cron field (`*`, `5`, `1-5`, `*/15`, `0-30/10`, or a comma list of those)
+/// matches a value within `min..=max`
+pub fn cron_field_matches(field: &str, value: u32, min: u32, max: u32) -> Result<bool, String> {
+    let parse = |n: &str| {
+        n.parse::<u32>()
+            .ok()
+            .filter(|n| (min..=max).contains(n))
+            .ok_or_else(|| format!("invalid cron value '{}'", n))
+    };
+    for part in field.split(',') {
+        let (range, step) = match part.split_once('/') {
+            Some((range, step)) => (
+                range,
+                parse(step)
+                    .ok()
+                    .filter(|s| *s > 0)
+                    .ok_or_else(|| format!("invalid cron step '{}'", step))?,
+            ),
+            None => (part, 1),
+        };
+        let (start, end) = match range {
+            "*" => (min, max),
+            _ => match range.split_once('-') {
+                Some((start, end)) => (parse(start)?, parse(end)?),
+                None if step > 1 => (parse(range)?, max),
+                None => (parse(range)?, parse(range)?),
+            },
+        };
+        if (start..=end).contains(&value) && (value - start).is_multiple_of(step) {
+            return Ok(true);
+        }
+    }
+    Ok(false)
+}
+
+/// Whether a 5-field cron expression (minute hour day-of-month month day-of-week, in the time
+/// zone of `at`) matches the minute of `at`. Used as a window covering every minute the
+/// expression matches, e.g. `* 9-17 * * 1-5` for business hours.
+pub fn cron_matches<Tz: TimeZone>(expression: &str, at: DateTime<Tz>) -> Result<bool, String> {
+    let fields: Vec<&str> = expression.split_whitespace().collect();
+    let [minute, hour, day, month, weekday] = fields[..] else {
+        return Err(format!(
+            "cron expression '{}' must have 5 fields",
+            expression
+        ));
+    };
+    let day_matches = cron_field_matches(day, at.day(), 1, 31)?;
+    // Sunday is both 0 and 7
+    let weekday_matches = cron_field_matches(weekday, at.weekday().num_days_from_sunday(), 0, 7)?
+        || (at.weekday().num_days_from_sunday() == 0 && cron_field_matches(weekday, 7, 0, 7)?);
+    // As in cron, a restricted day of month and day of week match if either does
+    let date_matches = match (day, weekday) {
+        ("*", _) | (_, "*") => day_matches && weekday_matches,
+        _ => day_matches || weekday_matches,
+    };
+    Ok(cron_field_matches(minute, at.minute(), 0, 59)?
+        && cron_field_matches(hour, at.hour(), 0, 23)?
+        && cron_field_matches(month, at.month(), 1, 12)?
+        && date_matches)
+}
+
+/// Whether any of the cron windows covers the current minute
+pub fn in_window<'a, Tz: TimeZone>(
+    windows: impl IntoIterator<Item = &'a str>,
+    at: DateTime<Tz>,
+) -> Result<bool, String> {
+    for window in windows {
+        if cron_matches(window, at.clone())? {
+            return Ok(true);
+        }
+    }
+    Ok(false)
+}
+
+/// Periodic work done by the main loop
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Task {
+    /// Poll Alertmanager and act on matching alerts
+    Poll,
+    /// Drop expired cooldowns, acknowledgements and flap state
+    Gc,
+    /// Save counters to --metrics-state-configmap
+    PersistMetrics,
+    /// Record missing permissions in the status of AlertRemediationRules
+    RuleStatus,
+    /// Expire the silences of alerts that resolved and remove taints that reached their TTL
+    Mitigations,
+}
+
+impl Task {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Self::Poll => "poll",
+            Self::Gc => "gc",
+            Self::PersistMetrics => "persist_metrics",
+            Self::RuleStatus => "rule_status",
+            Self::Mitigations => "mitigations",
+        }
+    }
+}
+
+struct ScheduledTask {
+    task: Task,
+    every: Duration,
+    /// Each delay is randomly lengthened or shortened by up to this fraction of `every`, so tasks
+    /// of many replicas (and tasks sharing a period) don't run in lockstep while keeping `every`
+    /// on average
+    jitter: f64,
+    next: tokio::time::Instant,
+}
+
+/// Runs several periodic tasks from one loop. Each task has its own period and jitter; a task
+/// failing only shows up in its own logs and metrics and doesn't delay the others.
+#[derive(Default)]
+pub struct Scheduler {
+    tasks: Vec<ScheduledTask>,
+}
+
+impl Scheduler {
+    pub fn new() -> Self {
+        Self { tasks: Vec::new() }
+    }
+
+    /// Schedule a task, first due after `first`
+    pub fn add(&mut self, task: Task, every: Duration, jitter: f64, first: Duration) {
+        self.tasks.push(ScheduledTask {
+            task,
+            every,
+            jitter,
+            next: tokio::time::Instant::now() + first,
+        });
+    }
+
+    /// Change how often a task runs. A shorter period also brings its next run forward.
+    pub fn set_period(&mut self, task: Task, every: Duration) {
+        for scheduled in self
+            .tasks
+            .iter_mut()
+            .filter(|scheduled| scheduled.task == task)
+        {
+            if scheduled.every == every {
+                continue;
+            }
+            scheduled.every = every;
+            scheduled.next = scheduled.next.min(tokio::time::Instant::now() + every);
+        }
+    }
+
+    /// Wait until the next task is due and schedule its following run
+    pub async fn next(&mut self) -> Task {
+        let Some(due) = self.tasks.iter_mut().min_by_key(|scheduled| scheduled.next) else {
+            return std::future::pending().await;
+        };
+        tokio::time::sleep_until(due.next).await;
+        let random = RandomState::new().hash_one(due.task.as_str()) as f64 / u64::MAX as f64;
+        due.next = tokio::time::Instant::now()
+            + due.every.mul_f64(1.0 + due.jitter * (2.0 * random - 1.0));
+        METRICS.inc(
+            "alert_actor_task_runs_total",
+            &[("task", due.task.as_str())],
+        );
+        METRICS.set(
+            "alert_actor_task_last_run_timestamp_seconds",
+            &[("task", due.task.as_str())],
+            Utc::now().timestamp() as f64,
+        );
+        due.task
+    }
+
+    /// Record that a run of the task failed
+    pub fn failed(task: Task, err: &dyn std::fmt::Display) {
+        warn!("Scheduled task {} failed: {}", task.as_str(), err);
+        METRICS.inc(
+            "alert_actor_task_failures_total",
+            &[("task", task.as_str())],
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use k8s_openapi::chrono::FixedOffset;
+
+    #[test]
+    fn cron_matches_fields() {
+        // A Wednesday
+        let at = Utc.with_ymd_and_hms(2024, 5, 15, 10, 30, 0).unwrap();
+        assert_eq!(cron_matches("* * * * *", at), Ok(true));
+        assert_eq!(cron_matches("30 10 15 5 3", at), Ok(true));
+        assert_eq!(cron_matches("*/15 9-17 * * 1-5", at), Ok(true));
+        assert_eq!(cron_matches("0-20/10 * * * *", at), Ok(false));
+        assert_eq!(cron_matches("* 11,12 * * *", at), Ok(false));
+        assert_eq!(cron_matches("* * * * 0,6", at), Ok(false));
+    }
+
+    #[test]
+    fn cron_matches_day_of_month_or_week() {
+        let at = Utc.with_ymd_and_hms(2024, 5, 15, 10, 30, 0).unwrap();
+        // Either restricted field may match
+        assert_eq!(cron_matches("* * 1 * 3", at), Ok(true));
+        assert_eq!(cron_matches("* * 15 * 0", at), Ok(true));
+        assert_eq!(cron_matches("* * 1 * 0", at), Ok(false));
+        // Sunday is both 0 and 7
+        let sunday = Utc.with_ymd_and_hms(2024, 5, 19, 0, 0, 0).unwrap();
+        assert_eq!(cron_matches("* * * * 7", sunday), Ok(true));
+        assert_eq!(cron_matches("* * * * 0", sunday), Ok(true));
+    }
+
+    #[test]
+    fn cron_matches_in_the_time_zone_of_at() {
+        let at = Utc.with_ymd_and_hms(2024, 5, 15, 22, 30, 0).unwrap();
+        assert_eq!(cron_matches("* 22 * * 3", at), Ok(true));
+        // 00:30 on Thursday two hours east of UTC
+        let east = at.with_timezone(&FixedOffset::east_opt(2 * 3600).unwrap());
+        assert_eq!(cron_matches("* 0 * * 4", east), Ok(true));
+        assert_eq!(cron_matches("* 22 * * 3", east), Ok(false));
+    }
+
+    #[test]
+    fn cron_matches_rejects_invalid_expressions() {
+        let at = Utc.with_ymd_and_hms(2024, 5, 15, 10, 30, 0).unwrap();
+        assert!(cron_matches("* * * *", at).is_err());
+        assert!(cron_matches("60 * * * *", at).is_err());
+        assert!(cron_matches("*/0 * * * *", at).is_err());
+        assert!(cron_matches("a * * * *", at).is_err());
+    }
+}
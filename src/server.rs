@@ -0,0 +1,526 @@
+//! HTTP server for metrics, health checks, admin requests and pushed alerts
+
+use anyhow::Result;
+use base64::{engine::general_purpose::STANDARD as BASE64, Engine};
+use http_body_util::{BodyExt, Full, LengthLimitError, Limited};
+use hyper::{
+    body::{Bytes, Incoming},
+    header::{HeaderValue, RETRY_AFTER},
+    server::conn::http1,
+    service::service_fn,
+    Method, Request, Response, StatusCode,
+};
+use hyper_util::rt::TokioIo;
+use log::{info, warn};
+use serde::Deserialize;
+use serde_json::json;
+use std::{
+    collections::{BTreeMap, HashMap},
+    convert::Infallible,
+    error::Error,
+    net::{IpAddr, SocketAddr},
+    sync::{atomic::Ordering, Arc, Mutex},
+    time::{Instant, SystemTime},
+};
+use tokio::{
+    io::{AsyncRead, AsyncWrite},
+    net::TcpListener,
+    time::Duration,
+};
+use tokio_native_tls::{native_tls, TlsAcceptor};
+
+use crate::{
+    alertmanager::{Alert, AlertStatus, Notification, Receiver},
+    config::Args,
+    metrics::METRICS,
+    notify::resolve_secret,
+    sources::grafana_alert,
+    state::SharedState,
+};
+
+/// Body of /admin/ack requests
+#[derive(Debug, Deserialize)]
+pub struct AckRequest {
+    pub fingerprints: Vec<String>,
+    #[serde(default)]
+    pub duration_secs: u64,
+}
+
+pub fn http_response(status: StatusCode, body: String) -> Response<Full<Bytes>> {
+    let mut resp = Response::new(Full::new(Bytes::from(body)));
+    *resp.status_mut() = status;
+    resp
+}
+
+/// Compare secrets in constant time, so response timing doesn't reveal how much of a guess matched
+fn secrets_equal(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |diff, (x, y)| diff | (x ^ y)) == 0
+}
+
+/// Whether a push carries the credentials configured with --receiver-bearer-token or
+/// --receiver-username / --receiver-password. Either is accepted when both are configured, and
+/// every push is when neither is.
+pub fn receiver_authorized(
+    req: &Request<Incoming>,
+    args: &Args,
+) -> Result<bool, Box<dyn Error + Send + Sync>> {
+    let mut expected = Vec::new();
+    if let Some(token) = &args.receiver_bearer_token {
+        let token = resolve_secret(token).map_err(|err| err.to_string())?;
+        expected.push(format!("Bearer {}", token));
+    }
+    if let (Some(username), Some(password)) = (&args.receiver_username, &args.receiver_password) {
+        let password = resolve_secret(password).map_err(|err| err.to_string())?;
+        let credentials = BASE64.encode(format!("{}:{}", username, password));
+        expected.push(format!("Basic {}", credentials));
+    }
+    if expected.is_empty() {
+        return Ok(true);
+    }
+    let Some(presented) = req.headers().get("Authorization") else {
+        return Ok(false);
+    };
+    Ok(expected
+        .iter()
+        .any(|expected| secrets_equal(presented.as_bytes(), expected.as_bytes())))
+}
+
+/// Handle /admin requests. Requires the configured bearer token.
+pub async fn handle_admin(
+    req: Request<Incoming>,
+    args: &Args,
+    state: &SharedState,
+) -> Result<Response<Full<Bytes>>, Box<dyn Error + Send + Sync>> {
+    let Some(admin_token) = &args.admin_token else {
+        return Ok(http_response(
+            StatusCode::NOT_FOUND,
+            "admin API disabled".to_string(),
+        ));
+    };
+    let expected = format!(
+        "Bearer {}",
+        resolve_secret(admin_token).map_err(|err| err.to_string())?
+    );
+    let authorized = req
+        .headers()
+        .get("Authorization")
+        .is_some_and(|value| secrets_equal(value.as_bytes(), expected.as_bytes()));
+    if !authorized {
+        return Ok(http_response(
+            StatusCode::UNAUTHORIZED,
+            "unauthorized".to_string(),
+        ));
+    }
+
+    let query: HashMap<String, String> = req
+        .uri()
+        .query()
+        .map(serde_urlencoded::from_str)
+        .transpose()?
+        .unwrap_or_default();
+    let (method, path) = (req.method().clone(), req.uri().path().to_string());
+    match (&method, path.as_str()) {
+        (&Method::POST, "/admin/emergency") => {
+            let secs: u64 = query
+                .get("duration_secs")
+                .ok_or("missing duration_secs")?
+                .parse()?;
+            let secs = secs.min(args.emergency_max_secs);
+            *state.emergency_until.lock().unwrap() =
+                Some(Instant::now() + Duration::from_secs(secs));
+            warn!("Emergency mode enabled for {}s", secs);
+            Ok(http_response(
+                StatusCode::OK,
+                format!("emergency mode enabled for {}s", secs),
+            ))
+        }
+        (&Method::DELETE, "/admin/emergency") => {
+            *state.emergency_until.lock().unwrap() = None;
+            warn!("Emergency mode disabled");
+            Ok(http_response(
+                StatusCode::OK,
+                "emergency mode disabled".to_string(),
+            ))
+        }
+        (&Method::GET, "/admin/emergency") => Ok(http_response(
+            StatusCode::OK,
+            json!({ "active": state.emergency_active() }).to_string(),
+        )),
+        (&Method::POST, "/admin/ack") => {
+            let body = req.into_body().collect().await?.to_bytes();
+            let ack: AckRequest = serde_json::from_slice(&body)?;
+            let until = Instant::now() + Duration::from_secs(ack.duration_secs);
+            let mut acknowledged = state.acknowledged.lock().unwrap();
+            for fingerprint in &ack.fingerprints {
+                acknowledged.insert(fingerprint.clone(), until);
+            }
+            warn!(
+                "Acknowledged {} alerts as handled externally for {}s",
+                ack.fingerprints.len(),
+                ack.duration_secs
+            );
+            Ok(http_response(
+                StatusCode::OK,
+                format!("acknowledged {} alerts", ack.fingerprints.len()),
+            ))
+        }
+        (&Method::DELETE, "/admin/ack") => {
+            let body = req.into_body().collect().await?.to_bytes();
+            let ack: AckRequest = serde_json::from_slice(&body)?;
+            let mut acknowledged = state.acknowledged.lock().unwrap();
+            for fingerprint in &ack.fingerprints {
+                acknowledged.remove(fingerprint);
+            }
+            Ok(http_response(
+                StatusCode::OK,
+                format!("removed {} acknowledgements", ack.fingerprints.len()),
+            ))
+        }
+        (&Method::GET, "/admin/ack") => {
+            let now = Instant::now();
+            let acknowledged: BTreeMap<String, u64> = state
+                .acknowledged
+                .lock()
+                .unwrap()
+                .iter()
+                .filter(|(_, until)| **until > now)
+                .map(|(fingerprint, until)| (fingerprint.clone(), (*until - now).as_secs()))
+                .collect();
+            Ok(http_response(
+                StatusCode::OK,
+                json!(acknowledged).to_string(),
+            ))
+        }
+        _ => Ok(http_response(
+            StatusCode::NOT_FOUND,
+            "not found".to_string(),
+        )),
+    }
+}
+
+/// Take a push token from the source's bucket, which refills at --receiver-rate-limit per minute
+/// up to --receiver-rate-burst. When it is empty, returns how many seconds until the next token.
+pub fn take_push_token(args: &Args, state: &SharedState, source: IpAddr) -> Result<(), u64> {
+    if args.receiver_rate_limit == 0 {
+        return Ok(());
+    }
+    let per_sec = args.receiver_rate_limit as f64 / 60.0;
+    let burst = args.receiver_rate_burst.max(1) as f64;
+    let now = Instant::now();
+    let mut buckets = state.push_buckets.lock().unwrap();
+    // Forget sources whose bucket has filled up again, so spoofed or churning sources can't
+    // grow the map without bound
+    if buckets.len() > 10_000 {
+        buckets.retain(|_, (tokens, at)| *tokens + at.elapsed().as_secs_f64() * per_sec < burst);
+    }
+    let (tokens, refilled) = buckets.entry(source).or_insert((burst, now));
+    *tokens = (*tokens + (now - *refilled).as_secs_f64() * per_sec).min(burst);
+    *refilled = now;
+    if *tokens < 1.0 {
+        return Err(((1.0 - *tokens) / per_sec).ceil() as u64);
+    }
+    *tokens -= 1.0;
+    Ok(())
+}
+
+/// Queue pushed alerts for the main loop, which only drains the queue between cycles. Beyond
+/// `max` alerts the oldest are dropped: newer pushes carry the current state, and the next poll
+/// sweep picks up anything lost. Returns how many were dropped.
+pub fn queue_pushed(queue: &mut Vec<Alert>, alerts: Vec<Alert>, max: usize) -> usize {
+    queue.extend(alerts);
+    let dropped = queue.len().saturating_sub(max);
+    queue.drain(..dropped);
+    dropped
+}
+
+/// Accept an Alertmanager webhook notification and hand its firing alerts to the main loop.
+/// Grafana's webhook contact point sends the same payload with a few extra fields, which become
+/// annotations. Bodies over --receiver-max-body-bytes are refused before they are read in full.
+pub async fn handle_push(
+    req: Request<Incoming>,
+    args: &Args,
+    state: &SharedState,
+    grafana: bool,
+) -> Result<Response<Full<Bytes>>, Box<dyn Error + Send + Sync>> {
+    let body = match Limited::new(req.into_body(), args.receiver_max_body_bytes)
+        .collect()
+        .await
+    {
+        Ok(body) => body.to_bytes(),
+        Err(err) if err.is::<LengthLimitError>() => {
+            METRICS.inc(
+                "alert_actor_push_rejected_total",
+                &[("reason", "too_large")],
+            );
+            return Ok(http_response(
+                StatusCode::PAYLOAD_TOO_LARGE,
+                format!("body exceeds {} bytes", args.receiver_max_body_bytes),
+            ));
+        }
+        Err(err) => return Err(err),
+    };
+    let notification: Notification = serde_json::from_slice(&body)?;
+    let alerts: Vec<Alert> = notification
+        .alerts
+        .into_iter()
+        .filter(|alert| alert.status == "firing")
+        .map(|alert| {
+            let mut annotations = alert.annotations;
+            for (name, value) in [
+                ("dashboard_url", alert.dashboard_url),
+                ("panel_url", alert.panel_url),
+                ("value", alert.value_string),
+            ] {
+                if let Some(value) = value.filter(|value| !value.is_empty()) {
+                    annotations.entry(name.to_string()).or_insert(value);
+                }
+            }
+            let alert = Alert {
+                fingerprint: alert.fingerprint,
+                status: AlertStatus {
+                    state: "active".to_string(),
+                    silenced_by: Vec::new(),
+                    inhibited_by: Vec::new(),
+                },
+                labels: alert.labels,
+                annotations,
+                starts_at: alert.starts_at,
+                ends_at: alert.ends_at,
+                updated_at: None,
+                generator_url: alert.generator_url,
+                receivers: vec![Receiver {
+                    name: notification.receiver.clone(),
+                }],
+                original_labels: BTreeMap::new(),
+            };
+            if grafana {
+                grafana_alert(alert)
+            } else {
+                alert
+            }
+        })
+        .collect();
+    METRICS.inc("alert_actor_pushed_alerts_total", &[]);
+    let dropped = queue_pushed(
+        &mut state.pushed.lock().unwrap(),
+        alerts,
+        args.receiver_max_queued_alerts,
+    );
+    if dropped > 0 {
+        warn!(
+            "Push queue full, dropped the {} oldest alerts until the main loop catches up",
+            dropped
+        );
+        for _ in 0..dropped {
+            METRICS.inc(
+                "alert_actor_push_rejected_total",
+                &[("reason", "queue_full")],
+            );
+        }
+    }
+    state.pushed_notify.notify_one();
+    Ok(http_response(StatusCode::OK, "ok".to_string()))
+}
+
+pub async fn handle_http(
+    req: Request<Incoming>,
+    peer: SocketAddr,
+    args: Arc<Args>,
+    state: Arc<SharedState>,
+) -> Result<Response<Full<Bytes>>, Infallible> {
+    let resp = match (req.method(), req.uri().path()) {
+        (&Method::GET, "/metrics") => http_response(StatusCode::OK, METRICS.render()),
+        (&Method::GET, "/healthz") => http_response(StatusCode::OK, "ok".to_string()),
+        (&Method::GET, "/readyz") if state.kube_unavailable.load(Ordering::Relaxed) => {
+            http_response(
+                StatusCode::SERVICE_UNAVAILABLE,
+                "kubernetes api unavailable".to_string(),
+            )
+        }
+        (&Method::GET, "/readyz")
+            if args.poll_failures_unready > 0
+                && state.poll_failures.load(Ordering::Relaxed) >= args.poll_failures_unready =>
+        {
+            http_response(
+                StatusCode::SERVICE_UNAVAILABLE,
+                "alertmanager unreachable".to_string(),
+            )
+        }
+        (&Method::GET, "/readyz") => http_response(StatusCode::OK, "ok".to_string()),
+        (&Method::POST, path @ ("/webhook/alertmanager" | "/webhook/grafana")) if args.reactive => {
+            let grafana = path == "/webhook/grafana";
+            // Limited before authentication, so credentials can't be guessed at full speed either
+            if let Err(retry_after) = take_push_token(&args, &state, peer.ip()) {
+                METRICS.inc(
+                    "alert_actor_push_rejected_total",
+                    &[("reason", "rate_limited")],
+                );
+                warn!("Rate limited push from {} to {}", peer.ip(), path);
+                let mut resp = http_response(
+                    StatusCode::TOO_MANY_REQUESTS,
+                    "rate limit exceeded".to_string(),
+                );
+                resp.headers_mut()
+                    .insert(RETRY_AFTER, HeaderValue::from(retry_after));
+                return Ok(resp);
+            }
+            match receiver_authorized(&req, &args) {
+                Ok(true) => handle_push(req, &args, &state, grafana)
+                    .await
+                    .unwrap_or_else(|err| http_response(StatusCode::BAD_REQUEST, err.to_string())),
+                Ok(false) => {
+                    METRICS.inc("alert_actor_push_unauthorized_total", &[]);
+                    warn!(
+                        "Rejected unauthenticated push from {} to {}",
+                        peer.ip(),
+                        path
+                    );
+                    http_response(StatusCode::UNAUTHORIZED, "unauthorized".to_string())
+                }
+                Err(err) => http_response(StatusCode::INTERNAL_SERVER_ERROR, err.to_string()),
+            }
+        }
+        (_, path) if path.starts_with("/admin/") => handle_admin(req, &args, &state)
+            .await
+            .unwrap_or_else(|err| http_response(StatusCode::BAD_REQUEST, err.to_string())),
+        _ => http_response(StatusCode::NOT_FOUND, "not found".to_string()),
+    };
+    Ok(resp)
+}
+
+/// TLS acceptor for --tls-cert-file and --tls-key-file, rebuilt when either file changes so that
+/// rotated certificates (e.g. an updated Secret mount) are served without a restart
+pub struct TlsReloader {
+    cert: String,
+    key: String,
+    current: Mutex<(Option<SystemTime>, Option<SystemTime>, TlsAcceptor)>,
+}
+
+fn modified(path: &str) -> Option<SystemTime> {
+    std::fs::metadata(path)
+        .and_then(|meta| meta.modified())
+        .ok()
+}
+
+fn tls_acceptor(cert: &str, key: &str) -> Result<TlsAcceptor, Box<dyn Error + Send + Sync>> {
+    let identity = native_tls::Identity::from_pkcs8(&std::fs::read(cert)?, &std::fs::read(key)?)?;
+    Ok(TlsAcceptor::from(native_tls::TlsAcceptor::new(identity)?))
+}
+
+impl TlsReloader {
+    pub fn new(cert: &str, key: &str) -> Result<TlsReloader, Box<dyn Error + Send + Sync>> {
+        Ok(TlsReloader {
+            cert: cert.to_string(),
+            key: key.to_string(),
+            current: Mutex::new((modified(cert), modified(key), tls_acceptor(cert, key)?)),
+        })
+    }
+
+    /// The acceptor for a new connection, reloading the certificate first if its files changed.
+    /// A broken replacement is logged and the previous certificate kept.
+    pub fn acceptor(&self) -> TlsAcceptor {
+        let mut current = self.current.lock().unwrap();
+        let (cert_modified, key_modified) = (modified(&self.cert), modified(&self.key));
+        if (cert_modified, key_modified) != (current.0, current.1) {
+            match tls_acceptor(&self.cert, &self.key) {
+                Ok(acceptor) => {
+                    info!("Reloaded TLS certificate {}", self.cert);
+                    *current = (cert_modified, key_modified, acceptor);
+                }
+                Err(err) => warn!(
+                    "Failed to reload TLS certificate, keeping the old one: {}",
+                    err
+                ),
+            }
+        }
+        current.2.clone()
+    }
+}
+
+async fn serve_connection<S>(stream: S, peer: SocketAddr, args: Arc<Args>, state: Arc<SharedState>)
+where
+    S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+    let service = service_fn(|req| handle_http(req, peer, args.clone(), state.clone()));
+    if let Err(err) = http1::Builder::new()
+        .serve_connection(TokioIo::new(stream), service)
+        .await
+    {
+        warn!("HTTP connection error: {}", err);
+    }
+}
+
+/// Serve metrics, health and admin endpoints, and in reactive mode the webhook receivers. With
+/// --tls-cert-file and --tls-key-file, everything is served over HTTPS.
+pub async fn serve_http(
+    args: Arc<Args>,
+    state: Arc<SharedState>,
+) -> Result<(), Box<dyn Error + Send + Sync>> {
+    let tls = match (&args.tls_cert_file, &args.tls_key_file) {
+        (Some(cert), Some(key)) => Some(Arc::new(TlsReloader::new(cert, key)?)),
+        (None, None) => None,
+        _ => return Err("--tls-cert-file and --tls-key-file must be set together".into()),
+    };
+    if args.reactive && args.receiver_bearer_token.is_none() && args.receiver_username.is_none() {
+        warn!("Webhook receivers accept unauthenticated pushes, set --receiver-bearer-token or --receiver-username");
+    }
+    let listener = TcpListener::bind(&args.metrics_addr).await?;
+    info!(
+        "Serving metrics on {}{}",
+        args.metrics_addr,
+        if tls.is_some() { " over TLS" } else { "" }
+    );
+    loop {
+        let (stream, peer) = listener.accept().await?;
+        let (args, state) = (args.clone(), state.clone());
+        match &tls {
+            Some(tls) => {
+                let acceptor = tls.acceptor();
+                tokio::spawn(async move {
+                    match acceptor.accept(stream).await {
+                        Ok(stream) => serve_connection(stream, peer, args, state).await,
+                        Err(err) => warn!("TLS handshake with {} failed: {}", peer, err),
+                    }
+                });
+            }
+            None => {
+                tokio::spawn(serve_connection(stream, peer, args, state));
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn alert(fingerprint: &str) -> Alert {
+        serde_json::from_value(json!({
+            "fingerprint": fingerprint,
+            "status": { "state": "active" },
+            "labels": { "alertname": "A" },
+        }))
+        .unwrap()
+    }
+
+    fn fingerprints(queue: &[Alert]) -> Vec<&str> {
+        queue
+            .iter()
+            .map(|alert| alert.fingerprint.as_str())
+            .collect()
+    }
+
+    #[test]
+    fn push_queue_drops_the_oldest_beyond_its_cap() {
+        let mut queue = Vec::new();
+        assert_eq!(queue_pushed(&mut queue, vec![alert("a"), alert("b")], 3), 0);
+        assert_eq!(fingerprints(&queue), ["a", "b"]);
+        assert_eq!(queue_pushed(&mut queue, vec![alert("c"), alert("d")], 3), 1);
+        assert_eq!(fingerprints(&queue), ["b", "c", "d"]);
+        // A single push larger than the cap keeps only its newest alerts
+        let burst = (0..5).map(|i| alert(&format!("e{}", i))).collect();
+        assert_eq!(queue_pushed(&mut queue, burst, 3), 5);
+        assert_eq!(fingerprints(&queue), ["e2", "e3", "e4"]);
+    }
+}
@@ -0,0 +1,233 @@
+//! Offline replay of a captured alert list through rule matching and the action guards, to see
+//! what the controller would do without touching any cluster
+
+use k8s_openapi::chrono::{DateTime, Utc};
+use kube::ResourceExt;
+use serde::Deserialize;
+use std::sync::Arc;
+
+use crate::{
+    actions::{is_known_action, pod_guards_enabled, protected_namespace},
+    alertmanager::Alert,
+    config::Args,
+    rules::{
+        find_rule, outside_windows, required_confirmations, requires_approval, stable_hash,
+        target_key, AlertRemediationRule,
+    },
+};
+
+/// What the controller would do with one alert
+#[derive(Debug, Clone, PartialEq)]
+pub struct Decision {
+    pub fingerprint: String,
+    pub alertname: String,
+    /// Matching rule as namespace/name
+    pub rule: Option<String>,
+    pub action: Option<String>,
+    pub target: Option<String>,
+    /// act, ignored, skipped, refused, deduplicated, approval or error
+    pub outcome: &'static str,
+    /// Why, or for actions that would run, what they still depend on
+    pub reason: String,
+}
+
+impl std::fmt::Display for Decision {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(
+            f,
+            "{} {}: {}",
+            self.fingerprint, self.alertname, self.outcome
+        )?;
+        if let Some(rule) = &self.rule {
+            write!(f, " (rule {})", rule)?;
+        }
+        if let (Some(action), Some(target)) = (&self.action, &self.target) {
+            write!(f, " {} on {}", action, target)?;
+        }
+        if !self.reason.is_empty() {
+            write!(f, " - {}", self.reason)?;
+        }
+        Ok(())
+    }
+}
+
+/// Load the AlertRemediationRules of a multi-document YAML file, ignoring other kinds
+pub fn load_rules_file(path: &str) -> Result<Vec<AlertRemediationRule>, String> {
+    let text = std::fs::read_to_string(path).map_err(|err| format!("{}: {}", path, err))?;
+    let mut rules = Vec::new();
+    for (index, document) in serde_yaml::Deserializer::from_str(&text).enumerate() {
+        let value = serde_yaml::Value::deserialize(document)
+            .map_err(|err| format!("{}: document {}: {}", path, index + 1, err))?;
+        if value["kind"].as_str() != Some("AlertRemediationRule") {
+            continue;
+        }
+        let rule = serde_yaml::from_value(value).map_err(|err| {
+            format!(
+                "{}: document {}: {} (run validate for details)",
+                path,
+                index + 1,
+                err
+            )
+        })?;
+        rules.push(rule);
+    }
+    Ok(rules)
+}
+
+/// Decide on every alert the way a poll cycle would, assuming a fresh controller that leads all
+/// shards and has no cooldowns, acknowledgements, flap suppression, open circuits or pause. The
+/// checks made against live objects (opt-in, pod age, preconditions, RBAC) are only mentioned.
+pub fn simulate(
+    args: &Args,
+    rules: &[Arc<AlertRemediationRule>],
+    controller_namespace: &str,
+    alerts: Vec<Alert>,
+    at: DateTime<Utc>,
+) -> Vec<Decision> {
+    let mut decisions: Vec<Decision> = Vec::new();
+    for mut alert in alerts {
+        let mut decision = Decision {
+            fingerprint: alert.fingerprint.clone(),
+            alertname: alert.labels.alertname.clone(),
+            rule: None,
+            action: None,
+            target: None,
+            outcome: "error",
+            reason: String::new(),
+        };
+        let decided = decide(
+            args,
+            rules,
+            controller_namespace,
+            &mut alert,
+            at,
+            &decisions,
+            &mut decision,
+        );
+        decision.reason = decided.unwrap_or_else(|err| err);
+        decisions.push(decision);
+    }
+    decisions
+}
+
+/// Fill in the decision for one alert, returning its reason. Errors leave the outcome at
+/// 'error' and are the reason.
+fn decide(
+    args: &Args,
+    rules: &[Arc<AlertRemediationRule>],
+    controller_namespace: &str,
+    alert: &mut Alert,
+    at: DateTime<Utc>,
+    earlier: &[Decision],
+    decision: &mut Decision,
+) -> Result<String, String> {
+    alert.original_labels = alert.labels.to_map();
+    if alert.labels.cluster.is_none() {
+        alert.labels.cluster = args.cluster_name.clone();
+    }
+    alert
+        .apply_target_paths(
+            args.target_paths
+                .iter()
+                .filter_map(|entry| entry.split_once('=')),
+        )
+        .map_err(|err| format!("failed to resolve target paths: {}", err))?;
+    let rule = find_rule(rules, alert, controller_namespace);
+    decision.rule = rule.as_ref().map(|rule| {
+        format!(
+            "{}/{}",
+            rule.namespace().unwrap_or_default(),
+            rule.name_any()
+        )
+    });
+    if rule.is_none() && !args.alert_names.contains(&alert.labels.alertname) {
+        decision.outcome = "ignored";
+        return Ok("no rule or --alert-names entry matches".to_string());
+    }
+    if alert.status.state != "active" {
+        decision.outcome = "ignored";
+        return Ok(format!("not active ({})", alert.status.state));
+    }
+    if args.canary_percent < 100
+        && stable_hash(&alert.labels, &args.hash_labels) % 100 >= args.canary_percent
+    {
+        decision.outcome = "skipped";
+        return Ok(format!(
+            "outside the {}% canary sample",
+            args.canary_percent
+        ));
+    }
+    alert
+        .labels
+        .fill_from_annotations(&alert.annotations)
+        .map_err(|err| format!("failed to read parameters from annotations: {}", err))?;
+    if let Some(rule) = &rule {
+        rule.apply(alert, controller_namespace)
+            .map_err(|err| format!("failed to apply rule: {}", err))?;
+    }
+    let action = alert.labels.action.as_deref().unwrap_or("delete_pod");
+    let target = target_key(alert);
+    decision.action = Some(action.to_string());
+    decision.target = Some(target.clone());
+    if !is_known_action(action) {
+        return Err(format!("unknown action '{}'", action));
+    }
+    let confirmations = required_confirmations(args, &alert.labels)?;
+    if outside_windows(args, &alert.labels, at)? {
+        decision.outcome = "skipped";
+        return Ok("outside of its action windows".to_string());
+    }
+    if let Some(namespace) = protected_namespace(args, action, &alert.labels) {
+        decision.outcome = "refused";
+        return Ok(format!("namespace {} is protected", namespace));
+    }
+    if let Some(first) = earlier.iter().find(|earlier| {
+        earlier.outcome == "act"
+            && earlier.target.as_deref() == Some(&target)
+            && earlier.action.as_deref() == Some(action)
+    }) {
+        decision.outcome = "deduplicated";
+        return Ok(format!("already planned for alert {}", first.fingerprint));
+    }
+    if requires_approval(args, &alert.labels, action) && !args.dry_run {
+        decision.outcome = "approval";
+        return Ok("held until its PendingRemediation is approved".to_string());
+    }
+    decision.outcome = "act";
+    let mut conditions = Vec::new();
+    if confirmations > 1 {
+        conditions.push(format!(
+            "once firing in {} consecutive polls",
+            confirmations
+        ));
+    }
+    if matches!(action, "delete_pod" | "delete_pods_by_selector")
+        && pod_guards_enabled(args, &alert.labels)
+    {
+        conditions.push("if the live pod passes its guards".to_string());
+    }
+    if args.dry_run {
+        conditions.push("as a dry run".to_string());
+    }
+    Ok(conditions.join(", "))
+}
+
+/// Simulate the alerts of a captured `/api/v2/alerts` response against the rules of the given
+/// files
+pub fn simulate_files(
+    args: &Args,
+    alerts_file: &str,
+    rules_files: &[String],
+    controller_namespace: &str,
+    at: DateTime<Utc>,
+) -> Result<Vec<Decision>, String> {
+    let text =
+        std::fs::read_to_string(alerts_file).map_err(|err| format!("{}: {}", alerts_file, err))?;
+    let alerts: Vec<Alert> =
+        serde_json::from_str(&text).map_err(|err| format!("{}: {}", alerts_file, err))?;
+    let mut rules = Vec::new();
+    for path in rules_files {
+        rules.extend(load_rules_file(path)?.into_iter().map(Arc::new));
+    }
+    Ok(simulate(args, &rules, controller_namespace, alerts, at))
+}
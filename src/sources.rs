@@ -0,0 +1,224 @@
+//! Alert sources besides Alertmanager, turning query results into synthetic alerts
+
+use k8s_openapi::chrono::Utc;
+use log::{error, warn};
+use reqwest::{Client as HttpClient, RequestBuilder};
+use serde_json::{json, Value};
+use std::{
+    collections::{BTreeMap, HashMap},
+    error::Error,
+};
+
+use crate::{
+    alertmanager::{Alert, AlertStatus, Labels},
+    config::Args,
+    notify::resolve_secret,
+    rules::stable_hash,
+};
+
+/// Fingerprint prefixes of alerts with no counterpart in --alertmanager-url to silence: synthetic
+/// ones and those managed by Grafana
+pub const SYNTHETIC_PREFIXES: [&str; 4] = ["kube-state/", "prometheus/", "loki/", "grafana/"];
+
+/// Whether the alert was made up by one of our own sources or came from Grafana rather than
+/// Alertmanager
+pub fn is_synthetic(alert: &Alert) -> bool {
+    SYNTHETIC_PREFIXES
+        .iter()
+        .any(|prefix| alert.fingerprint.starts_with(prefix))
+}
+
+/// Build a firing synthetic alert from a result's labels, named after the query it came from
+fn synthetic_alert(
+    source: &str,
+    alertname: &str,
+    mut labels: BTreeMap<String, String>,
+    annotations: HashMap<String, String>,
+) -> Result<Alert, Box<dyn Error>> {
+    labels.remove("__name__");
+    labels.insert("alertname".to_string(), alertname.to_string());
+    let labels: Labels = serde_json::from_value(json!(labels))?;
+    let now = Utc::now().to_rfc3339();
+    Ok(Alert {
+        fingerprint: format!(
+            "{}/{}/{:016x}",
+            source,
+            alertname,
+            stable_hash(&labels, &[])
+        ),
+        status: AlertStatus {
+            state: "active".to_string(),
+            silenced_by: Vec::new(),
+            inhibited_by: Vec::new(),
+        },
+        labels,
+        annotations,
+        starts_at: Some(now.clone()),
+        ends_at: None,
+        updated_at: Some(now),
+        generator_url: None,
+        receivers: Vec::new(),
+        original_labels: BTreeMap::new(),
+    })
+}
+
+/// Evaluate one instant query returning a Prometheus-style vector, as Prometheus and Loki do;
+/// every series in the result is a firing alert
+pub async fn query_vector(
+    request: RequestBuilder,
+    source: &str,
+    alertname: &str,
+    query: &str,
+) -> Result<Vec<Alert>, Box<dyn Error>> {
+    let body: Value = request
+        .query(&[("query", query)])
+        .send()
+        .await?
+        .error_for_status()?
+        .json()
+        .await?;
+    if body["status"] != "success" {
+        return Err(format!("query failed: {}", body["error"]).into());
+    }
+    let data = &body["data"];
+    // Conditions are written as filters (`... > 5`), so only vectors name what to act on
+    if data["resultType"] != "vector" {
+        return Err(format!("expected a vector result, got {}", data["resultType"]).into());
+    }
+    data["result"]
+        .as_array()
+        .into_iter()
+        .flatten()
+        .map(|sample| {
+            let labels: BTreeMap<String, String> =
+                serde_json::from_value(sample["metric"].clone())?;
+            let value = sample["value"][1].as_str().unwrap_or_default().to_string();
+            let annotations = HashMap::from([
+                ("value".to_string(), value),
+                ("query".to_string(), query.to_string()),
+            ]);
+            synthetic_alert(source, alertname, labels, annotations)
+        })
+        .collect()
+}
+
+/// Evaluate ';' separated 'AlertName=query' entries with requests from `request`. A failing
+/// query is logged and contributes no alerts; the call only fails when every query did.
+async fn evaluate_queries(
+    source: &str,
+    entries: &[String],
+    request: impl Fn() -> Result<RequestBuilder, Box<dyn Error>>,
+) -> Result<Vec<Alert>, Box<dyn Error>> {
+    let mut alerts = Vec::new();
+    let mut failed = 0;
+    for entry in entries {
+        let Some((alertname, query)) = entry.split_once('=') else {
+            return Err(
+                format!("invalid {} query '{}', expected name=query", source, entry).into(),
+            );
+        };
+        let result = match request() {
+            Ok(request) => query_vector(request, source, alertname.trim(), query.trim()).await,
+            Err(err) => Err(err),
+        };
+        match result {
+            Ok(mut results) => alerts.append(&mut results),
+            Err(err) => {
+                error!("{} query {} failed: {}", source, alertname, err);
+                failed += 1;
+            }
+        }
+    }
+    if failed > 0 && failed == entries.len() {
+        return Err(format!("every {} query failed", source).into());
+    }
+    Ok(alerts)
+}
+
+/// Evaluate every --prometheus-queries entry against --prometheus-url
+pub async fn get_prometheus_alerts(
+    args: &Args,
+    http_client: &HttpClient,
+) -> Result<Vec<Alert>, Box<dyn Error>> {
+    let Some(url) = &args.prometheus_url else {
+        return Err("--prometheus-queries needs --prometheus-url".into());
+    };
+    let endpoint = format!("{}/api/v1/query", url.trim_end_matches('/'));
+    evaluate_queries("prometheus", &args.prometheus_queries, || {
+        let mut request = http_client.get(&endpoint);
+        if let Some(token) = &args.prometheus_bearer_token {
+            request = request.bearer_auth(resolve_secret(token)?);
+        }
+        Ok(request)
+    })
+    .await
+}
+
+/// Evaluate every --loki-queries entry (LogQL metric queries) against --loki-url
+pub async fn get_loki_alerts(
+    args: &Args,
+    http_client: &HttpClient,
+) -> Result<Vec<Alert>, Box<dyn Error>> {
+    let Some(url) = &args.loki_url else {
+        return Err("--loki-queries needs --loki-url".into());
+    };
+    let endpoint = format!("{}/loki/api/v1/query", url.trim_end_matches('/'));
+    evaluate_queries("loki", &args.loki_queries, || {
+        let mut request = http_client.get(&endpoint);
+        if let Some(token) = &args.loki_bearer_token {
+            request = request.bearer_auth(resolve_secret(token)?);
+        }
+        // Multi-tenant Loki needs to be told whose logs to query
+        if let Some(tenant) = &args.loki_tenant {
+            request = request.header("X-Scope-OrgID", tenant);
+        }
+        Ok(request)
+    })
+    .await
+}
+
+/// Mark an alert as Grafana-managed, so it isn't silenced in Alertmanager and can't collide with
+/// an Alertmanager alert of the same fingerprint
+pub fn grafana_alert(mut alert: Alert) -> Alert {
+    if !alert.fingerprint.starts_with("grafana/") {
+        alert.fingerprint = format!("grafana/{}", alert.fingerprint);
+    }
+    alert
+}
+
+/// Fetch active, unsilenced, uninhibited Grafana-managed alerts from Grafana's built-in
+/// Alertmanager at --grafana-url
+pub async fn get_grafana_alerts(
+    args: &Args,
+    http_client: &HttpClient,
+) -> Result<Vec<Alert>, Box<dyn Error>> {
+    let Some(url) = &args.grafana_url else {
+        return Err("--grafana-url is not set".into());
+    };
+    let mut request = http_client
+        .get(format!(
+            "{}/api/alertmanager/grafana/api/v2/alerts",
+            url.trim_end_matches('/')
+        ))
+        .query(&[
+            ("active", "true"),
+            ("silenced", "false"),
+            ("inhibited", "false"),
+        ]);
+    if let Some(token) = &args.grafana_token {
+        request = request.bearer_auth(resolve_secret(token)?);
+    }
+    if let Some(org_id) = &args.grafana_org_id {
+        request = request.header("X-Grafana-Org-Id", org_id);
+    }
+    let mut alerts: Vec<Alert> = request.send().await?.error_for_status()?.json().await?;
+    if alerts.len() > args.max_alerts {
+        warn!(
+            "Grafana returned {} alerts, only handling the first {}",
+            alerts.len(),
+            args.max_alerts
+        );
+        alerts.truncate(args.max_alerts);
+    }
+    Ok(alerts.into_iter().map(grafana_alert).collect())
+}
@@ -0,0 +1,295 @@
+//! State shared across a cycle and between the main loop and the HTTP server
+
+use anyhow::Result;
+use k8s_openapi::api::core::v1::ConfigMap;
+use kube::{
+    api::{Patch, PatchParams},
+    runtime::reflector,
+    Api, Client,
+};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use std::{
+    collections::HashMap,
+    error::Error,
+    future::Future,
+    net::IpAddr,
+    sync::{
+        atomic::{AtomicBool, AtomicU64},
+        Arc, Mutex,
+    },
+    time::{Instant, SystemTime},
+};
+use tokio::sync::{watch, Notify};
+
+use crate::{
+    actions::TrackedTaint,
+    alertmanager::{Alert, TrackedSilence},
+    trace::Trace,
+};
+
+/// Execution context of one poll cycle, threaded from polling through matching to every action
+#[derive(Clone)]
+pub struct ExecContext {
+    /// Every Kubernetes and HTTP call made for this cycle must finish by this instant
+    pub deadline: tokio::time::Instant,
+    /// Ties together the log lines and audit records of one cycle
+    pub correlation_id: String,
+    /// Log what would be done instead of doing it
+    pub dry_run: bool,
+    /// Reports which shard leases we still hold
+    pub leadership: watch::Receiver<Vec<bool>>,
+    /// Replica acting in this cycle, recorded in audit records
+    pub identity: String,
+    /// Spans recorded for this cycle
+    pub trace: Arc<Trace>,
+}
+
+impl ExecContext {
+    /// Run a call like `run`, recording it as a span of the cycle's trace
+    pub async fn traced<T>(
+        &self,
+        name: &str,
+        attributes: &[(&str, &str)],
+        call: impl Future<Output = Result<T, Box<dyn Error>>>,
+    ) -> Result<T, Box<dyn Error>> {
+        let start = SystemTime::now();
+        let result = self.run(call).await;
+        let error = result.as_ref().err().map(|err| err.to_string());
+        self.trace.record(name, start, attributes, error);
+        result
+    }
+
+    /// Run a call on behalf of this cycle, abandoning it at the deadline or on leadership loss
+    pub async fn run<T>(
+        &self,
+        call: impl Future<Output = Result<T, Box<dyn Error>>>,
+    ) -> Result<T, Box<dyn Error>> {
+        let mut leadership = self.leadership.clone();
+        tokio::select! {
+            result = tokio::time::timeout_at(self.deadline, call) => {
+                result.map_err(|_| format!("cycle {} deadline exceeded", self.correlation_id))?
+            }
+            _ = leadership.wait_for(|owned| !owned.contains(&true)) => {
+                Err(format!("cycle {} cancelled, leadership lost", self.correlation_id).into())
+            }
+        }
+    }
+}
+
+/// Runtime state shared between the main loop and the HTTP server
+#[derive(Default)]
+pub struct SharedState {
+    /// Emergency mode (short cooldowns, no flap suppression) is active until this instant
+    pub emergency_until: Mutex<Option<Instant>>,
+    /// Fingerprints handled externally, left alone until the given instant
+    pub acknowledged: Mutex<HashMap<String, Instant>>,
+    /// Set while the Kubernetes API server can't be reached, which makes the pod unready
+    pub kube_unavailable: AtomicBool,
+    /// Polls in a row in which no Alertmanager could be reached
+    pub poll_failures: AtomicU64,
+    /// Alerts pushed by Alertmanager in reactive mode, waiting for the main loop
+    pub pushed: Mutex<Vec<Alert>>,
+    pub pushed_notify: Notify,
+    /// Push tokens left per source address and when they were last refilled, for rate limiting
+    pub push_buckets: Mutex<HashMap<IpAddr, (f64, Instant)>>,
+}
+
+impl SharedState {
+    pub fn emergency_active(&self) -> bool {
+        self.emergency_until
+            .lock()
+            .unwrap()
+            .is_some_and(|until| Instant::now() < until)
+    }
+}
+
+/// Failure tracking of one downstream for the circuit breaker
+#[derive(Debug, Default)]
+pub struct Circuit {
+    /// Failed actions in a row
+    pub failures: u32,
+    /// Actions are skipped until this instant once the circuit opened
+    pub open_until: Option<Instant>,
+}
+
+/// Key of the pause ConfigMap that suspends all actions while set to "true"
+pub const PAUSED_KEY: &str = "paused";
+
+/// Whether the watched pause ConfigMap currently suspends all actions
+pub fn is_paused(store: Option<&reflector::Store<ConfigMap>>) -> bool {
+    store.is_some_and(|store| {
+        store.state().iter().any(|configmap| {
+            configmap
+                .data
+                .as_ref()
+                .and_then(|data| data.get(PAUSED_KEY))
+                .is_some_and(|paused| paused == "true")
+        })
+    })
+}
+
+/// Temporary mitigations whose timers outlive a replica: silences to lift once their alert
+/// resolves and taints to take off once they expire
+#[derive(Debug, Clone, Default, Deserialize, Serialize, PartialEq)]
+pub struct Mitigations {
+    #[serde(default)]
+    pub silences: Vec<TrackedSilence>,
+    #[serde(default)]
+    pub taints: Vec<TrackedTaint>,
+}
+
+impl Mitigations {
+    /// Track a taint, replacing an earlier one with the same node, key and effect so only the
+    /// latest expiry counts
+    pub fn track_taint(&mut self, taint: TrackedTaint) {
+        self.taints.retain(|known| {
+            (&known.cluster, &known.node, &known.key, &known.effect)
+                != (&taint.cluster, &taint.node, &taint.key, &taint.effect)
+        });
+        self.taints.push(taint);
+    }
+
+    /// Add mitigations recorded by another replica, keeping ours where both know one
+    pub fn merge(&mut self, other: Mitigations) {
+        for silence in other.silences {
+            if !self.silences.iter().any(|known| known.id == silence.id) {
+                self.silences.push(silence);
+            }
+        }
+        for taint in other.taints {
+            if !self.taints.iter().any(|known| {
+                (&known.cluster, &known.node, &known.key, &known.effect)
+                    == (&taint.cluster, &taint.node, &taint.key, &taint.effect)
+            }) {
+                self.taints.push(taint);
+            }
+        }
+    }
+}
+
+/// ConfigMap key holding a shard's mitigations. Only the shard's leader writes it, so replicas
+/// leading other shards never overwrite each other.
+fn mitigations_key(shard: usize) -> String {
+    format!("mitigations-{}.json", shard)
+}
+
+/// Server-side apply of one shard's key, under a field manager of its own so the keys of other
+/// shards are left alone
+fn mitigations_patch(
+    name: &str,
+    shard: usize,
+    mitigations: &Mitigations,
+) -> Result<(PatchParams, serde_json::Value), Box<dyn Error>> {
+    let configmap = json!({
+        "apiVersion": "v1",
+        "kind": "ConfigMap",
+        "metadata": { "name": name },
+        "data": { mitigations_key(shard): serde_json::to_string(mitigations)? },
+    });
+    let params = PatchParams::apply(&format!("alert-actor-mitigations-{}", shard)).force();
+    Ok((params, configmap))
+}
+
+/// Read the mitigations recorded for a shard, if any
+pub async fn load_mitigations(
+    client: Client,
+    name: &str,
+    shard: usize,
+) -> Result<Mitigations, Box<dyn Error>> {
+    let configmaps: Api<ConfigMap> = Api::default_namespaced(client);
+    let saved = configmaps
+        .get_opt(name)
+        .await?
+        .and_then(|configmap| configmap.data?.remove(&mitigations_key(shard)));
+    match saved {
+        Some(saved) => Ok(serde_json::from_str(&saved)?),
+        None => Ok(Mitigations::default()),
+    }
+}
+
+/// Record a shard's current mitigations, replacing what the ConfigMap held for it
+pub async fn save_mitigations(
+    client: Client,
+    name: &str,
+    shard: usize,
+    mitigations: &Mitigations,
+) -> Result<(), Box<dyn Error>> {
+    let configmaps: Api<ConfigMap> = Api::default_namespaced(client);
+    let (params, configmap) = mitigations_patch(name, shard, mitigations)?;
+    configmaps
+        .patch(name, &params, &Patch::Apply(&configmap))
+        .await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn silence(id: &str) -> TrackedSilence {
+        TrackedSilence {
+            id: id.to_string(),
+            fingerprint: format!("fp-{}", id),
+            labels: Default::default(),
+            created_at: 0,
+            ends_at: 600,
+        }
+    }
+
+    fn taint(node: &str, expires_at: i64) -> TrackedTaint {
+        TrackedTaint {
+            cluster: None,
+            node: node.to_string(),
+            key: "alert-actor/investigate".to_string(),
+            effect: "NoSchedule".to_string(),
+            expires_at,
+        }
+    }
+
+    #[test]
+    fn merge_keeps_ours_and_adds_theirs() {
+        let mut ours = Mitigations {
+            silences: vec![silence("a")],
+            taints: vec![taint("node-1", 100)],
+        };
+        let mut stored_silence = silence("a");
+        stored_silence.ends_at = 1;
+        ours.merge(Mitigations {
+            silences: vec![stored_silence, silence("b")],
+            taints: vec![taint("node-1", 50), taint("node-2", 50)],
+        });
+        assert_eq!(ours.silences, [silence("a"), silence("b")]);
+        assert_eq!(ours.taints, [taint("node-1", 100), taint("node-2", 50)]);
+    }
+
+    #[test]
+    fn track_taint_keeps_the_latest_expiry() {
+        let mut mitigations = Mitigations::default();
+        mitigations.track_taint(taint("node-1", 100));
+        mitigations.track_taint(taint("node-2", 100));
+        mitigations.track_taint(taint("node-1", 200));
+        assert_eq!(
+            mitigations.taints,
+            [taint("node-2", 100), taint("node-1", 200)]
+        );
+    }
+
+    #[test]
+    fn shards_save_to_their_own_key_and_field_manager() {
+        let mitigations = Mitigations {
+            silences: vec![silence("a")],
+            taints: vec![],
+        };
+        let (params, configmap) = mitigations_patch("mitigations", 2, &mitigations).unwrap();
+        assert_eq!(
+            params.field_manager.as_deref(),
+            Some("alert-actor-mitigations-2")
+        );
+        let data = configmap["data"].as_object().unwrap();
+        assert_eq!(data.keys().collect::<Vec<_>>(), ["mitigations-2.json"]);
+        let saved: Mitigations =
+            serde_json::from_str(data["mitigations-2.json"].as_str().unwrap()).unwrap();
+        assert_eq!(saved, mitigations);
+    }
+}
@@ -0,0 +1,131 @@
+//! OTLP traces of poll cycles
+
+use anyhow::Result;
+use serde_json::{json, Value};
+use std::{
+    collections::hash_map::RandomState, error::Error, hash::BuildHasher, sync::Mutex,
+    time::SystemTime,
+};
+
+use crate::notify::notification_client;
+
+/// Random lowercase hex id of `bytes` length (a multiple of 8), as used for trace and span ids
+pub fn random_hex_id(bytes: usize) -> String {
+    (0..bytes / 8)
+        .map(|i| format!("{:016x}", RandomState::new().hash_one(i)))
+        .collect()
+}
+
+pub fn unix_nanos(time: SystemTime) -> String {
+    time.duration_since(std::time::UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_nanos()
+        .to_string()
+}
+
+/// One poll cycle as a trace: a root span for the cycle with a child span for every Alertmanager,
+/// Kubernetes and HTTP call, kept as OTLP JSON spans until the cycle is exported
+pub struct Trace {
+    trace_id: String,
+    root_span_id: String,
+    start: SystemTime,
+    spans: Mutex<Vec<Value>>,
+}
+
+impl Default for Trace {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Trace {
+    pub fn new() -> Self {
+        Self {
+            trace_id: random_hex_id(16),
+            root_span_id: random_hex_id(8),
+            start: SystemTime::now(),
+            spans: Mutex::new(Vec::new()),
+        }
+    }
+
+    pub fn span(
+        &self,
+        span_id: &str,
+        parent_span_id: &str,
+        name: &str,
+        start: SystemTime,
+        attributes: &[(&str, &str)],
+        error: Option<String>,
+    ) -> Value {
+        let attributes: Vec<Value> = attributes
+            .iter()
+            .map(|(key, value)| json!({ "key": key, "value": { "stringValue": value } }))
+            .collect();
+        let status = match error {
+            Some(message) => json!({ "code": 2, "message": message }),
+            None => json!({ "code": 1 }),
+        };
+        json!({
+            "traceId": self.trace_id,
+            "spanId": span_id,
+            "parentSpanId": parent_span_id,
+            "name": name,
+            "kind": 1,
+            "startTimeUnixNano": unix_nanos(start),
+            "endTimeUnixNano": unix_nanos(SystemTime::now()),
+            "attributes": attributes,
+            "status": status,
+        })
+    }
+
+    /// Record a finished child span of the cycle
+    pub fn record(
+        &self,
+        name: &str,
+        start: SystemTime,
+        attributes: &[(&str, &str)],
+        error: Option<String>,
+    ) {
+        let span = self.span(
+            &random_hex_id(8),
+            &self.root_span_id,
+            name,
+            start,
+            attributes,
+            error,
+        );
+        self.spans.lock().unwrap().push(span);
+    }
+
+    /// Close the cycle's root span and send the trace to an OTLP/HTTP collector
+    pub async fn export(&self, endpoint: &str, correlation_id: &str) -> Result<(), Box<dyn Error>> {
+        let root = self.span(
+            &self.root_span_id,
+            "",
+            "poll_cycle",
+            self.start,
+            &[("alert_actor.correlation_id", correlation_id)],
+            None,
+        );
+        let mut spans = std::mem::take(&mut *self.spans.lock().unwrap());
+        spans.push(root);
+        let request = json!({
+            "resourceSpans": [{
+                "resource": {
+                    "attributes": [
+                        { "key": "service.name", "value": { "stringValue": "alert-actor" } },
+                        { "key": "service.version", "value": { "stringValue": env!("CARGO_PKG_VERSION") } },
+                    ],
+                },
+                "scopeSpans": [{ "scope": { "name": "alert-actor" }, "spans": spans }],
+            }],
+        });
+        notification_client()
+            .post(format!("{}/v1/traces", endpoint.trim_end_matches('/')))
+            .json(&request)
+            .send()
+            .await?
+            .error_for_status()?;
+        Ok(())
+    }
+}
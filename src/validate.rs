@@ -0,0 +1,408 @@
+//! Offline checks of the flags and of rule files, so CI can catch mistakes before they reach a
+//! cluster
+
+use k8s_openapi::chrono::Utc;
+use kube::ResourceExt;
+use serde_json::Value;
+use std::collections::BTreeMap;
+
+use crate::{
+    actions::is_known_action,
+    config::{logger, Args},
+    expr::{json_path, Expr},
+    leadership::LeaseTiming,
+    notify::{
+        ca_certificates, check_email_address, label_webhook_headers, proxy, resolve_secret,
+        webhook_headers,
+    },
+    rules::AlertRemediationRule,
+    schedule::cron_matches,
+};
+
+/// Check that every ';' or ',' separated 'name=value' entry of a flag has both parts
+fn check_entries(flag: &str, entries: &[String], errors: &mut Vec<String>) {
+    for entry in entries {
+        if !entry
+            .split_once('=')
+            .is_some_and(|(name, value)| !name.trim().is_empty() && !value.trim().is_empty())
+        {
+            errors.push(format!(
+                "{}: invalid entry '{}', expected name=value",
+                flag, entry
+            ));
+        }
+    }
+}
+
+/// Mistakes in the flags that would stop the controller or make its actions fail, without
+/// connecting anywhere. Secrets are not resolved here, see `check_secrets`.
+pub fn check_args(args: &Args) -> Vec<String> {
+    let mut errors = Vec::new();
+    if let Err(err) = logger(&args.log_level) {
+        errors.push(format!("--log-level: {}", err));
+    }
+    for (flag, windows) in [
+        ("--quiet-windows", &args.quiet_windows),
+        ("--active-windows", &args.active_windows),
+    ] {
+        for window in windows {
+            if let Err(err) = cron_matches(window, Utc::now()) {
+                errors.push(format!("{}: '{}': {}", flag, window, err));
+            }
+        }
+    }
+    if args.pod_name.is_some() && !args.no_leader_election {
+        if let Err(err) = LeaseTiming::from_args(args) {
+            errors.push(format!("lease timing: {}", err));
+        }
+    }
+    if !["warn", "fail", "off"].contains(&args.rbac_preflight.as_str()) {
+        errors.push(format!(
+            "--rbac-preflight: unknown mode '{}', expected warn, fail or off",
+            args.rbac_preflight
+        ));
+    }
+    if !args.prometheus_queries.is_empty() && args.prometheus_url.is_none() {
+        errors.push("--prometheus-queries needs --prometheus-url".to_string());
+    }
+    if !args.loki_queries.is_empty() && args.loki_url.is_none() {
+        errors.push("--loki-queries needs --loki-url".to_string());
+    }
+    if args.tls_cert_file.is_some() != args.tls_key_file.is_some() {
+        errors.push("--tls-cert-file and --tls-key-file must be set together".to_string());
+    }
+    check_entries(
+        "--prometheus-queries",
+        &args.prometheus_queries,
+        &mut errors,
+    );
+    check_entries("--loki-queries", &args.loki_queries, &mut errors);
+    check_entries("--clusters", &args.clusters, &mut errors);
+    check_entries("--commands", &args.commands, &mut errors);
+    check_entries("--job-templates", &args.job_templates, &mut errors);
+    check_entries("--target-paths", &args.target_paths, &mut errors);
+    for (name, path) in args.target_paths.iter().filter_map(|e| e.split_once('=')) {
+        if let Err(err) = json_path(&Value::Null, path.trim()) {
+            errors.push(format!("--target-paths: {}: {}", name.trim(), err));
+        }
+    }
+    for (name, path) in args.job_templates.iter().filter_map(|e| e.split_once('=')) {
+        if let Err(err) = std::fs::read_to_string(path) {
+            errors.push(format!("--job-templates: {}: {}: {}", name, path, err));
+        }
+    }
+    if let Some(path) = &args.ca_cert {
+        if let Err(err) = ca_certificates(path) {
+            errors.push(format!("--ca-cert: {}", err));
+        }
+    }
+    for (flag, url) in [
+        ("--alertmanager-proxy", &args.alertmanager_proxy),
+        ("--notification-proxy", &args.notification_proxy),
+    ] {
+        if let Some(err) = url.as_deref().and_then(|url| proxy(args, url).err()) {
+            errors.push(format!("{}: {}", flag, err));
+        }
+    }
+    for (flag, address) in std::iter::once(("--email-from", &args.email_from))
+        .chain(args.email_to.iter().map(|to| ("--email-to", to)))
+    {
+        if let Err(err) = check_email_address(address) {
+            errors.push(format!("{}: {}", flag, err));
+        }
+    }
+    if args.expire_resolved_silences && args.silence_duration_secs == 0 {
+        errors.push("--expire-resolved-silences needs --silence-duration-secs".to_string());
+    }
+    if args.smtp_username.is_some() && !args.smtp_tls && !args.smtp_starttls {
+        errors.push("--smtp-username needs --smtp-tls or --smtp-starttls".to_string());
+    }
+    if args.taint_effect == "NoExecute" && !args.allow_noexecute_taints {
+        errors.push("--taint-effect: NoExecute needs --allow-noexecute-taints".to_string());
+    }
+    for channel in &args.digest_channels {
+        let missing = match channel.as_str() {
+            "log" => None,
+            "webhook" => args
+                .digest_webhook_url
+                .is_none()
+                .then_some("--digest-webhook-url"),
+            "slack" => args
+                .slack_webhook_url
+                .is_none()
+                .then_some("--slack-webhook-url"),
+            other => {
+                errors.push(format!("--digest-channels: unknown channel '{}'", other));
+                None
+            }
+        };
+        if let Some(flag) = missing {
+            errors.push(format!("--digest-channels: {} needs {}", channel, flag));
+        }
+    }
+    if let Some(path) = &args.webhook_template_file {
+        if let Err(err) = std::fs::read_to_string(path) {
+            errors.push(format!("--webhook-template-file: {}: {}", path, err));
+        }
+    }
+    errors
+}
+
+/// Resolve every configured 'env:' and 'file:' secret, reporting those that can't be read
+pub fn check_secrets(args: &Args) -> Vec<String> {
+    let secrets = [
+        ("--alertmanager-password", &args.alertmanager_password),
+        (
+            "--alertmanager-bearer-token",
+            &args.alertmanager_bearer_token,
+        ),
+        ("--prometheus-bearer-token", &args.prometheus_bearer_token),
+        ("--loki-bearer-token", &args.loki_bearer_token),
+        ("--grafana-token", &args.grafana_token),
+        ("--opsgenie-api-key", &args.opsgenie_api_key),
+        ("--smtp-password", &args.smtp_password),
+        ("--webhook-secret", &args.webhook_secret),
+        ("--admin-token", &args.admin_token),
+        ("--receiver-bearer-token", &args.receiver_bearer_token),
+        ("--receiver-password", &args.receiver_password),
+    ];
+    let mut errors: Vec<String> = secrets
+        .into_iter()
+        .filter_map(|(flag, secret)| Some((flag, secret.as_deref()?)))
+        .filter_map(|(flag, secret)| {
+            let err = resolve_secret(secret).err()?;
+            Some(format!("{}: {}", flag, err))
+        })
+        .collect();
+    if let Err(err) = webhook_headers(&args.webhook_headers) {
+        errors.push(format!("--webhook-headers: {}", err));
+    }
+    errors
+}
+
+/// Line (0-based, within `text`) of the first `key:` entry, to point at the field at fault
+fn field_line(text: &str, key: &str) -> Option<usize> {
+    text.lines().position(|line| {
+        line.trim_start()
+            .trim_start_matches("- ")
+            .strip_prefix(key)
+            .is_some_and(|rest| rest.starts_with(':'))
+    })
+}
+
+/// Mistakes in one rule, as the spec field at fault (e.g. `expression` or `labels.command`) and
+/// what is wrong with it
+pub fn check_rule(rule: &AlertRemediationRule, args: &Args) -> Vec<(&'static str, String)> {
+    let spec = &rule.spec;
+    let mut errors = Vec::new();
+    if !is_known_action(&spec.action) {
+        errors.push(("action", format!("unknown action '{}'", spec.action)));
+    }
+    if let Some(expression) = &spec.expression {
+        if let Err(err) = Expr::parse(expression)
+            .map_err(|err| err.to_string())
+            .and_then(|expr| expr.check())
+        {
+            // Regex errors draw a caret over several lines, keep it to one
+            let err = err.split_whitespace().collect::<Vec<_>>().join(" ");
+            errors.push(("expression", err));
+        }
+    }
+    for (name, path) in &spec.target_paths {
+        if let Err(err) = json_path(&Value::Null, path) {
+            errors.push(("targetPaths", format!("{}: {}", name, err)));
+        }
+    }
+    if spec.interval_secs == Some(0) {
+        errors.push(("intervalSecs", "must be positive".to_string()));
+    }
+    if spec.confirmations == Some(0) {
+        errors.push(("confirmations", "must be positive".to_string()));
+    }
+    for (field, policy) in [
+        ("propagationPolicy", spec.propagation_policy.as_ref()),
+        (
+            "labels.propagation_policy",
+            spec.labels.get("propagation_policy"),
+        ),
+    ] {
+        if let Some(policy) = policy {
+            if !["Background", "Foreground", "Orphan"].contains(&policy.as_str()) {
+                errors.push((
+                    field,
+                    format!(
+                        "unknown policy '{}', expected Background, Foreground or Orphan",
+                        policy
+                    ),
+                ));
+            }
+        }
+    }
+    // Scope checks depend on the controller's namespace, only the self-contained ones apply here
+    if let Err(err) = rule.impersonation(&rule.namespace().unwrap_or_default()) {
+        errors.push(("impersonateGroups", err.to_string()));
+    }
+    let labels = &spec.labels;
+    let registered = |entries: &[String], name: &str| {
+        entries
+            .iter()
+            .any(|entry| entry.split_once('=').is_some_and(|(n, _)| n == name))
+    };
+    if let Some(command) = labels.get("command") {
+        if !registered(&args.commands, command) {
+            errors.push((
+                "labels.command",
+                format!("'{}' is not in --commands", command),
+            ));
+        }
+    }
+    if let Some(template) = labels.get("job_template") {
+        if !registered(&args.job_templates, template) {
+            errors.push((
+                "labels.job_template",
+                format!("'{}' is not in --job-templates", template),
+            ));
+        }
+    }
+    for (window, field) in [
+        ("quiet_window", "labels.quiet_window"),
+        ("active_window", "labels.active_window"),
+    ] {
+        if let Some(expression) = labels.get(window) {
+            if let Err(err) = cron_matches(expression, Utc::now()) {
+                errors.push((field, err));
+            }
+        }
+    }
+    for (label, field) in [
+        ("action_timeout_secs", "labels.action_timeout_secs"),
+        ("taint_ttl_secs", "labels.taint_ttl_secs"),
+    ] {
+        if let Some(value) = labels.get(label) {
+            if value.parse::<u64>().is_err() {
+                errors.push((field, format!("invalid number of seconds '{}'", value)));
+            }
+        }
+    }
+    if let Some(headers) = labels.get("webhook_headers") {
+        if let Err(err) = label_webhook_headers(args, headers) {
+            errors.push(("labels.webhook_headers", err.to_string()));
+        }
+    }
+    if let Some(to) = labels.get("email_to") {
+        for recipient in to.split(',').map(str::trim) {
+            if let Err(err) = check_email_address(recipient) {
+                errors.push(("labels.email_to", err.to_string()));
+            }
+        }
+    }
+    if labels.get("taint_effect").map(String::as_str) == Some("NoExecute")
+        && !args.allow_noexecute_taints
+    {
+        errors.push((
+            "labels.taint_effect",
+            "NoExecute needs --allow-noexecute-taints".to_string(),
+        ));
+    }
+    if let Some(channels) = labels.get("notify") {
+        for channel in channels.split(',').map(str::trim) {
+            if !["teams", "discord"].contains(&channel) {
+                errors.push(("labels.notify", format!("unknown channel '{}'", channel)));
+            }
+        }
+    }
+    errors
+}
+
+/// Check a file of AlertRemediationRule resources (multi-document YAML), reporting problems as
+/// 'path:line: message'
+pub fn check_rules_file(path: &str, args: &Args) -> Vec<String> {
+    let text = match std::fs::read_to_string(path) {
+        Ok(text) => text,
+        Err(err) => return vec![format!("{}: {}", path, err)],
+    };
+    // Documents with the line each starts on, so errors point into the whole file
+    let mut documents: Vec<(usize, String)> = vec![(0, String::new())];
+    for (number, line) in text.lines().enumerate() {
+        if line.trim_end() == "---" {
+            documents.push((number + 1, String::new()));
+        } else if let Some((_, document)) = documents.last_mut() {
+            document.push_str(line);
+            document.push('\n');
+        }
+    }
+    let mut errors = Vec::new();
+    let mut names: BTreeMap<String, usize> = BTreeMap::new();
+    for (start, document) in documents {
+        let value: serde_yaml::Value = match serde_yaml::from_str(&document) {
+            Ok(serde_yaml::Value::Null) => continue,
+            Ok(value) => value,
+            Err(err) => {
+                let line = err.location().map_or(0, |location| location.line() - 1);
+                // The location is relative to the document, the prefix already has the real one
+                let message = err.to_string();
+                let message = message.split(" at line ").next().unwrap_or_default();
+                errors.push(format!("{}:{}: {}", path, start + line + 1, message));
+                continue;
+            }
+        };
+        if value["kind"].as_str() != Some("AlertRemediationRule") {
+            errors.push(format!(
+                "{}:{}: expected kind AlertRemediationRule, got {:?}",
+                path,
+                start + 1,
+                value["kind"]
+            ));
+            continue;
+        }
+        let rule: AlertRemediationRule = match serde_yaml::from_value(value) {
+            Ok(rule) => rule,
+            Err(err) => {
+                errors.push(format!("{}:{}: {}", path, start + 1, err));
+                continue;
+            }
+        };
+        let name = format!(
+            "{}/{}",
+            rule.namespace().unwrap_or_default(),
+            rule.name_any()
+        );
+        if let Some(first) = names.insert(name.clone(), start + 1) {
+            errors.push(format!(
+                "{}:{}: rule {} is already defined on line {}",
+                path,
+                start + 1,
+                name,
+                first
+            ));
+        }
+        for (field, err) in check_rule(&rule, args) {
+            let key = field.rsplit('.').next().unwrap_or(field);
+            let line = start + field_line(&document, key).unwrap_or(0) + 1;
+            errors.push(format!(
+                "{}:{}: rule {}: {}: {}",
+                path, line, name, field, err
+            ));
+        }
+    }
+    errors
+}
+
+/// Run every check, printing problems to stderr. Returns whether everything is valid.
+pub fn validate(args: &Args, rules_files: &[String]) -> bool {
+    let mut errors = check_args(args);
+    errors.extend(check_secrets(args));
+    for path in rules_files {
+        errors.extend(check_rules_file(path, args));
+    }
+    for error in &errors {
+        eprintln!("{}", error);
+    }
+    if errors.is_empty() {
+        println!(
+            "configuration and {} rule file(s) are valid",
+            rules_files.len()
+        );
+    }
+    errors.is_empty()
+}